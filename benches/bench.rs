@@ -2,10 +2,21 @@
 // https://github.com/PaulGrandperrin/XChaCha8Blake3Siv/blob/bbcc3874da3375a5111d113b01c4156b660ef034/benches/bench.rs
 // Thanks Paul!
 
+use aes::{Aes128, Aes256};
 use aes_gcm::Aes128Gcm;
-use kc_aeads::{MacHteUtcAes128Gcm, UtcAes128Gcm};
+use kc_aeads::{
+    CommittingPrf, CxPrf, FusedHteUtcAes128Gcm, HkdfHteUtcAes128Gcm, MacHteUtcAes128Gcm, PrfCom,
+    PrfMask, UtcAes128Gcm,
+};
 
-use aead::{generic_array::typenum::Unsigned, AeadCore, AeadInPlace, Key, NewAead, Nonce};
+use aead::{
+    generic_array::{
+        typenum::{Unsigned, U12},
+        GenericArray,
+    },
+    AeadCore, AeadInPlace, Key, NewAead, Nonce,
+};
+use cipher::KeyInit;
 use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
 use rand_core::RngCore;
 
@@ -109,10 +120,64 @@ fn bench_aead<A: NewAead + AeadInPlace>(c: &mut Criterion, name: &str) {
     group.finish();
 }
 
+//
+// We bench the committing PRF on n random messages, for n in {8, 64}, evaluated one at a time with
+// prf() and all at once with prf_batch(). For CX[E], the batch version packs the blocks of 8
+// messages into one encrypt_blocks call, which hardware AES can pipeline.
+//
+
+fn bench_prf_batch<F: CommittingPrf>(c: &mut Criterion, name: &str) {
+    let mut key = GenericArray::default();
+    rand::thread_rng().fill_bytes(&mut key);
+    let prf = <F as KeyInit>::new(&key);
+
+    let mut group = c.benchmark_group(name);
+    for num_msgs in [8, 64].iter() {
+        group.throughput(Throughput::Elements(*num_msgs as u64));
+
+        let msgs: Vec<GenericArray<u8, F::MsgSize>> = (0..*num_msgs)
+            .map(|_| {
+                let mut msg = GenericArray::default();
+                rand::thread_rng().fill_bytes(&mut msg);
+                msg
+            })
+            .collect();
+        let mut out: Vec<(PrfCom<F>, PrfMask<F>)> = vec![Default::default(); *num_msgs];
+
+        group.bench_with_input(
+            BenchmarkId::from_parameter(format!("prf [n={}]", num_msgs)),
+            &msgs,
+            |b, msgs| {
+                b.iter(|| {
+                    for (msg, o) in msgs.iter().zip(out.iter_mut()) {
+                        *o = prf.prf(msg);
+                    }
+                })
+            },
+        );
+
+        group.bench_with_input(
+            BenchmarkId::from_parameter(format!("prf_batch [n={}]", num_msgs)),
+            &msgs,
+            |b, msgs| {
+                b.iter(|| {
+                    prf.prf_batch(msgs, &mut out)
+                        .expect("PRF batch length mismatch!")
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
 fn bench(c: &mut Criterion) {
     bench_aead::<Aes128Gcm>(c, "Aes128Gcm");
     bench_aead::<UtcAes128Gcm>(c, "UtcAes128Gcm");
     bench_aead::<MacHteUtcAes128Gcm>(c, "MacHteUtcAes128Gcm");
+    bench_aead::<HkdfHteUtcAes128Gcm>(c, "HkdfHteUtcAes128Gcm");
+    bench_aead::<FusedHteUtcAes128Gcm>(c, "FusedHteUtcAes128Gcm");
+    bench_prf_batch::<CxPrf<Aes128, U12>>(c, "CxPrf<Aes128>");
+    bench_prf_batch::<CxPrf<Aes256, U12>>(c, "CxPrf<Aes256>");
 }
 
 criterion_group!(benches, bench);
@@ -0,0 +1,192 @@
+//! Defines a registry of stable identifiers for the committing AEADs in this crate
+
+use core::{fmt, str::FromStr};
+
+use aead::{AeadCore, Error, NewAead};
+use cipher::typenum::Unsigned;
+
+/// A stable identifier for one of the committing AEADs in this crate. The numeric values are part
+/// of serialized formats, and the string names (e.g., `"UtcAes128Gcm"`) are meant for
+/// configuration files, so neither ever changes or is reused.
+///
+/// Only committing schemes get an identifier. In particular, plain AES-GCM has none, so a format
+/// which looks up its scheme by identifier can't be downgraded to a non-committing one. The
+/// identifier 0 is reserved.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[repr(u16)]
+#[non_exhaustive]
+pub enum AlgorithmId {
+    /// [`UtcAes128Gcm`](crate::UtcAes128Gcm)
+    UtcAes128Gcm = 0x0001,
+    /// [`UtcAes256Gcm`](crate::UtcAes256Gcm)
+    UtcAes256Gcm = 0x0002,
+    /// [`MacHteUtcAes128Gcm`](crate::MacHteUtcAes128Gcm)
+    MacHteUtcAes128Gcm = 0x0003,
+    /// [`MacHteUtcAes256Gcm`](crate::MacHteUtcAes256Gcm)
+    MacHteUtcAes256Gcm = 0x0004,
+    /// [`HkdfHteUtcAes128Gcm`](crate::HkdfHteUtcAes128Gcm)
+    HkdfHteUtcAes128Gcm = 0x0005,
+    /// [`HkdfHteUtcAes256Gcm`](crate::HkdfHteUtcAes256Gcm)
+    HkdfHteUtcAes256Gcm = 0x0006,
+    /// [`FusedHteUtcAes128Gcm`](crate::FusedHteUtcAes128Gcm)
+    FusedHteUtcAes128Gcm = 0x0007,
+    /// [`FusedHteUtcAes256Gcm`](crate::FusedHteUtcAes256Gcm)
+    FusedHteUtcAes256Gcm = 0x0008,
+}
+
+// Runs $body with the type alias $aead set to the AEAD that $alg identifies
+macro_rules! with_algorithm {
+    ($alg:expr, $aead:ident => $body:expr) => {
+        match $alg {
+            $crate::AlgorithmId::UtcAes128Gcm => {
+                type $aead = $crate::UtcAes128Gcm;
+                $body
+            }
+            $crate::AlgorithmId::UtcAes256Gcm => {
+                type $aead = $crate::UtcAes256Gcm;
+                $body
+            }
+            $crate::AlgorithmId::MacHteUtcAes128Gcm => {
+                type $aead = $crate::MacHteUtcAes128Gcm;
+                $body
+            }
+            $crate::AlgorithmId::MacHteUtcAes256Gcm => {
+                type $aead = $crate::MacHteUtcAes256Gcm;
+                $body
+            }
+            $crate::AlgorithmId::HkdfHteUtcAes128Gcm => {
+                type $aead = $crate::HkdfHteUtcAes128Gcm;
+                $body
+            }
+            $crate::AlgorithmId::HkdfHteUtcAes256Gcm => {
+                type $aead = $crate::HkdfHteUtcAes256Gcm;
+                $body
+            }
+            $crate::AlgorithmId::FusedHteUtcAes128Gcm => {
+                type $aead = $crate::FusedHteUtcAes128Gcm;
+                $body
+            }
+            $crate::AlgorithmId::FusedHteUtcAes256Gcm => {
+                type $aead = $crate::FusedHteUtcAes256Gcm;
+                $body
+            }
+        }
+    };
+}
+
+// Only the tests need this outside of this module
+#[cfg(test)]
+pub(crate) use with_algorithm;
+
+impl AlgorithmId {
+    /// Every registered algorithm, in order of identifier
+    pub const ALL: [AlgorithmId; 8] = [
+        AlgorithmId::UtcAes128Gcm,
+        AlgorithmId::UtcAes256Gcm,
+        AlgorithmId::MacHteUtcAes128Gcm,
+        AlgorithmId::MacHteUtcAes256Gcm,
+        AlgorithmId::HkdfHteUtcAes128Gcm,
+        AlgorithmId::HkdfHteUtcAes256Gcm,
+        AlgorithmId::FusedHteUtcAes128Gcm,
+        AlgorithmId::FusedHteUtcAes256Gcm,
+    ];
+
+    /// Returns the numeric identifier of this algorithm
+    pub fn to_u16(self) -> u16 {
+        self as u16
+    }
+
+    /// Returns the algorithm with the given numeric identifier, if there is one
+    pub fn from_u16(id: u16) -> Option<Self> {
+        Self::ALL.iter().copied().find(|alg| alg.to_u16() == id)
+    }
+
+    /// Returns the string name of this algorithm. This is the same as the name of its type.
+    pub fn name(self) -> &'static str {
+        match self {
+            AlgorithmId::UtcAes128Gcm => "UtcAes128Gcm",
+            AlgorithmId::UtcAes256Gcm => "UtcAes256Gcm",
+            AlgorithmId::MacHteUtcAes128Gcm => "MacHteUtcAes128Gcm",
+            AlgorithmId::MacHteUtcAes256Gcm => "MacHteUtcAes256Gcm",
+            AlgorithmId::HkdfHteUtcAes128Gcm => "HkdfHteUtcAes128Gcm",
+            AlgorithmId::HkdfHteUtcAes256Gcm => "HkdfHteUtcAes256Gcm",
+            AlgorithmId::FusedHteUtcAes128Gcm => "FusedHteUtcAes128Gcm",
+            AlgorithmId::FusedHteUtcAes256Gcm => "FusedHteUtcAes256Gcm",
+        }
+    }
+
+    /// Returns the key length of this algorithm in bytes
+    pub fn key_len(self) -> usize {
+        with_algorithm!(self, A => <A as NewAead>::KeySize::USIZE)
+    }
+
+    /// Returns the nonce length of this algorithm in bytes
+    pub fn nonce_len(self) -> usize {
+        with_algorithm!(self, A => <A as AeadCore>::NonceSize::USIZE)
+    }
+
+    /// Returns the tag length of this algorithm in bytes. This includes the commitment.
+    pub fn tag_len(self) -> usize {
+        with_algorithm!(self, A => <A as AeadCore>::TagSize::USIZE)
+    }
+
+    /// Returns whether this algorithm commits to the nonce and associated data as well as the key,
+    /// i.e., whether it is CMT-4 rather than just CMT-1
+    pub fn is_context_committing(self) -> bool {
+        !matches!(self, AlgorithmId::UtcAes128Gcm | AlgorithmId::UtcAes256Gcm)
+    }
+}
+
+impl fmt::Display for AlgorithmId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Parses an algorithm from its string name. The match is exact and case-sensitive.
+impl FromStr for AlgorithmId {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self, Error> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|alg| alg.name() == name)
+            .ok_or(Error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // The identifiers are stable, so pin them
+    #[test]
+    fn algorithm_ids() {
+        let ids: Vec<u16> = AlgorithmId::ALL.iter().map(|alg| alg.to_u16()).collect();
+        assert_eq!(ids, [1, 2, 3, 4, 5, 6, 7, 8]);
+
+        for alg in AlgorithmId::ALL {
+            assert_eq!(AlgorithmId::from_u16(alg.to_u16()), Some(alg));
+        }
+        assert_eq!(AlgorithmId::from_u16(0), None);
+        assert_eq!(AlgorithmId::from_u16(9), None);
+        assert_eq!(AlgorithmId::from_u16(u16::MAX), None);
+    }
+
+    // The names are stable too
+    #[test]
+    fn algorithm_names() {
+        for alg in AlgorithmId::ALL {
+            assert_eq!(alg.name().parse::<AlgorithmId>().unwrap(), alg);
+            assert_eq!(alg.to_string(), alg.name());
+        }
+        assert_eq!(
+            "HkdfHteUtcAes256Gcm".parse::<AlgorithmId>().unwrap(),
+            AlgorithmId::HkdfHteUtcAes256Gcm
+        );
+        assert!("hkdfhteutcaes256gcm".parse::<AlgorithmId>().is_err());
+        assert!("Aes256Gcm".parse::<AlgorithmId>().is_err());
+        assert!("".parse::<AlgorithmId>().is_err());
+    }
+}
@@ -0,0 +1,256 @@
+//! Defines [`AnyKcAead`], which selects one of the committing AEADs in this crate at runtime
+
+use crate::{
+    algorithm::AlgorithmId, FusedHteUtcAes128Gcm, FusedHteUtcAes256Gcm, HkdfHteUtcAes128Gcm,
+    HkdfHteUtcAes256Gcm, MacHteUtcAes128Gcm, MacHteUtcAes256Gcm, UtcAes128Gcm, UtcAes256Gcm,
+};
+
+use aead::{AeadInPlace, Error, Key, NewAead, Nonce, Tag};
+use cipher::typenum::Unsigned;
+
+/// One of the committing AEADs in this crate, chosen at runtime. The methods mirror
+/// [`AeadInPlace`], except that keys, nonces, and tags are slices whose lengths are checked at
+/// runtime against the algorithm's [`AlgorithmId::key_len`], [`AlgorithmId::nonce_len`], and
+/// [`AlgorithmId::tag_len`].
+#[non_exhaustive]
+pub enum AnyKcAead {
+    /// [`AlgorithmId::UtcAes128Gcm`], backed by a [`UtcAes128Gcm`]
+    UtcAes128Gcm(UtcAes128Gcm),
+    /// [`AlgorithmId::UtcAes256Gcm`], backed by a [`UtcAes256Gcm`]
+    UtcAes256Gcm(UtcAes256Gcm),
+    /// [`AlgorithmId::MacHteUtcAes128Gcm`], backed by a [`MacHteUtcAes128Gcm`]
+    MacHteUtcAes128Gcm(MacHteUtcAes128Gcm),
+    /// [`AlgorithmId::MacHteUtcAes256Gcm`], backed by a [`MacHteUtcAes256Gcm`]
+    MacHteUtcAes256Gcm(MacHteUtcAes256Gcm),
+    /// [`AlgorithmId::HkdfHteUtcAes128Gcm`], backed by a [`HkdfHteUtcAes128Gcm`]
+    HkdfHteUtcAes128Gcm(HkdfHteUtcAes128Gcm),
+    /// [`AlgorithmId::HkdfHteUtcAes256Gcm`], backed by a [`HkdfHteUtcAes256Gcm`]
+    HkdfHteUtcAes256Gcm(HkdfHteUtcAes256Gcm),
+    /// [`AlgorithmId::FusedHteUtcAes128Gcm`], backed by a [`FusedHteUtcAes128Gcm`]
+    FusedHteUtcAes128Gcm(FusedHteUtcAes128Gcm),
+    /// [`AlgorithmId::FusedHteUtcAes256Gcm`], backed by a [`FusedHteUtcAes256Gcm`]
+    FusedHteUtcAes256Gcm(FusedHteUtcAes256Gcm),
+}
+
+// Runs $body with $ciph bound to the inner AEAD of $any
+macro_rules! with_any_aead {
+    ($any:expr, $ciph:ident => $body:expr) => {
+        match $any {
+            AnyKcAead::UtcAes128Gcm($ciph) => $body,
+            AnyKcAead::UtcAes256Gcm($ciph) => $body,
+            AnyKcAead::MacHteUtcAes128Gcm($ciph) => $body,
+            AnyKcAead::MacHteUtcAes256Gcm($ciph) => $body,
+            AnyKcAead::HkdfHteUtcAes128Gcm($ciph) => $body,
+            AnyKcAead::HkdfHteUtcAes256Gcm($ciph) => $body,
+            AnyKcAead::FusedHteUtcAes128Gcm($ciph) => $body,
+            AnyKcAead::FusedHteUtcAes256Gcm($ciph) => $body,
+        }
+    };
+}
+
+/// Converts a key slice to a key of `A`, checking its length
+fn key_from_slice<A: NewAead>(key: &[u8]) -> Result<&Key<A>, Error> {
+    if key.len() == A::KeySize::USIZE {
+        Ok(Key::<A>::from_slice(key))
+    } else {
+        Err(Error)
+    }
+}
+
+/// Encrypts with the given AEAD, after checking the nonce and tag lengths
+fn encrypt_detached<A: AeadInPlace>(
+    ciph: &A,
+    nonce: &[u8],
+    associated_data: &[u8],
+    buffer: &mut [u8],
+    tag_out: &mut [u8],
+) -> Result<(), Error> {
+    if nonce.len() != A::NonceSize::USIZE || tag_out.len() != A::TagSize::USIZE {
+        return Err(Error);
+    }
+    let tag =
+        ciph.encrypt_in_place_detached(Nonce::<A>::from_slice(nonce), associated_data, buffer)?;
+    tag_out.copy_from_slice(&tag);
+
+    Ok(())
+}
+
+/// Decrypts with the given AEAD, after checking the nonce and tag lengths
+fn decrypt_detached<A: AeadInPlace>(
+    ciph: &A,
+    nonce: &[u8],
+    associated_data: &[u8],
+    buffer: &mut [u8],
+    tag: &[u8],
+) -> Result<(), Error> {
+    if nonce.len() != A::NonceSize::USIZE || tag.len() != A::TagSize::USIZE {
+        return Err(Error);
+    }
+    ciph.decrypt_in_place_detached(
+        Nonce::<A>::from_slice(nonce),
+        associated_data,
+        buffer,
+        Tag::<A>::from_slice(tag),
+    )
+}
+
+impl AnyKcAead {
+    /// Makes a new AEAD of the given algorithm. Returns an error if `key` is the wrong length.
+    pub fn new(alg: AlgorithmId, key: &[u8]) -> Result<Self, Error> {
+        // Every variant has the same name as the type it holds
+        macro_rules! new_variant {
+            ($variant:ident) => {
+                AnyKcAead::$variant(<$variant as NewAead>::new(key_from_slice::<$variant>(key)?))
+            };
+        }
+
+        let ciph = match alg {
+            AlgorithmId::UtcAes128Gcm => new_variant!(UtcAes128Gcm),
+            AlgorithmId::UtcAes256Gcm => new_variant!(UtcAes256Gcm),
+            AlgorithmId::MacHteUtcAes128Gcm => new_variant!(MacHteUtcAes128Gcm),
+            AlgorithmId::MacHteUtcAes256Gcm => new_variant!(MacHteUtcAes256Gcm),
+            AlgorithmId::HkdfHteUtcAes128Gcm => new_variant!(HkdfHteUtcAes128Gcm),
+            AlgorithmId::HkdfHteUtcAes256Gcm => new_variant!(HkdfHteUtcAes256Gcm),
+            AlgorithmId::FusedHteUtcAes128Gcm => new_variant!(FusedHteUtcAes128Gcm),
+            AlgorithmId::FusedHteUtcAes256Gcm => new_variant!(FusedHteUtcAes256Gcm),
+        };
+
+        Ok(ciph)
+    }
+
+    /// Returns the algorithm of this AEAD
+    pub fn algorithm(&self) -> AlgorithmId {
+        match self {
+            AnyKcAead::UtcAes128Gcm(_) => AlgorithmId::UtcAes128Gcm,
+            AnyKcAead::UtcAes256Gcm(_) => AlgorithmId::UtcAes256Gcm,
+            AnyKcAead::MacHteUtcAes128Gcm(_) => AlgorithmId::MacHteUtcAes128Gcm,
+            AnyKcAead::MacHteUtcAes256Gcm(_) => AlgorithmId::MacHteUtcAes256Gcm,
+            AnyKcAead::HkdfHteUtcAes128Gcm(_) => AlgorithmId::HkdfHteUtcAes128Gcm,
+            AnyKcAead::HkdfHteUtcAes256Gcm(_) => AlgorithmId::HkdfHteUtcAes256Gcm,
+            AnyKcAead::FusedHteUtcAes128Gcm(_) => AlgorithmId::FusedHteUtcAes128Gcm,
+            AnyKcAead::FusedHteUtcAes256Gcm(_) => AlgorithmId::FusedHteUtcAes256Gcm,
+        }
+    }
+
+    /// Returns the key length in bytes
+    pub fn key_len(&self) -> usize {
+        self.algorithm().key_len()
+    }
+
+    /// Returns the nonce length in bytes
+    pub fn nonce_len(&self) -> usize {
+        self.algorithm().nonce_len()
+    }
+
+    /// Returns the tag length in bytes. This includes the commitment.
+    pub fn tag_len(&self) -> usize {
+        self.algorithm().tag_len()
+    }
+
+    /// Encrypts `buffer` in place and writes the tag to `tag_out`. Returns an error if `nonce` or
+    /// `tag_out` is the wrong length.
+    pub fn encrypt_in_place_detached(
+        &self,
+        nonce: &[u8],
+        associated_data: &[u8],
+        buffer: &mut [u8],
+        tag_out: &mut [u8],
+    ) -> Result<(), Error> {
+        with_any_aead!(self, ciph => encrypt_detached(ciph, nonce, associated_data, buffer, tag_out))
+    }
+
+    /// Decrypts `buffer` in place. Returns an error if `nonce` or `tag` is the wrong length, or if
+    /// decryption fails, in which case `buffer` is left as ciphertext.
+    pub fn decrypt_in_place_detached(
+        &self,
+        nonce: &[u8],
+        associated_data: &[u8],
+        buffer: &mut [u8],
+        tag: &[u8],
+    ) -> Result<(), Error> {
+        with_any_aead!(self, ciph => decrypt_detached(ciph, nonce, associated_data, buffer, tag))
+    }
+
+    /// Encrypts `msg` and returns the ciphertext with the tag appended
+    pub fn encrypt(
+        &self,
+        nonce: &[u8],
+        associated_data: &[u8],
+        msg: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let mut out = vec![0u8; msg.len() + self.tag_len()];
+        let (buffer, tag_out) = out.split_at_mut(msg.len());
+        buffer.copy_from_slice(msg);
+        self.encrypt_in_place_detached(nonce, associated_data, buffer, tag_out)?;
+
+        Ok(out)
+    }
+
+    /// Decrypts a ciphertext with the tag appended, and returns the plaintext
+    pub fn decrypt(
+        &self,
+        nonce: &[u8],
+        associated_data: &[u8],
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let ct_len = ciphertext.len().checked_sub(self.tag_len()).ok_or(Error)?;
+        let (ct, tag) = ciphertext.split_at(ct_len);
+
+        let mut buf = ct.to_vec();
+        self.decrypt_in_place_detached(nonce, associated_data, &mut buf, tag)?;
+
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{algorithm::with_algorithm, util::random_bytes};
+
+    #[test]
+    fn any_aead_correctness() {
+        for alg in AlgorithmId::ALL {
+            let key = random_bytes(alg.key_len());
+            let nonce = random_bytes(alg.nonce_len());
+            let ciph = AnyKcAead::new(alg, &key).unwrap();
+            assert_eq!(ciph.algorithm(), alg);
+
+            let msg = random_bytes(100);
+            let ct = ciph.encrypt(&nonce, b"aad", &msg).unwrap();
+            assert_eq!(ct.len(), msg.len() + ciph.tag_len());
+            assert_eq!(ciph.decrypt(&nonce, b"aad", &ct).unwrap(), msg);
+            assert!(ciph.decrypt(&nonce, b"aae", &ct).is_err());
+
+            // The output is the same as that of the underlying type
+            with_algorithm!(alg, A => {
+                use aead::Aead;
+                let typed = A::new(Key::<A>::from_slice(&key));
+                let typed_ct = typed
+                    .encrypt(Nonce::<A>::from_slice(&nonce), aead::Payload {
+                        msg: &msg,
+                        aad: b"aad",
+                    })
+                    .unwrap();
+                assert_eq!(typed_ct, ct);
+            });
+
+            // Wrong lengths are errors, not panics
+            assert!(AnyKcAead::new(alg, &key[1..]).is_err());
+            assert!(ciph.encrypt(&nonce[1..], b"", &msg).is_err());
+            assert!(ciph.decrypt(&nonce[1..], b"", &ct).is_err());
+            assert!(ciph
+                .decrypt(&nonce, b"", &ct[..ciph.tag_len() - 1])
+                .is_err());
+            let mut buf = msg.clone();
+            let mut short_tag = vec![0u8; ciph.tag_len() - 1];
+            assert!(ciph
+                .encrypt_in_place_detached(&nonce, b"", &mut buf, &mut short_tag)
+                .is_err());
+            assert!(ciph
+                .decrypt_in_place_detached(&nonce, b"", &mut buf, &short_tag)
+                .is_err());
+            assert_eq!(buf, msg);
+        }
+    }
+}
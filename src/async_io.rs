@@ -0,0 +1,552 @@
+//! Defines [`tokio::io`] adapters which encrypt and decrypt a byte stream with STREAM over one of
+//! the committing AEADs in this crate. The ciphertext format is the same as in the
+//! [`io`](crate::io) module, so a stream written by one can be read by the other.
+//!
+//! Both adapters propagate back-pressure: the writer does not accept more plaintext until the
+//! previous segment's ciphertext has been written to the inner writer, and the reader only reads
+//! from the inner reader when it has no plaintext left to return.
+
+use crate::{
+    io::DEFAULT_CHUNK_SIZE,
+    stream::{DecryptorBE32, EncryptorBE32, Nonce, StreamBE32},
+};
+
+use core::{
+    ops::Sub,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use std::io;
+
+use aead::{AeadInPlace, Key, NewAead};
+use cipher::{
+    generic_array::ArrayLength,
+    typenum::{Unsigned, U5},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+fn aead_error() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "committing AEAD stream error")
+}
+
+fn closed_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::BrokenPipe,
+        "committing AEAD stream already shut down",
+    )
+}
+
+/// An async writer which encrypts everything written to it and writes the ciphertext to an inner
+/// writer. The caller MUST call `shutdown()` when done, otherwise the ciphertext is truncated and
+/// will fail to decrypt.
+pub struct AsyncEncryptingWriter<W, A>
+where
+    W: AsyncWrite + Unpin,
+    A: AeadInPlace + NewAead,
+    A::NonceSize: Sub<U5>,
+    <A::NonceSize as Sub<U5>>::Output: ArrayLength<u8>,
+{
+    inner: W,
+    // This is None once the last segment has been encrypted
+    encryptor: Option<EncryptorBE32<A>>,
+    // The plaintext of the current segment
+    buf: Vec<u8>,
+    // Ciphertext which hasn't been written to the inner writer yet, and how much of it has been
+    // written
+    out_buf: Vec<u8>,
+    out_pos: usize,
+    chunk_size: usize,
+}
+
+impl<W, A> AsyncEncryptingWriter<W, A>
+where
+    W: AsyncWrite + Unpin,
+    A: AeadInPlace + NewAead,
+    A::NonceSize: Sub<U5>,
+    <A::NonceSize as Sub<U5>>::Output: ArrayLength<u8>,
+{
+    /// Makes a new encrypting writer with [`DEFAULT_CHUNK_SIZE`]-byte segments. The nonce MUST
+    /// NOT be reused under the same key.
+    pub fn new(inner: W, key: &Key<A>, nonce: &Nonce<A, StreamBE32<A>>) -> Self {
+        Self::with_chunk_size(inner, key, nonce, DEFAULT_CHUNK_SIZE)
+    }
+
+    /// Makes a new encrypting writer with `chunk_size`-byte segments. The reader must use the same
+    /// chunk size. The nonce MUST NOT be reused under the same key.
+    ///
+    /// Panics if `chunk_size` is 0.
+    pub fn with_chunk_size(
+        inner: W,
+        key: &Key<A>,
+        nonce: &Nonce<A, StreamBE32<A>>,
+        chunk_size: usize,
+    ) -> Self {
+        assert!(chunk_size > 0, "chunk size must be nonzero");
+
+        let segment_size = chunk_size + A::TagSize::USIZE;
+        AsyncEncryptingWriter {
+            inner,
+            encryptor: Some(EncryptorBE32::new(key, nonce)),
+            buf: Vec::with_capacity(segment_size),
+            out_buf: Vec::with_capacity(segment_size),
+            out_pos: 0,
+            chunk_size,
+        }
+    }
+
+    /// Returns the inner writer
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    /// Writes out any pending ciphertext. Returns `Poll::Ready(Ok(()))` once it's all written.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.out_pos < self.out_buf.len() {
+            let n = match Pin::new(&mut self.inner).poll_write(cx, &self.out_buf[self.out_pos..]) {
+                Poll::Ready(Ok(n)) => n,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.out_pos += n;
+        }
+
+        self.out_buf.clear();
+        self.out_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    /// Moves the encrypted segment in `buf` to `out_buf`. `out_buf` MUST be empty.
+    fn queue_segment(&mut self) {
+        debug_assert!(self.out_buf.is_empty());
+        core::mem::swap(&mut self.buf, &mut self.out_buf);
+    }
+}
+
+// None of the fields are ever pinned, and the inner writer is Unpin, so this is always Unpin
+impl<W, A> Unpin for AsyncEncryptingWriter<W, A>
+where
+    W: AsyncWrite + Unpin,
+    A: AeadInPlace + NewAead,
+    A::NonceSize: Sub<U5>,
+    <A::NonceSize as Sub<U5>>::Output: ArrayLength<u8>,
+{
+}
+
+impl<W, A> AsyncWrite for AsyncEncryptingWriter<W, A>
+where
+    W: AsyncWrite + Unpin,
+    A: AeadInPlace + NewAead,
+    A::NonceSize: Sub<U5>,
+    <A::NonceSize as Sub<U5>>::Output: ArrayLength<u8>,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        // Once shut down, the last segment has been encrypted, so nothing more can be written
+        if this.encryptor.is_none() {
+            return Poll::Ready(Err(closed_error()));
+        }
+
+        // Don't accept anything until the previous segment is written out
+        match this.poll_drain(cx) {
+            Poll::Ready(Ok(())) => (),
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
+        }
+        if data.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        // A full segment is only encrypted once we know more data follows it. Otherwise it might
+        // have to be the last segment.
+        if this.buf.len() == this.chunk_size {
+            let encryptor = this.encryptor.as_mut().ok_or_else(closed_error)?;
+            encryptor
+                .encrypt_next_in_place(&[], &mut this.buf)
+                .map_err(|_| aead_error())?;
+            this.queue_segment();
+
+            // Try to write it out now. If we can't, the data will be accepted on a later call.
+            match this.poll_drain(cx) {
+                Poll::Ready(Ok(())) => (),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        let n = core::cmp::min(data.len(), this.chunk_size - this.buf.len());
+        this.buf.extend_from_slice(&data[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    // This writes out any encrypted segments and flushes the inner writer. The current segment
+    // can't be written until it's full or until the writer is shut down.
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_drain(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_flush(cx),
+            other => other,
+        }
+    }
+
+    // This encrypts and writes the last segment, then shuts down the inner writer
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        match this.poll_drain(cx) {
+            Poll::Ready(Ok(())) => (),
+            other => return other,
+        }
+
+        // If this is the first call to shutdown, encrypt the last segment and write it out
+        if let Some(encryptor) = this.encryptor.take() {
+            encryptor
+                .encrypt_last_in_place(&[], &mut this.buf)
+                .map_err(|_| aead_error())?;
+            this.queue_segment();
+
+            match this.poll_drain(cx) {
+                Poll::Ready(Ok(())) => (),
+                other => return other,
+            }
+        }
+
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/// An async reader which reads ciphertext from an inner reader and returns the decrypted
+/// plaintext. A read returns an error of kind [`io::ErrorKind::InvalidData`] if the ciphertext was
+/// modified or truncated, and every read after that returns the same kind of error. Plaintext is
+/// only returned once its segment has been authenticated.
+pub struct AsyncDecryptingReader<R, A>
+where
+    R: AsyncRead + Unpin,
+    A: AeadInPlace + NewAead,
+    A::NonceSize: Sub<U5>,
+    <A::NonceSize as Sub<U5>>::Output: ArrayLength<u8>,
+{
+    inner: R,
+    // This is None once the last segment has been decrypted, or decryption has failed
+    decryptor: Option<DecryptorBE32<A>>,
+    failed: bool,
+    // Ciphertext read from the inner reader. We read one byte past the end of a segment to find
+    // out whether it is the last segment.
+    ct_buf: Vec<u8>,
+    inner_eof: bool,
+    // The plaintext of the current segment, and how much of it has been returned
+    pt_buf: Vec<u8>,
+    pt_pos: usize,
+    chunk_size: usize,
+}
+
+impl<R, A> AsyncDecryptingReader<R, A>
+where
+    R: AsyncRead + Unpin,
+    A: AeadInPlace + NewAead,
+    A::NonceSize: Sub<U5>,
+    <A::NonceSize as Sub<U5>>::Output: ArrayLength<u8>,
+{
+    /// Makes a new decrypting reader with [`DEFAULT_CHUNK_SIZE`]-byte segments
+    pub fn new(inner: R, key: &Key<A>, nonce: &Nonce<A, StreamBE32<A>>) -> Self {
+        Self::with_chunk_size(inner, key, nonce, DEFAULT_CHUNK_SIZE)
+    }
+
+    /// Makes a new decrypting reader with `chunk_size`-byte segments. This must match the chunk
+    /// size used by the writer.
+    ///
+    /// Panics if `chunk_size` is 0.
+    pub fn with_chunk_size(
+        inner: R,
+        key: &Key<A>,
+        nonce: &Nonce<A, StreamBE32<A>>,
+        chunk_size: usize,
+    ) -> Self {
+        assert!(chunk_size > 0, "chunk size must be nonzero");
+
+        let segment_size = chunk_size + A::TagSize::USIZE;
+        AsyncDecryptingReader {
+            inner,
+            decryptor: Some(DecryptorBE32::new(key, nonce)),
+            failed: false,
+            ct_buf: Vec::with_capacity(segment_size + 1),
+            inner_eof: false,
+            pt_buf: Vec::with_capacity(segment_size + 1),
+            pt_pos: 0,
+            chunk_size,
+        }
+    }
+
+    /// Returns the inner reader
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Reads until `ct_buf` holds one byte more than a full segment, or until EOF
+    fn poll_fill(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let target_len = self.chunk_size + A::TagSize::USIZE + 1;
+
+        while !self.inner_eof && self.ct_buf.len() < target_len {
+            let old_len = self.ct_buf.len();
+            self.ct_buf.resize(target_len, 0);
+
+            let mut read_buf = ReadBuf::new(&mut self.ct_buf[old_len..]);
+            let res = Pin::new(&mut self.inner).poll_read(cx, &mut read_buf);
+            let n = read_buf.filled().len();
+            self.ct_buf.truncate(old_len + n);
+
+            match res {
+                Poll::Ready(Ok(())) if n == 0 => self.inner_eof = true,
+                Poll::Ready(Ok(())) => (),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    /// Decrypts the segment in `ct_buf` into `pt_buf`. `ct_buf` MUST be full or at EOF.
+    fn decrypt_segment(&mut self) -> io::Result<()> {
+        let segment_size = self.chunk_size + A::TagSize::USIZE;
+
+        // Swap the buffers, so that the segment is decrypted in place in pt_buf and the old
+        // plaintext buffer is reused for the next ciphertext
+        core::mem::swap(&mut self.ct_buf, &mut self.pt_buf);
+        self.ct_buf.clear();
+        self.pt_pos = 0;
+
+        if self.pt_buf.len() > segment_size {
+            // There's more data, so this isn't the last segment. Move the extra byte over.
+            let extra = self.pt_buf.pop().unwrap();
+            self.ct_buf.push(extra);
+
+            let decryptor = self.decryptor.as_mut().ok_or_else(aead_error)?;
+            decryptor
+                .decrypt_next_in_place(&[], &mut self.pt_buf)
+                .map_err(|_| aead_error())
+        } else {
+            // We hit EOF, so this is the last segment
+            let decryptor = self.decryptor.take().ok_or_else(aead_error)?;
+            decryptor
+                .decrypt_last_in_place(&[], &mut self.pt_buf)
+                .map_err(|_| aead_error())
+        }
+    }
+}
+
+// None of the fields are ever pinned, and the inner reader is Unpin, so this is always Unpin
+impl<R, A> Unpin for AsyncDecryptingReader<R, A>
+where
+    R: AsyncRead + Unpin,
+    A: AeadInPlace + NewAead,
+    A::NonceSize: Sub<U5>,
+    <A::NonceSize as Sub<U5>>::Output: ArrayLength<u8>,
+{
+}
+
+impl<R, A> AsyncRead for AsyncDecryptingReader<R, A>
+where
+    R: AsyncRead + Unpin,
+    A: AeadInPlace + NewAead,
+    A::NonceSize: Sub<U5>,
+    <A::NonceSize as Sub<U5>>::Output: ArrayLength<u8>,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        // Get a new segment if we've returned all of the current one. Segments can be empty, so
+        // loop until we get some plaintext or finish the stream.
+        while this.pt_pos == this.pt_buf.len() {
+            if this.failed {
+                return Poll::Ready(Err(aead_error()));
+            }
+            if this.decryptor.is_none() {
+                return Poll::Ready(Ok(()));
+            }
+
+            let res = match this.poll_fill(cx) {
+                Poll::Ready(Ok(())) => this.decrypt_segment(),
+                Poll::Ready(Err(e)) => Err(e),
+                Poll::Pending => return Poll::Pending,
+            };
+            if let Err(e) = res {
+                // Don't leave unauthenticated plaintext around, and don't decrypt anything else
+                this.pt_buf.clear();
+                this.pt_pos = 0;
+                this.decryptor = None;
+                this.failed = true;
+                return Poll::Ready(Err(e));
+            }
+        }
+
+        let n = core::cmp::min(out.remaining(), this.pt_buf.len() - this.pt_pos);
+        out.put_slice(&this.pt_buf[this.pt_pos..this.pt_pos + n]);
+        this.pt_pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{io::EncryptingWriter, MacHteUtcAes256Gcm, UtcAes256Gcm};
+
+    use rand::RngCore;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const CHUNK_SIZE: usize = 16;
+
+    // Encrypts through a small duplex pipe, so the writer sees back-pressure, and decrypts from the
+    // other end concurrently
+    async fn duplex_round_trip<A>(
+        key: &Key<A>,
+        nonce: &Nonce<A, StreamBE32<A>>,
+        msg: &[u8],
+    ) -> Vec<u8>
+    where
+        A: AeadInPlace + NewAead,
+        A::NonceSize: Sub<U5>,
+        <A::NonceSize as Sub<U5>>::Output: ArrayLength<u8>,
+    {
+        let (client, server) = tokio::io::duplex(8);
+        let mut writer =
+            AsyncEncryptingWriter::<_, A>::with_chunk_size(client, key, nonce, CHUNK_SIZE);
+        let mut reader =
+            AsyncDecryptingReader::<_, A>::with_chunk_size(server, key, nonce, CHUNK_SIZE);
+
+        let write = async {
+            for piece in msg.chunks(7) {
+                writer.write_all(piece).await.unwrap();
+            }
+            writer.shutdown().await.unwrap();
+        };
+        let read = async {
+            let mut out = Vec::new();
+            reader.read_to_end(&mut out).await.unwrap();
+            out
+        };
+
+        tokio::join!(write, read).1
+    }
+
+    async fn encrypt<A>(key: &Key<A>, nonce: &Nonce<A, StreamBE32<A>>, msg: &[u8]) -> Vec<u8>
+    where
+        A: AeadInPlace + NewAead,
+        A::NonceSize: Sub<U5>,
+        <A::NonceSize as Sub<U5>>::Output: ArrayLength<u8>,
+    {
+        let mut writer =
+            AsyncEncryptingWriter::<_, A>::with_chunk_size(Vec::new(), key, nonce, CHUNK_SIZE);
+        writer.write_all(msg).await.unwrap();
+        writer.shutdown().await.unwrap();
+        writer.into_inner()
+    }
+
+    async fn decrypt<A>(
+        key: &Key<A>,
+        nonce: &Nonce<A, StreamBE32<A>>,
+        ct: &[u8],
+    ) -> io::Result<Vec<u8>>
+    where
+        A: AeadInPlace + NewAead,
+        A::NonceSize: Sub<U5>,
+        <A::NonceSize as Sub<U5>>::Output: ArrayLength<u8>,
+    {
+        let mut reader = AsyncDecryptingReader::<_, A>::with_chunk_size(ct, key, nonce, CHUNK_SIZE);
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).await?;
+        Ok(out)
+    }
+
+    macro_rules! test_async_io {
+        ($aead:ty, $test_name:ident) => {
+            #[tokio::test]
+            async fn $test_name() {
+                let mut rng = rand::thread_rng();
+                let key = <$aead>::generate_key(&mut rng);
+                let nonce = Nonce::<$aead, StreamBE32<$aead>>::default();
+                let tag_size = <$aead as aead::AeadCore>::TagSize::USIZE;
+
+                for msg_len in [0, 1, CHUNK_SIZE, CHUNK_SIZE + 1, 5 * CHUNK_SIZE + 3] {
+                    let mut msg = vec![0u8; msg_len];
+                    rng.fill_bytes(&mut msg);
+
+                    // Round trip through a pipe
+                    assert_eq!(duplex_round_trip::<$aead>(&key, &nonce, &msg).await, msg);
+
+                    // The format matches the sync writer
+                    let ct = encrypt::<$aead>(&key, &nonce, &msg).await;
+                    let mut sync_writer = EncryptingWriter::<_, $aead>::with_chunk_size(
+                        Vec::new(),
+                        &key,
+                        &nonce,
+                        CHUNK_SIZE,
+                    );
+                    std::io::Write::write_all(&mut sync_writer, &msg).unwrap();
+                    assert_eq!(sync_writer.finish().unwrap(), ct);
+
+                    // Truncating at a segment boundary fails, as does every read afterwards
+                    if msg_len > CHUNK_SIZE {
+                        let trunc = &ct[..CHUNK_SIZE + tag_size];
+                        let mut reader = AsyncDecryptingReader::<_, $aead>::with_chunk_size(
+                            trunc, &key, &nonce, CHUNK_SIZE,
+                        );
+                        let mut out = Vec::new();
+                        let err = reader.read_to_end(&mut out).await.unwrap_err();
+                        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+                        assert!(out.is_empty());
+                        assert!(reader.read(&mut [0u8; 1]).await.is_err());
+                    }
+
+                    // Modifying any byte fails
+                    let mut bad_ct = ct.clone();
+                    bad_ct[ct.len() / 2] ^= 1;
+                    assert!(decrypt::<$aead>(&key, &nonce, &bad_ct).await.is_err());
+                }
+            }
+        };
+    }
+
+    // Writing after shutdown is an error, rather than data that's silently dropped
+    #[tokio::test]
+    async fn async_io_write_after_shutdown() {
+        let mut rng = rand::thread_rng();
+        let key = UtcAes256Gcm::generate_key(&mut rng);
+        let nonce = Nonce::<UtcAes256Gcm, StreamBE32<UtcAes256Gcm>>::default();
+
+        let mut writer = AsyncEncryptingWriter::<_, UtcAes256Gcm>::with_chunk_size(
+            Vec::new(),
+            &key,
+            &nonce,
+            CHUNK_SIZE,
+        );
+        writer.write_all(b"hello").await.unwrap();
+        writer.shutdown().await.unwrap();
+
+        let err = writer.write(b"world").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert!(writer.write(b"").await.is_err());
+
+        // The ciphertext only has what was written before the shutdown
+        let ct = writer.into_inner();
+        assert_eq!(
+            decrypt::<UtcAes256Gcm>(&key, &nonce, &ct).await.unwrap(),
+            b"hello"
+        );
+    }
+
+    test_async_io!(UtcAes256Gcm, async_io_utc_aes256);
+    test_async_io!(MacHteUtcAes256Gcm, async_io_machte_utc_aes256);
+}
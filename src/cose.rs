@@ -0,0 +1,584 @@
+//! Defines `COSE_Encrypt0` and `COSE_Encrypt` messages (RFC 9052 §5) with committing content
+//! encryption.
+//!
+//! COSE has no registered committing content encryption algorithms, so every [`AlgorithmId`] gets
+//! an `alg` value in the private-use range below -65536, namely `-65536 - id`. For example,
+//! [`HkdfHteUtcAes256Gcm`](crate::HkdfHteUtcAes256Gcm) is -65542. Both sides must agree on them
+//! out of band.
+//!
+//! The `alg` goes in the protected header, and the nonce goes in the unprotected header as the
+//! `IV`. As in RFC 9052 §5.3, the associated data is the `Enc_structure`, which binds the protected
+//! header and the caller's external AAD. `COSE_Encrypt` messages have exactly one recipient, which
+//! uses direct encryption (`alg` -6) and names the key by its `kid`.
+//!
+//! Messages are always tagged, and detached ciphertexts, `crit`, and `Partial IV` are unsupported.
+
+use crate::{AlgorithmId, AnyKcAead};
+
+use aead::Error;
+use ciborium::Value;
+use rand_core::{CryptoRng, RngCore};
+
+/// The CBOR tag of a `COSE_Encrypt0` message
+const TAG_ENCRYPT0: u64 = 16;
+/// The CBOR tag of a `COSE_Encrypt` message
+const TAG_ENCRYPT: u64 = 96;
+
+// Header labels, from RFC 9052 §3.1
+const LABEL_ALG: i64 = 1;
+const LABEL_CRIT: i64 = 2;
+const LABEL_KID: i64 = 4;
+const LABEL_IV: i64 = 5;
+const LABEL_PARTIAL_IV: i64 = 6;
+
+/// The `alg` of a recipient that uses the content key directly
+const ALG_DIRECT: i64 = -6;
+
+/// Private-use `alg` values are less than this
+const PRIVATE_USE_BASE: i64 = -65536;
+
+/// Returns the private-use COSE `alg` value for `alg`
+pub fn alg_value(alg: AlgorithmId) -> i64 {
+    PRIVATE_USE_BASE - i64::from(alg.to_u16())
+}
+
+/// Returns the algorithm with the given COSE `alg` value, if there is one
+pub fn algorithm(alg_value: i64) -> Option<AlgorithmId> {
+    let id = PRIVATE_USE_BASE.checked_sub(alg_value)?;
+    AlgorithmId::from_u16(u16::try_from(id).ok()?)
+}
+
+// Serializes a CBOR value
+fn to_cbor(value: &Value) -> Vec<u8> {
+    let mut out = Vec::new();
+    ciborium::into_writer(value, &mut out).expect("writing to a Vec never fails");
+    out
+}
+
+// Deserializes exactly one CBOR value, with nothing after it
+fn from_cbor(mut bytes: &[u8]) -> Result<Value, Error> {
+    let value = ciborium::from_reader(&mut bytes).map_err(|_| Error)?;
+    if !bytes.is_empty() {
+        return Err(Error);
+    }
+    Ok(value)
+}
+
+// Returns the header map in `value`. Returns an error if it isn't a map, or has a repeated label.
+fn header_map(value: &Value) -> Result<&[(Value, Value)], Error> {
+    let map = value.as_map().ok_or(Error)?;
+    for (i, (label, _)) in map.iter().enumerate() {
+        if map[..i].iter().any(|(l, _)| l == label) {
+            return Err(Error);
+        }
+    }
+    Ok(map)
+}
+
+// Returns the value of `label` in a header map, if it's there
+fn header_get(map: &[(Value, Value)], label: i64) -> Option<&Value> {
+    map.iter()
+        .find(|(l, _)| *l == Value::from(label))
+        .map(|(_, v)| v)
+}
+
+// Returns the `Enc_structure`, i.e., the CBOR encoding of `[context, protected, external_aad]`
+fn enc_structure(context: &str, protected: &[u8], external_aad: &[u8]) -> Vec<u8> {
+    to_cbor(&Value::Array(vec![
+        Value::Text(context.into()),
+        Value::Bytes(protected.to_vec()),
+        Value::Bytes(external_aad.to_vec()),
+    ]))
+}
+
+// Encrypts `plaintext`, and returns the first three fields of the message, i.e., the protected
+// header, the unprotected header, and the ciphertext
+fn encrypt_layer<R>(
+    rng: &mut R,
+    alg: AlgorithmId,
+    key: &[u8],
+    context: &str,
+    external_aad: &[u8],
+    plaintext: &[u8],
+) -> Result<Vec<Value>, Error>
+where
+    R: CryptoRng + RngCore,
+{
+    let ciph = AnyKcAead::new(alg, key)?;
+
+    let protected = to_cbor(&Value::Map(vec![(LABEL_ALG.into(), alg_value(alg).into())]));
+    let mut iv = vec![0u8; ciph.nonce_len()];
+    rng.fill_bytes(&mut iv);
+
+    let aad = enc_structure(context, &protected, external_aad);
+    let ciphertext = ciph.encrypt(&iv, &aad, plaintext)?;
+
+    Ok(vec![
+        Value::Bytes(protected),
+        Value::Map(vec![(LABEL_IV.into(), Value::Bytes(iv))]),
+        Value::Bytes(ciphertext),
+    ])
+}
+
+// Checks the headers of a message, and decrypts its ciphertext. `protected`, `unprotected`, and
+// `ciphertext` are the first three fields of the message.
+fn decrypt_layer(
+    expected_alg: AlgorithmId,
+    key: &[u8],
+    context: &str,
+    external_aad: &[u8],
+    protected: &Value,
+    unprotected: &Value,
+    ciphertext: &Value,
+) -> Result<Vec<u8>, Error> {
+    let ciph = AnyKcAead::new(expected_alg, key)?;
+
+    // The protected header is a serialized map, and the associated data covers its exact bytes
+    let protected_bytes = protected.as_bytes().ok_or(Error)?;
+    let protected_value = from_cbor(protected_bytes)?;
+    let protected = header_map(&protected_value)?;
+    let unprotected = header_map(unprotected)?;
+
+    // No label can be in both buckets. The alg is protected, the IV isn't, and we understand no
+    // extensions.
+    if protected
+        .iter()
+        .any(|(label, _)| unprotected.iter().any(|(l, _)| l == label))
+    {
+        return Err(Error);
+    }
+    if header_get(protected, LABEL_ALG) != Some(&alg_value(expected_alg).into())
+        || header_get(protected, LABEL_CRIT).is_some()
+        || header_get(unprotected, LABEL_CRIT).is_some()
+        || header_get(protected, LABEL_PARTIAL_IV).is_some()
+        || header_get(unprotected, LABEL_PARTIAL_IV).is_some()
+    {
+        return Err(Error);
+    }
+
+    let iv = header_get(unprotected, LABEL_IV)
+        .and_then(Value::as_bytes)
+        .ok_or(Error)?;
+    if iv.len() != ciph.nonce_len() {
+        return Err(Error);
+    }
+
+    // A nil ciphertext means it's detached, which we don't support
+    let ciphertext = ciphertext.as_bytes().ok_or(Error)?;
+    let aad = enc_structure(context, protected_bytes, external_aad);
+    ciph.decrypt(iv, &aad, ciphertext)
+}
+
+/// Encrypts `plaintext` under `key`, and returns a tagged `COSE_Encrypt0` message. Returns an
+/// error if `key` is the wrong length for `alg`.
+pub fn encrypt0<R>(
+    rng: &mut R,
+    alg: AlgorithmId,
+    key: &[u8],
+    external_aad: &[u8],
+    plaintext: &[u8],
+) -> Result<Vec<u8>, Error>
+where
+    R: CryptoRng + RngCore,
+{
+    let fields = encrypt_layer(rng, alg, key, "Encrypt0", external_aad, plaintext)?;
+    Ok(to_cbor(&Value::Tag(
+        TAG_ENCRYPT0,
+        Box::new(Value::Array(fields)),
+    )))
+}
+
+/// Decrypts a tagged `COSE_Encrypt0` message under `key`, and returns the plaintext. The message's
+/// `alg` must be `expected_alg`, which stops an attacker from choosing the algorithm. Returns an
+/// error if the message is malformed, uses another `alg`, has a `crit` or `Partial IV` header,
+/// has an IV of the wrong length, or fails to decrypt.
+pub fn decrypt0(
+    expected_alg: AlgorithmId,
+    key: &[u8],
+    external_aad: &[u8],
+    msg: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let msg = from_cbor(msg)?;
+    let (tag, fields) = msg.as_tag().ok_or(Error)?;
+    if tag != TAG_ENCRYPT0 {
+        return Err(Error);
+    }
+    let [protected, unprotected, ciphertext] = fields.as_array().ok_or(Error)?.as_slice() else {
+        return Err(Error);
+    };
+
+    decrypt_layer(
+        expected_alg,
+        key,
+        "Encrypt0",
+        external_aad,
+        protected,
+        unprotected,
+        ciphertext,
+    )
+}
+
+/// Encrypts `plaintext` under `key`, and returns a tagged `COSE_Encrypt` message with one direct
+/// recipient, whose `kid` is `kid`. Returns an error if `key` is the wrong length for `alg`.
+pub fn encrypt<R>(
+    rng: &mut R,
+    alg: AlgorithmId,
+    key: &[u8],
+    kid: &[u8],
+    external_aad: &[u8],
+    plaintext: &[u8],
+) -> Result<Vec<u8>, Error>
+where
+    R: CryptoRng + RngCore,
+{
+    let mut fields = encrypt_layer(rng, alg, key, "Encrypt", external_aad, plaintext)?;
+
+    // A direct recipient has an empty protected header and an empty ciphertext
+    let recipient = Value::Array(vec![
+        Value::Bytes(Vec::new()),
+        Value::Map(vec![
+            (LABEL_ALG.into(), ALG_DIRECT.into()),
+            (LABEL_KID.into(), Value::Bytes(kid.to_vec())),
+        ]),
+        Value::Bytes(Vec::new()),
+    ]);
+    fields.push(Value::Array(vec![recipient]));
+
+    Ok(to_cbor(&Value::Tag(
+        TAG_ENCRYPT,
+        Box::new(Value::Array(fields)),
+    )))
+}
+
+/// Decrypts a tagged `COSE_Encrypt` message under `key`, and returns the plaintext. The message
+/// must have exactly one recipient, which uses direct encryption with the key `kid`. Returns an
+/// error under the same conditions as [`decrypt0`], or if the recipient is malformed, isn't direct,
+/// or names another key.
+pub fn decrypt(
+    expected_alg: AlgorithmId,
+    key: &[u8],
+    kid: &[u8],
+    external_aad: &[u8],
+    msg: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let msg = from_cbor(msg)?;
+    let (tag, fields) = msg.as_tag().ok_or(Error)?;
+    if tag != TAG_ENCRYPT {
+        return Err(Error);
+    }
+    let [protected, unprotected, ciphertext, recipients] =
+        fields.as_array().ok_or(Error)?.as_slice()
+    else {
+        return Err(Error);
+    };
+
+    // Check the recipient. Since it's direct, it has no protected header or ciphertext.
+    let [recipient] = recipients.as_array().ok_or(Error)?.as_slice() else {
+        return Err(Error);
+    };
+    let [r_protected, r_unprotected, r_ciphertext] = recipient.as_array().ok_or(Error)?.as_slice()
+    else {
+        return Err(Error);
+    };
+    let r_unprotected = header_map(r_unprotected)?;
+    if r_protected.as_bytes().map(Vec::len) != Some(0)
+        || r_ciphertext.as_bytes().map(Vec::len) != Some(0)
+        || r_unprotected.len() != 2
+        || header_get(r_unprotected, LABEL_ALG) != Some(&ALG_DIRECT.into())
+        || header_get(r_unprotected, LABEL_KID).and_then(Value::as_bytes) != Some(&kid.to_vec())
+    {
+        return Err(Error);
+    }
+
+    decrypt_layer(
+        expected_alg,
+        key,
+        "Encrypt",
+        external_aad,
+        protected,
+        unprotected,
+        ciphertext,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Decodes a message, applies `f` to its fields, and re-encodes it
+    fn modify(msg: &[u8], f: impl FnOnce(&mut Vec<Value>)) -> Vec<u8> {
+        let (tag, fields) = from_cbor(msg).unwrap().into_tag().unwrap();
+        let mut fields = fields.into_array().unwrap();
+        f(&mut fields);
+        to_cbor(&Value::Tag(tag, Box::new(Value::Array(fields))))
+    }
+
+    // Replaces the headers of a message with the given maps. The protected one is serialized.
+    fn with_headers(
+        msg: &[u8],
+        protected: Vec<(Value, Value)>,
+        unprotected: Vec<(Value, Value)>,
+    ) -> Vec<u8> {
+        modify(msg, |fields| {
+            fields[0] = Value::Bytes(to_cbor(&Value::Map(protected)));
+            fields[1] = Value::Map(unprotected);
+        })
+    }
+
+    // The alg values are stable, so pin them, and make sure they're all private-use
+    #[test]
+    fn cose_alg_values() {
+        let values: Vec<i64> = AlgorithmId::ALL.iter().map(|&a| alg_value(a)).collect();
+        assert_eq!(
+            values,
+            [-65537, -65538, -65539, -65540, -65541, -65542, -65543, -65544]
+        );
+
+        for alg in AlgorithmId::ALL {
+            assert_eq!(algorithm(alg_value(alg)), Some(alg));
+        }
+        for v in [PRIVATE_USE_BASE, -65545, ALG_DIRECT, 3, i64::MIN, i64::MAX] {
+            assert_eq!(algorithm(v), None);
+        }
+    }
+
+    #[test]
+    fn cose_encrypt0_correctness() {
+        let mut rng = rand::thread_rng();
+
+        for alg in AlgorithmId::ALL {
+            let key = vec![0x42; alg.key_len()];
+            let msg = encrypt0(&mut rng, alg, &key, b"aad", b"hello world").unwrap();
+            assert_eq!(decrypt0(alg, &key, b"aad", &msg).unwrap(), b"hello world");
+
+            // Encryption is randomized
+            assert_ne!(
+                encrypt0(&mut rng, alg, &key, b"aad", b"hello world").unwrap(),
+                msg
+            );
+
+            // The key, external AAD, and alg must match
+            assert!(decrypt0(alg, &vec![0x43; alg.key_len()], b"aad", &msg).is_err());
+            assert!(decrypt0(alg, &key, b"bad", &msg).is_err());
+            for other in AlgorithmId::ALL {
+                if other != alg && other.key_len() == alg.key_len() {
+                    assert!(decrypt0(other, &key, b"aad", &msg).is_err());
+                }
+            }
+
+            // Keys of the wrong length are rejected
+            assert!(encrypt0(&mut rng, alg, &key[1..], b"", b"").is_err());
+            assert!(decrypt0(alg, &key[1..], b"aad", &msg).is_err());
+
+            // Modifying any byte fails, as does truncating or appending
+            for i in 0..msg.len() {
+                let mut bad = msg.clone();
+                bad[i] ^= 1;
+                assert!(decrypt0(alg, &key, b"aad", &bad).is_err());
+            }
+            for len in 0..msg.len() {
+                assert!(decrypt0(alg, &key, b"aad", &msg[..len]).is_err());
+            }
+            assert!(decrypt0(alg, &key, b"aad", &[msg.as_slice(), &[0]].concat()).is_err());
+
+            // A COSE_Encrypt0 isn't a COSE_Encrypt, or vice versa
+            assert!(decrypt(alg, &key, b"", b"aad", &msg).is_err());
+            let msg = encrypt(&mut rng, alg, &key, b"", b"aad", b"hello world").unwrap();
+            assert!(decrypt0(alg, &key, b"aad", &msg).is_err());
+        }
+    }
+
+    // The message layout matches RFC 9052 §5.2, with the alg protected and the IV unprotected
+    #[test]
+    fn cose_encrypt0_layout() {
+        let mut rng = rand::thread_rng();
+        let alg = AlgorithmId::HkdfHteUtcAes256Gcm;
+        let key = [0x42; 32];
+        let msg = encrypt0(&mut rng, alg, &key, b"", b"hello world").unwrap();
+
+        // Tag 16 is a single byte 0xd0, followed by a 3-element array
+        assert_eq!(&msg[..2], [0xd0, 0x83]);
+
+        let (_, fields) = from_cbor(&msg).unwrap().into_tag().unwrap();
+        let fields = fields.into_array().unwrap();
+        let protected = from_cbor(fields[0].as_bytes().unwrap()).unwrap();
+        assert_eq!(protected, Value::Map(vec![(1.into(), (-65542i64).into())]));
+        let unprotected = fields[1].as_map().unwrap();
+        assert_eq!(unprotected.len(), 1);
+        assert_eq!(unprotected[0].0, Value::from(LABEL_IV));
+
+        // The ciphertext is the payload followed by the full tag, and the associated data is the
+        // Enc_structure
+        let iv = unprotected[0].1.as_bytes().unwrap();
+        let ciphertext = fields[2].as_bytes().unwrap();
+        assert_eq!(ciphertext.len(), 11 + alg.tag_len());
+        let aad = enc_structure("Encrypt0", fields[0].as_bytes().unwrap(), b"");
+        let ciph = AnyKcAead::new(alg, &key).unwrap();
+        assert_eq!(ciph.decrypt(iv, &aad, ciphertext).unwrap(), b"hello world");
+    }
+
+    // Headers can't be changed, even for equivalent ones, since the protected header's bytes are
+    // in the associated data. Unknown extensions and misplaced parameters are rejected.
+    #[test]
+    fn cose_encrypt0_tampered_headers() {
+        let mut rng = rand::thread_rng();
+        let alg = AlgorithmId::UtcAes256Gcm;
+        let key = [0x42; 32];
+        let msg = encrypt0(&mut rng, alg, &key, b"", b"hello world").unwrap();
+
+        let (_, fields) = from_cbor(&msg).unwrap().into_tag().unwrap();
+        let fields = fields.into_array().unwrap();
+        let iv = fields[1].as_map().unwrap()[0].clone();
+        let alg_entry = || (Value::from(LABEL_ALG), Value::from(alg_value(alg)));
+
+        let bad_headers = [
+            // The same header, with an extra parameter
+            (
+                vec![alg_entry(), (LABEL_KID.into(), Value::Bytes(vec![1]))],
+                vec![iv.clone()],
+            ),
+            // Another alg, or none
+            (
+                vec![(
+                    LABEL_ALG.into(),
+                    alg_value(AlgorithmId::UtcAes128Gcm).into(),
+                )],
+                vec![iv.clone()],
+            ),
+            (vec![(LABEL_ALG.into(), 3.into())], vec![iv.clone()]),
+            (vec![], vec![iv.clone()]),
+            // The alg in the unprotected header
+            (vec![], vec![alg_entry(), iv.clone()]),
+            // A repeated label
+            (vec![alg_entry(), alg_entry()], vec![iv.clone()]),
+            (vec![alg_entry()], vec![iv.clone(), iv.clone()]),
+            // A label in both buckets
+            (vec![alg_entry(), iv.clone()], vec![iv.clone()]),
+            // No IV, or one in the wrong place
+            (vec![alg_entry()], vec![]),
+            (vec![alg_entry(), iv.clone()], vec![]),
+            // Extensions
+            (
+                vec![alg_entry(), (LABEL_CRIT.into(), Value::Array(vec![]))],
+                vec![iv.clone()],
+            ),
+            (
+                vec![alg_entry()],
+                vec![iv.clone(), (LABEL_PARTIAL_IV.into(), Value::Bytes(vec![1]))],
+            ),
+        ];
+        for (protected, unprotected) in bad_headers {
+            let bad = with_headers(&msg, protected, unprotected);
+            assert!(decrypt0(alg, &key, b"", &bad).is_err());
+        }
+
+        // Re-encoding the same headers works, so the checks above are about the headers
+        let same = with_headers(&msg, vec![alg_entry()], vec![iv.clone()]);
+        assert_eq!(decrypt0(alg, &key, b"", &same).unwrap(), b"hello world");
+
+        // IVs of the wrong length are rejected
+        for len in [0, 11, 13] {
+            let bad_iv = (Value::from(LABEL_IV), Value::Bytes(vec![0; len]));
+            let bad = with_headers(&msg, vec![alg_entry()], vec![bad_iv]);
+            assert!(decrypt0(alg, &key, b"", &bad).is_err());
+        }
+
+        // A detached ciphertext, or a wrong structure
+        let nil = modify(&msg, |fields| fields[2] = Value::Null);
+        assert!(decrypt0(alg, &key, b"", &nil).is_err());
+        let short = modify(&msg, |fields| {
+            fields.pop();
+        });
+        assert!(decrypt0(alg, &key, b"", &short).is_err());
+        let untagged = to_cbor(&from_cbor(&msg).unwrap().into_tag().unwrap().1);
+        assert!(decrypt0(alg, &key, b"", &untagged).is_err());
+    }
+
+    #[test]
+    fn cose_encrypt_correctness() {
+        let mut rng = rand::thread_rng();
+        let alg = AlgorithmId::HkdfHteUtcAes256Gcm;
+        let key = [0x42; 32];
+        let msg = encrypt(&mut rng, alg, &key, b"key 1", b"aad", b"hello world").unwrap();
+        assert_eq!(&msg[..3], [0xd8, 0x60, 0x84]);
+        assert_eq!(
+            decrypt(alg, &key, b"key 1", b"aad", &msg).unwrap(),
+            b"hello world"
+        );
+
+        // The key, kid, and external AAD must match
+        assert!(decrypt(alg, &[0x43; 32], b"key 1", b"aad", &msg).is_err());
+        assert!(decrypt(alg, &key, b"key 2", b"aad", &msg).is_err());
+        assert!(decrypt(alg, &key, b"key 1", b"bad", &msg).is_err());
+
+        // Modifying any byte fails
+        for i in 0..msg.len() {
+            let mut bad = msg.clone();
+            bad[i] ^= 1;
+            assert!(decrypt(alg, &key, b"key 1", b"aad", &bad).is_err());
+        }
+
+        // The Enc_structure context differs from COSE_Encrypt0's, so the layer can't be moved
+        // into a COSE_Encrypt0
+        let moved = modify(&msg, |fields| {
+            fields.pop();
+        });
+        let (_, fields) = from_cbor(&moved).unwrap().into_tag().unwrap();
+        let moved = to_cbor(&Value::Tag(TAG_ENCRYPT0, Box::new(*fields)));
+        assert!(decrypt0(alg, &key, b"aad", &moved).is_err());
+
+        // Recipients must be a single direct recipient with no protected header or ciphertext
+        let direct = |kid: &[u8]| {
+            Value::Map(vec![
+                (LABEL_ALG.into(), ALG_DIRECT.into()),
+                (LABEL_KID.into(), Value::Bytes(kid.to_vec())),
+            ])
+        };
+        let recipient = |protected: &[u8], unprotected, ciphertext: &[u8]| {
+            Value::Array(vec![
+                Value::Bytes(protected.to_vec()),
+                unprotected,
+                Value::Bytes(ciphertext.to_vec()),
+            ])
+        };
+        let bad_recipients = [
+            vec![],
+            vec![
+                recipient(b"", direct(b"key 1"), b""),
+                recipient(b"", direct(b"key 1"), b""),
+            ],
+            vec![recipient(b"\xa0", direct(b"key 1"), b"")],
+            vec![recipient(b"", direct(b"key 1"), b"\x00")],
+            vec![recipient(
+                b"",
+                Value::Map(vec![
+                    (LABEL_ALG.into(), (-3).into()),
+                    (LABEL_KID.into(), Value::Bytes(b"key 1".to_vec())),
+                ]),
+                b"",
+            )],
+            vec![recipient(
+                b"",
+                Value::Map(vec![
+                    (LABEL_ALG.into(), ALG_DIRECT.into()),
+                    (LABEL_KID.into(), Value::Bytes(b"key 1".to_vec())),
+                    (LABEL_CRIT.into(), Value::Array(vec![])),
+                ]),
+                b"",
+            )],
+        ];
+        for recipients in bad_recipients {
+            let bad = modify(&msg, |fields| fields[3] = Value::Array(recipients));
+            assert!(decrypt(alg, &key, b"key 1", b"aad", &bad).is_err());
+        }
+
+        // Re-encoding the same recipient works
+        let same = modify(&msg, |fields| {
+            fields[3] = Value::Array(vec![recipient(b"", direct(b"key 1"), b"")])
+        });
+        assert_eq!(
+            decrypt(alg, &key, b"key 1", b"aad", &same).unwrap(),
+            b"hello world"
+        );
+    }
+}
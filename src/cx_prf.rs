@@ -1,9 +1,11 @@
 //! Defines the `CX[E]` committing PRF scheme described in <https://eprint.iacr.org/2022/268> §7
 
-use crate::util::{CommittingPrf, DoubleKeySize};
+use crate::util::{CommittingPrf, DoubleKeySize, BATCH_SIZE};
 
 use core::marker::PhantomData;
 
+use aead::Error;
+
 use cipher::{
     generic_array::{arr::AddLength, ArrayLength, GenericArray},
     typenum::Unsigned,
@@ -17,6 +19,9 @@ pub(crate) type CxMask<Ciph> = Key<Ciph>;
 // Com has to be collision resistant. So it should be 2x the keysize
 pub(crate) type CxCom<Ciph> = GenericArray<u8, DoubleKeySize<Ciph>>;
 
+// The largest number of blocks a single PRF evaluation uses. This is 6 for AES-256.
+const MAX_BLOCKS_PER_MSG: usize = 6;
+
 /// The `CX[E]` committing PRF, defined over a block cipher `E`.
 ///
 /// NOTE: `E::KeySize` MUST be a multiple of `E::BlockSize`. `Self::prf()` will panic otherwise.
@@ -97,41 +102,105 @@ where
         GenericArray<u8, Self::ComSize>,
         GenericArray<u8, Self::MaskSize>,
     ) {
-        // These should be a rounding-up division. But the numerator is always a multiple of block
-        // size so it doesn't matter.
-        let num_com_blocks = Self::ComSize::USIZE / Ciph::BlockSize::USIZE;
-        let num_mask_blocks = Self::MaskSize::USIZE / Ciph::BlockSize::USIZE;
-        let num_total_blocks = num_com_blocks + num_mask_blocks;
-
-        // Compute pad(nonce, 1), pad(nonce, 2), pad(nonce, 3), pad(nonce 4), where pad(M, i) is
-        // the concatenation of M and a (block_size - msg_size)-bit encoding of i.
+        let num_total_blocks = Self::num_total_blocks();
 
         // In stable we can't make an array of size num_com_blocks + num_mask_blocks. The error is
         //     Error: constant expression depends on a generic parameter
         // This requires the const_evaluatable_checked feature
         // (https://github.com/rust-lang/rust/issues/76560). So instead we just use a buf of the
         // maximum size, 6 blocks, and take an appropriately sized slice.
-        let mut block_buf = [Block::<Ciph>::default(); 6];
+        let mut block_buf = [Block::<Ciph>::default(); MAX_BLOCKS_PER_MSG];
         let blocks = &mut block_buf[..num_total_blocks];
 
+        // Pad the message, encrypt all the blocks, and split them into com and mask
+        Self::pad_blocks(msg, blocks);
+        self.ciph.encrypt_blocks(blocks);
+        Self::finish(msg, blocks)
+    }
+
+    /// The `CX[E]` PRF, evaluated over many messages. This packs the blocks of up to 8 messages
+    /// into a single `encrypt_blocks` call, so that hardware-accelerated block ciphers can
+    /// pipeline them.
+    fn prf_batch(
+        &self,
+        msgs: &[GenericArray<u8, MsgSize>],
+        out: &mut [(CxCom<Ciph>, CxMask<Ciph>)],
+    ) -> Result<(), Error> {
+        if msgs.len() != out.len() {
+            return Err(Error);
+        }
+
+        let num_total_blocks = Self::num_total_blocks();
+
+        // Same reasoning as above. We use a buf big enough for BATCH_SIZE messages
+        let mut block_buf = [Block::<Ciph>::default(); MAX_BLOCKS_PER_MSG * BATCH_SIZE];
+
+        for (msg_chunk, out_chunk) in msgs.chunks(BATCH_SIZE).zip(out.chunks_mut(BATCH_SIZE)) {
+            let blocks = &mut block_buf[..num_total_blocks * msg_chunk.len()];
+
+            // Pad every message in the chunk, then encrypt all their blocks at once
+            for (msg, msg_blocks) in msg_chunk.iter().zip(blocks.chunks_mut(num_total_blocks)) {
+                Self::pad_blocks(msg, msg_blocks);
+            }
+            self.ciph.encrypt_blocks(blocks);
+
+            // Split each message's blocks into its com and mask
+            for ((msg, msg_blocks), o) in msg_chunk
+                .iter()
+                .zip(blocks.chunks_mut(num_total_blocks))
+                .zip(out_chunk.iter_mut())
+            {
+                *o = Self::finish(msg, msg_blocks);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<Ciph, MsgSize> CxPrf<Ciph, MsgSize>
+where
+    MsgSize: ArrayLength<u8>,
+    Ciph: BlockEncrypt + KeyInit,
+    <Ciph::BlockSize as ArrayLength<u8>>::ArrayType: Copy,
+    Ciph::KeySize: AddLength<u8, Ciph::KeySize>,
+{
+    /// Returns the number of blocks that one PRF evaluation needs
+    fn num_total_blocks() -> usize {
+        // These should be a rounding-up division. But the numerator is always a multiple of block
+        // size so it doesn't matter.
+        let num_com_blocks = DoubleKeySize::<Ciph>::USIZE / Ciph::BlockSize::USIZE;
+        let num_mask_blocks = Ciph::KeySize::USIZE / Ciph::BlockSize::USIZE;
+        num_com_blocks + num_mask_blocks
+    }
+
+    /// Computes pad(msg, 1), pad(msg, 2), ..., pad(msg, blocks.len()), where pad(M, i) is the
+    /// concatenation of M and a (block_size - msg_size)-bit encoding of i.
+    fn pad_blocks(msg: &GenericArray<u8, MsgSize>, blocks: &mut [Block<Ciph>]) {
         for (i, block) in blocks.iter_mut().enumerate() {
+            *block = Block::<Ciph>::default();
             block[..MsgSize::USIZE].copy_from_slice(msg);
             block[Ciph::BlockSize::USIZE - 1] = (i + 1) as u8;
         }
+    }
 
-        // Save block 0 for XORing
-        let block0 = blocks[0].clone();
-
-        // Now encrypt all the blocks
-        self.ciph.encrypt_blocks(blocks);
+    /// Given the encrypted blocks of `msg`, XORs pad(msg, 1) into the 0th block, and splits the
+    /// blocks into `(com, mask)`
+    fn finish(
+        msg: &GenericArray<u8, MsgSize>,
+        blocks: &mut [Block<Ciph>],
+    ) -> (CxCom<Ciph>, CxMask<Ciph>) {
+        let num_com_blocks = DoubleKeySize::<Ciph>::USIZE / Ciph::BlockSize::USIZE;
 
-        // Finally, XOR block 0 into the 0th ciphertext
+        // XOR block 0 into the 0th ciphertext
+        let mut block0 = [Block::<Ciph>::default()];
+        Self::pad_blocks(msg, &mut block0);
         blocks[0]
             .iter_mut()
-            .zip(block0.iter())
+            .zip(block0[0].iter())
             .for_each(|(c, m)| *c ^= m);
 
-        // com is the first `num_com_blocks` blocks, and mask is the next `num_mask_blocks` blocks
+        // com is the first `num_com_blocks` blocks, and mask is the rest
         let com = CxCom::<Ciph>::from_exact_iter(
             blocks
                 .iter()
@@ -144,7 +213,6 @@ where
             blocks
                 .iter()
                 .skip(num_com_blocks)
-                .take(num_mask_blocks)
                 .flat_map(IntoIterator::into_iter)
                 .cloned(),
         )
@@ -202,6 +270,36 @@ mod test {
         CxPrf::<Aes256, U12>::new(&key).prf(&nonce);
     }
 
+    // Make sure that prf_batch() agrees with prf() over enough messages to span several batches
+    #[test]
+    fn cx_batch() {
+        let mut rng = thread_rng();
+
+        let key = {
+            let mut buf = Key::<Aes256>::default();
+            rng.fill_bytes(buf.as_mut_slice());
+            buf
+        };
+        let prf = CxPrf::<Aes256, U12>::new(&key);
+
+        let nonces: Vec<GenericArray<u8, U12>> = (0..3 * BATCH_SIZE + 1)
+            .map(|_| {
+                let mut buf = GenericArray::default();
+                rng.fill_bytes(&mut buf);
+                buf
+            })
+            .collect();
+        let mut outs = vec![Default::default(); nonces.len()];
+        prf.prf_batch(&nonces, &mut outs).unwrap();
+
+        for (nonce, out) in nonces.iter().zip(outs.iter()) {
+            assert_eq!(&prf.prf(nonce), out);
+        }
+
+        // Mismatched lengths are an error
+        assert!(prf.prf_batch(&nonces, &mut outs[1..]).is_err());
+    }
+
     #[should_panic]
     #[test]
     fn cx_aes192() {
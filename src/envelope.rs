@@ -0,0 +1,248 @@
+//! Defines a self-describing, versioned ciphertext envelope, which records which committing AEAD
+//! made the ciphertext. The envelope is laid out as
+//!
+//! ```text
+//! header = version || be16(algorithm_id) || nonce
+//! envelope = header || ciphertext || tag
+//! ```
+//!
+//! where `version` is one byte, currently [`VERSION`], and the nonce and tag lengths are those of
+//! the algorithm. The header is prepended to the caller's associated data, so changing any part of
+//! it, including the algorithm, causes decryption to fail. Only the algorithms in [`AlgorithmId`]
+//! can be named by an envelope, and they are all committing. Still, they don't all commit to the
+//! same things, so [`open`] takes the set of algorithms the caller allows, and rejects an envelope
+//! naming any other before decrypting it.
+
+use crate::{AlgorithmId, AnyKcAead};
+
+use aead::Error;
+
+/// The current envelope version
+pub const VERSION: u8 = 1;
+
+// The sizes of the version and algorithm fields
+const VERSION_LEN: usize = 1;
+const ALG_ID_LEN: usize = 2;
+
+/// A parsed envelope. The fields borrow from the envelope bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Envelope<'a> {
+    /// The algorithm which made the ciphertext
+    pub alg: AlgorithmId,
+    /// The header, i.e., the version, algorithm, and nonce
+    pub header: &'a [u8],
+    /// The nonce, which is `alg.nonce_len()` bytes long
+    pub nonce: &'a [u8],
+    /// The ciphertext, without the tag
+    pub ciphertext: &'a [u8],
+    /// The tag, which is `alg.tag_len()` bytes long
+    pub tag: &'a [u8],
+}
+
+impl<'a> Envelope<'a> {
+    /// Parses an envelope. Returns an error if the version is unknown, the algorithm is unknown,
+    /// or the envelope is too short to hold a header and tag. This does not decrypt anything.
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, Error> {
+        if bytes.len() < VERSION_LEN + ALG_ID_LEN {
+            return Err(Error);
+        }
+        if bytes[0] != VERSION {
+            return Err(Error);
+        }
+        let alg_id = u16::from_be_bytes([bytes[1], bytes[2]]);
+        let alg = AlgorithmId::from_u16(alg_id).ok_or(Error)?;
+
+        let header_len = VERSION_LEN + ALG_ID_LEN + alg.nonce_len();
+        if bytes.len() < header_len + alg.tag_len() {
+            return Err(Error);
+        }
+        let (header, rest) = bytes.split_at(header_len);
+        let (ciphertext, tag) = rest.split_at(rest.len() - alg.tag_len());
+
+        Ok(Envelope {
+            alg,
+            header,
+            nonce: &header[VERSION_LEN + ALG_ID_LEN..],
+            ciphertext,
+            tag,
+        })
+    }
+}
+
+/// Returns the header prepended to the associated data
+fn full_aad(header: &[u8], associated_data: &[u8]) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(header.len() + associated_data.len());
+    aad.extend_from_slice(header);
+    aad.extend_from_slice(associated_data);
+    aad
+}
+
+/// Encrypts `msg` with the given algorithm and returns the envelope. The nonce MUST NOT be reused
+/// under the same key.
+///
+/// Returns an error if the key or nonce is the wrong length for `alg`.
+pub fn seal(
+    alg: AlgorithmId,
+    key: &[u8],
+    nonce: &[u8],
+    associated_data: &[u8],
+    msg: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let ciph = AnyKcAead::new(alg, key)?;
+    if nonce.len() != alg.nonce_len() {
+        return Err(Error);
+    }
+
+    let mut out =
+        Vec::with_capacity(VERSION_LEN + ALG_ID_LEN + alg.nonce_len() + msg.len() + alg.tag_len());
+    out.push(VERSION);
+    out.extend_from_slice(&alg.to_u16().to_be_bytes());
+    out.extend_from_slice(nonce);
+
+    let aad = full_aad(&out, associated_data);
+    out.extend_from_slice(&ciph.encrypt(nonce, &aad, msg)?);
+
+    Ok(out)
+}
+
+/// Parses the envelope, decrypts it with the algorithm it names, and returns the plaintext. The
+/// algorithm must be in `allowed`. Otherwise, whoever made the envelope could pick the algorithm,
+/// e.g., a key-committing UtC alias in place of a context-committing HtE one. To require context
+/// commitment, allow only the algorithms for which [`AlgorithmId::is_context_committing`] holds.
+///
+/// Returns an error if the envelope is malformed, names an algorithm not in `allowed`, the key is
+/// the wrong length for the algorithm, or decryption fails.
+pub fn open(
+    allowed: &[AlgorithmId],
+    key: &[u8],
+    associated_data: &[u8],
+    envelope: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let env = Envelope::parse(envelope)?;
+    if !allowed.contains(&env.alg) {
+        return Err(Error);
+    }
+    let ciph = AnyKcAead::new(env.alg, key)?;
+    let aad = full_aad(env.header, associated_data);
+
+    let mut buf = env.ciphertext.to_vec();
+    ciph.decrypt_in_place_detached(env.nonce, &aad, &mut buf, env.tag)?;
+
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::random_bytes;
+
+    #[test]
+    fn envelope_correctness() {
+        for alg in AlgorithmId::ALL {
+            let key = random_bytes(alg.key_len());
+            let nonce = random_bytes(alg.nonce_len());
+
+            for msg_len in [0, 1, 100] {
+                let msg = random_bytes(msg_len);
+                let env = seal(alg, &key, &nonce, b"aad", &msg).unwrap();
+
+                let parsed = Envelope::parse(&env).unwrap();
+                assert_eq!(parsed.alg, alg);
+                assert_eq!(parsed.nonce, nonce);
+                assert_eq!(parsed.ciphertext.len(), msg_len);
+
+                // open picks the algorithm from the envelope
+                assert_eq!(open(&AlgorithmId::ALL, &key, b"aad", &env).unwrap(), msg);
+                assert!(open(&AlgorithmId::ALL, &key, b"aae", &env).is_err());
+
+                // Modifying any byte fails, whether it's caught by parsing or decryption
+                for i in 0..env.len() {
+                    let mut bad_env = env.clone();
+                    bad_env[i] ^= 1;
+                    assert!(open(&AlgorithmId::ALL, &key, b"aad", &bad_env).is_err());
+                }
+            }
+        }
+    }
+
+    // Relabeling an envelope as another algorithm with the same key size must fail
+    #[test]
+    fn envelope_alg_substitution() {
+        let alg = AlgorithmId::UtcAes256Gcm;
+        let key = random_bytes(alg.key_len());
+        let nonce = random_bytes(alg.nonce_len());
+        let env = seal(alg, &key, &nonce, b"", b"hello world").unwrap();
+
+        for other in AlgorithmId::ALL {
+            if other == alg {
+                continue;
+            }
+            let mut bad_env = env.clone();
+            bad_env[1..3].copy_from_slice(&other.to_u16().to_be_bytes());
+            assert!(open(&AlgorithmId::ALL, &key, b"", &bad_env).is_err());
+        }
+    }
+
+    // An honest envelope for an algorithm outside the allowed set is rejected, even under the
+    // right key
+    #[test]
+    fn envelope_disallowed_alg() {
+        let context_committing: Vec<AlgorithmId> = AlgorithmId::ALL
+            .into_iter()
+            .filter(|alg| alg.is_context_committing())
+            .collect();
+
+        for alg in AlgorithmId::ALL {
+            let key = random_bytes(alg.key_len());
+            let nonce = random_bytes(alg.nonce_len());
+            let env = seal(alg, &key, &nonce, b"", b"hello world").unwrap();
+
+            // A policy requiring context commitment only opens the HtE algorithms
+            assert_eq!(
+                open(&context_committing, &key, b"", &env).is_ok(),
+                alg.is_context_committing()
+            );
+
+            // Allowing exactly the envelope's algorithm works, and anything else doesn't
+            assert!(open(&[alg], &key, b"", &env).is_ok());
+            let others: Vec<AlgorithmId> =
+                AlgorithmId::ALL.into_iter().filter(|&a| a != alg).collect();
+            assert!(open(&others, &key, b"", &env).is_err());
+            assert!(open(&[], &key, b"", &env).is_err());
+        }
+    }
+
+    #[test]
+    fn envelope_malformed() {
+        let alg = AlgorithmId::HkdfHteUtcAes128Gcm;
+        let key = random_bytes(alg.key_len());
+        let nonce = random_bytes(alg.nonce_len());
+        let env = seal(alg, &key, &nonce, b"", b"").unwrap();
+        let min_len = VERSION_LEN + ALG_ID_LEN + alg.nonce_len() + alg.tag_len();
+        assert_eq!(env.len(), min_len);
+
+        // Anything shorter than a header and tag fails to parse
+        for len in 0..min_len {
+            assert!(Envelope::parse(&env[..len]).is_err());
+        }
+
+        // Unknown versions fail to parse
+        for version in [0, 2, 0xff] {
+            let mut bad_env = env.clone();
+            bad_env[0] = version;
+            assert!(Envelope::parse(&bad_env).is_err());
+        }
+
+        // Unknown and reserved algorithms fail to parse
+        for id in [0, 9, 0x8000, u16::MAX] {
+            let mut bad_env = env.clone();
+            bad_env[1..3].copy_from_slice(&id.to_be_bytes());
+            assert!(Envelope::parse(&bad_env).is_err());
+        }
+
+        // Wrong key and nonce lengths are errors, not panics
+        assert!(open(&[alg], &key[1..], b"", &env).is_err());
+        assert!(seal(alg, &key[1..], &nonce, b"", b"").is_err());
+        assert!(seal(alg, &key, &nonce[1..], b"", b"").is_err());
+    }
+}
@@ -0,0 +1,284 @@
+//! Defines `FusedHteUtc`, a context-committing AEAD which collapses the key derivations of
+//! `HtE[UtC[F, A], H]` into a single HKDF-Expand per message
+
+use crate::utc_transform::{Commitment, DetachedCommitment, InnerTag};
+
+use core::marker::PhantomData;
+
+use aead::{AeadCore, AeadInPlace, Error, Key, NewAead, Nonce, Tag};
+use aes_gcm::{Aes128Gcm, Aes256Gcm, ClobberingDecrypt};
+use cipher::{
+    generic_array::{arr::AddLength, GenericArray},
+    typenum::Unsigned,
+    BlockSizeUser,
+};
+use digest::{Digest, OutputSizeUser};
+use hkdf::SimpleHkdf;
+use sha2::{Sha256, Sha512};
+use subtle::ConstantTimeEq;
+use zeroize::Zeroize;
+
+/// A context-committing AEAD built on top of AES-128-GCM. This has the same tag size as
+/// `HkdfHteUtcAes128Gcm`, but it is a different scheme and its ciphertexts are not compatible.
+pub type FusedHteUtcAes128Gcm = FusedHteUtc<Aes128Gcm, Sha256>;
+
+/// A context-committing AEAD built on top of AES-256-GCM. This has the same tag size as
+/// `HkdfHteUtcAes256Gcm`, but it is a different scheme and its ciphertexts are not compatible.
+pub type FusedHteUtcAes256Gcm = FusedHteUtc<Aes256Gcm, Sha512>;
+
+// The commitment is 2x the key size, like in UtC
+type FusedComSize<A> = <<A as NewAead>::KeySize as AddLength<u8, <A as NewAead>::KeySize>>::Output;
+
+// The HKDF output is com || mask
+type FusedOkmSize<A> = <FusedComSize<A> as AddLength<u8, <A as NewAead>::KeySize>>::Output;
+
+// Here's the current definition:
+//
+// FusedHteUtc[H,A].Enc(K, N, A, M):
+//     prk ← HKDF[H].Extract(salt="FusedHteUtc", ikm=K)
+//     com || mask ← HKDF[H].Expand(prk, info=N || A, len=3*|K|)
+//     (C, T) ← A.Enc(mask, N, "", M)
+//     return (C, T || com)
+//
+// FusedHteUtc[H,A].Dec(K, N, A, C, T'):
+//     (T, com) ← T'
+//     prk ← HKDF[H].Extract(salt="FusedHteUtc", ikm=K)
+//     expected_com || mask ← HKDF[H].Expand(prk, info=N || A, len=3*|K|)
+//     if com != expected_com:
+//         return ⊥
+//     else:
+//         M ← A.Dec(mask, N, "", C, T)
+//         return M
+//
+// Compare this to HtE[UtC[F, A], H], which computes L ← H(K, (N, A)) and then (com, mask) ←
+// F(L, N). Write G(K, (N, A)) = F(H(K, (N, A)), N). Then HtE[UtC[F, A], H] is the same thing as
+// UtC[G, A] run with empty associated data, where G takes the whole context (N, A) as its input.
+// The above is exactly UtC[G', A] where G' is a single HKDF call on (N, A). If H, F, and HKDF are
+// modeled as random oracles, then G and G' are both random functions of (K, N, A), except that G
+// can additionally fail on a collision of the |K|-bit intermediate key L, a term which already
+// appears in the HtE bound. So the privacy and CMT-4 bounds of HtE[UtC[F, A], H] carry over to
+// FusedHteUtc.
+
+const EXTRACT_DOMAIN_SEP: &[u8] = b"FusedHteUtc";
+
+/// A context-committing AEAD over a generic AEAD and hash function. This is equivalent in
+/// security to `HkdfHte<Utc<A, F>, H>`, but it derives the commitment and the encryption key of
+/// `A` with one HKDF-Expand call, rather than an HKDF-Expand, HKDF-Extract, and two more
+/// HKDF-Expands.
+pub struct FusedHteUtc<A, H>
+where
+    A: AeadInPlace + NewAead,
+    H: BlockSizeUser + Clone + Digest + OutputSizeUser,
+    A::KeySize: AddLength<u8, A::KeySize>,
+    FusedComSize<A>: AddLength<u8, A::KeySize> + AddLength<u8, A::TagSize>,
+{
+    hkdf: SimpleHkdf<H>,
+    _marker: PhantomData<A>,
+}
+
+impl<A, H> AeadCore for FusedHteUtc<A, H>
+where
+    A: AeadInPlace + NewAead,
+    H: BlockSizeUser + Clone + Digest + OutputSizeUser,
+    A::KeySize: AddLength<u8, A::KeySize>,
+    FusedComSize<A>: AddLength<u8, A::KeySize> + AddLength<u8, A::TagSize>,
+{
+    /// Tag size is commitment size + original tag size, same as UtC
+    type TagSize = <FusedComSize<A> as AddLength<u8, A::TagSize>>::Output;
+
+    /// Nonce size is the same
+    type NonceSize = A::NonceSize;
+
+    /// Ciphertext overhead is the same
+    type CiphertextOverhead = A::CiphertextOverhead;
+}
+
+impl<A, H> NewAead for FusedHteUtc<A, H>
+where
+    A: AeadInPlace + NewAead,
+    H: BlockSizeUser + Clone + Digest + OutputSizeUser,
+    A::KeySize: AddLength<u8, A::KeySize>,
+    FusedComSize<A>: AddLength<u8, A::KeySize> + AddLength<u8, A::TagSize>,
+{
+    type KeySize = A::KeySize;
+
+    fn new(key: &Key<Self>) -> Self {
+        FusedHteUtc {
+            hkdf: SimpleHkdf::extract(Some(EXTRACT_DOMAIN_SEP), key).1,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<A, H> FusedHteUtc<A, H>
+where
+    A: AeadInPlace + NewAead,
+    H: BlockSizeUser + Clone + Digest + OutputSizeUser,
+    A::KeySize: AddLength<u8, A::KeySize>,
+    FusedComSize<A>: AddLength<u8, A::KeySize> + AddLength<u8, A::TagSize>,
+{
+    /// Derives `com || mask` from the nonce and associated data in one HKDF-Expand
+    fn derive_com_and_mask(
+        &self,
+        nonce: &Nonce<Self>,
+        associated_data: &[u8],
+    ) -> (GenericArray<u8, FusedComSize<A>>, Key<A>) {
+        // This only fails if the output size is greater than 255*HashLen, which is way too big
+        let mut okm = GenericArray::<u8, FusedOkmSize<A>>::default();
+        self.hkdf
+            .expand_multi_info(&[nonce, associated_data], &mut okm)
+            .expect("key size is far too large");
+
+        let com_size = FusedComSize::<A>::USIZE;
+        let com = GenericArray::clone_from_slice(&okm[..com_size]);
+        let mask = Key::<A>::clone_from_slice(&okm[com_size..]);
+        okm.zeroize();
+
+        (com, mask)
+    }
+}
+
+impl<A, H> DetachedCommitment for FusedHteUtc<A, H>
+where
+    A: AeadInPlace + ClobberingDecrypt + NewAead,
+    H: BlockSizeUser + Clone + Digest + OutputSizeUser,
+    A::KeySize: AddLength<u8, A::KeySize>,
+    FusedComSize<A>: AddLength<u8, A::KeySize> + AddLength<u8, A::TagSize>,
+{
+    type InnerTagSize = A::TagSize;
+    type ComSize = FusedComSize<A>;
+
+    fn encrypt_in_place_detached_with_commitment(
+        &self,
+        nonce: &Nonce<Self>,
+        associated_data: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<(InnerTag<Self>, Commitment<Self>), Error> {
+        // Generate the commitment and mask
+        let (com, mask) = self.derive_com_and_mask(nonce, associated_data);
+
+        // Now use the mask as an encryption key. The associated data is excluded
+        let ciph = A::new(&mask);
+        let ciph_tag = ciph.encrypt_in_place_detached(nonce, &[], buffer)?;
+
+        Ok((ciph_tag, com))
+    }
+
+    fn decrypt_in_place_detached_with_commitment(
+        &self,
+        nonce: &Nonce<Self>,
+        associated_data: &[u8],
+        buffer: &mut [u8],
+        ciph_tag: &InnerTag<Self>,
+        com: &Commitment<Self>,
+    ) -> Result<(), Error> {
+        // Generate the commitment and mask
+        let (expected_com, mask) = self.derive_com_and_mask(nonce, associated_data);
+
+        // Now use the mask as an encryption key. The associated data is excluded
+        let ciph = A::new(&mask);
+        let decryption_success = ciph.clobbering_decrypt(nonce, &[], buffer, ciph_tag)?;
+
+        // Check that the commitments match
+        let com_matches = com.ct_eq(&expected_com);
+
+        // If the decryption AND the commitment checks succeeded, return Ok(()). Otherwise,
+        // re-encrypt the plaintext and error out.
+        if (decryption_success & com_matches).unwrap_u8() == 1 {
+            Ok(())
+        } else {
+            // Unclobber so the caller doesn't see unauthenticated plaintext
+            ciph.unclobber(nonce, buffer, ciph_tag);
+            Err(Error)
+        }
+    }
+
+    fn commitment(&self, nonce: &Nonce<Self>, associated_data: &[u8]) -> Commitment<Self> {
+        let (com, mut mask) = self.derive_com_and_mask(nonce, associated_data);
+        mask.zeroize();
+        com
+    }
+}
+
+impl<A, H> AeadInPlace for FusedHteUtc<A, H>
+where
+    A: AeadInPlace + ClobberingDecrypt + NewAead,
+    H: BlockSizeUser + Clone + Digest + OutputSizeUser,
+    A::KeySize: AddLength<u8, A::KeySize>,
+    FusedComSize<A>: AddLength<u8, A::KeySize> + AddLength<u8, A::TagSize>,
+{
+    fn encrypt_in_place_detached(
+        &self,
+        nonce: &Nonce<Self>,
+        associated_data: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<Tag<Self>, Error> {
+        let (ciph_tag, com) =
+            self.encrypt_in_place_detached_with_commitment(nonce, associated_data, buffer)?;
+
+        // The tag is ciph_tag || com
+        let mut tag = Tag::<Self>::default();
+        tag[..A::TagSize::USIZE].copy_from_slice(&ciph_tag);
+        tag[A::TagSize::USIZE..].copy_from_slice(&com);
+
+        Ok(tag)
+    }
+
+    fn decrypt_in_place_detached(
+        &self,
+        nonce: &Nonce<Self>,
+        associated_data: &[u8],
+        buffer: &mut [u8],
+        tag: &Tag<Self>,
+    ) -> Result<(), Error> {
+        // Unpack the tag into ciph_tag || com
+        let ciph_tag = InnerTag::<Self>::from_slice(&tag[..A::TagSize::USIZE]);
+        let com = Commitment::<Self>::from_slice(&tag[A::TagSize::USIZE..]);
+
+        self.decrypt_in_place_detached_with_commitment(
+            nonce,
+            associated_data,
+            buffer,
+            ciph_tag,
+            com,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::{test_aead_correctness, test_detached_commitment};
+
+    test_aead_correctness!(FusedHteUtcAes128Gcm, fusedhte_utc_aes128_correctness);
+    test_aead_correctness!(FusedHteUtcAes256Gcm, fusedhte_utc_aes256_correctness);
+
+    test_detached_commitment!(
+        FusedHteUtcAes128Gcm,
+        fusedhte_utc_aes128_detached_commitment
+    );
+    test_detached_commitment!(
+        FusedHteUtcAes256Gcm,
+        fusedhte_utc_aes256_detached_commitment
+    );
+
+    // The associated data is not passed to the underlying AEAD, so make sure it's bound by the
+    // commitment
+    #[test]
+    fn fusedhte_aad_is_committed() {
+        let mut rng = rand::thread_rng();
+        let ciph = FusedHteUtcAes128Gcm::new(&FusedHteUtcAes128Gcm::generate_key(&mut rng));
+        let nonce = Nonce::<FusedHteUtcAes128Gcm>::default();
+
+        let mut buf = b"hello world".to_vec();
+        let tag = ciph
+            .encrypt_in_place_detached(&nonce, b"aad", &mut buf)
+            .unwrap();
+        assert!(ciph
+            .decrypt_in_place_detached(&nonce, b"aae", &mut buf, &tag)
+            .is_err());
+        ciph.decrypt_in_place_detached(&nonce, b"aad", &mut buf, &tag)
+            .unwrap();
+        assert_eq!(buf, b"hello world");
+    }
+}
@@ -4,12 +4,13 @@ use crate::util::{CommittingPrf, DoubleKeySize};
 
 use core::marker::PhantomData;
 
-use cipher::{BlockSizeUser, Key, KeySizeUser};
+use cipher::{typenum::Unsigned, BlockSizeUser, Key, KeySizeUser};
 use digest::{
     generic_array::{arr::AddLength, ArrayLength, GenericArray},
     Digest, KeyInit, OutputSizeUser,
 };
 use hkdf::SimpleHkdf;
+use zeroize::Zeroize;
 
 // Here's the current definition:
 //
@@ -18,10 +19,25 @@ use hkdf::SimpleHkdf;
 //     com ← HKDF[H].Expand(prk, info="P" || N, len=2*|K|)
 //     mask ← HKDF[H].Expand(prk, info="L" || N, len=|K|)
 //     return (com, mask)
+//
+// And the single-expand version:
+//
+// HkdfComPrfV2[H].Prf(K, N):
+//     prk ← HKDF[H].Extract(salt="HkdfComPrfV2", ikm=K)
+//     com || mask ← HKDF[H].Expand(prk, info="PL" || N, len=3*|K|)
+//     return (com, mask)
+//
+// These produce different outputs, so switching between them breaks existing ciphertexts.
 
 const EXTRACT_DOMAIN_SEP: &[u8] = b"HkdfComPrf";
+const EXTRACT_DOMAIN_SEP_V2: &[u8] = b"HkdfComPrfV2";
+
+// The output of HKDF-Expand in HkdfComPrfV2, i.e., com || mask
+type TripleKeySize<MaskSize> =
+    <<MaskSize as AddLength<u8, MaskSize>>::Output as AddLength<u8, MaskSize>>::Output;
 
 /// A committing PRF derived from HKDF, defined over a hash funtion `H`
+#[derive(Clone)]
 pub struct HkdfComPrf<H, MaskSize, MsgSize>
 where
     H: BlockSizeUser + Clone + Digest + OutputSizeUser,
@@ -95,3 +111,137 @@ where
         (com, mask)
     }
 }
+
+/// A committing PRF derived from HKDF, defined over a hash funtion `H`. Unlike [`HkdfComPrf`],
+/// this derives the commitment and mask with a single HKDF-Expand call and splits the output.
+pub struct HkdfComPrfV2<H, MaskSize, MsgSize>
+where
+    H: BlockSizeUser + Clone + Digest + OutputSizeUser,
+    MaskSize: ArrayLength<u8>,
+    MaskSize: AddLength<u8, MaskSize>,
+    <MaskSize as AddLength<u8, MaskSize>>::Output: AddLength<u8, MaskSize>,
+    MsgSize: ArrayLength<u8>,
+{
+    hkdf: SimpleHkdf<H>,
+    _marker: PhantomData<(MaskSize, MsgSize)>,
+}
+
+impl<H, MaskSize, MsgSize> KeySizeUser for HkdfComPrfV2<H, MaskSize, MsgSize>
+where
+    H: BlockSizeUser + Clone + Digest + OutputSizeUser,
+    MaskSize: ArrayLength<u8>,
+    MaskSize: AddLength<u8, MaskSize>,
+    <MaskSize as AddLength<u8, MaskSize>>::Output: AddLength<u8, MaskSize>,
+    MsgSize: ArrayLength<u8>,
+{
+    // Same as in HkdfComPrf
+    type KeySize = MaskSize;
+}
+
+impl<H, MaskSize, MsgSize> KeyInit for HkdfComPrfV2<H, MaskSize, MsgSize>
+where
+    H: BlockSizeUser + Clone + Digest + OutputSizeUser,
+    MaskSize: ArrayLength<u8>,
+    MaskSize: AddLength<u8, MaskSize>,
+    <MaskSize as AddLength<u8, MaskSize>>::Output: AddLength<u8, MaskSize>,
+    MsgSize: ArrayLength<u8>,
+{
+    fn new(key: &Key<Self>) -> Self {
+        HkdfComPrfV2 {
+            hkdf: SimpleHkdf::extract(Some(EXTRACT_DOMAIN_SEP_V2), key).1,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<H, MaskSize, MsgSize> CommittingPrf for HkdfComPrfV2<H, MaskSize, MsgSize>
+where
+    H: BlockSizeUser + Clone + Digest + OutputSizeUser,
+    MaskSize: ArrayLength<u8>,
+    MaskSize: AddLength<u8, MaskSize>,
+    <MaskSize as AddLength<u8, MaskSize>>::Output: AddLength<u8, MaskSize>,
+    MsgSize: ArrayLength<u8>,
+{
+    type ComSize = DoubleKeySize<Self>;
+    type MaskSize = MaskSize;
+    type MsgSize = MsgSize;
+
+    fn prf(
+        &self,
+        msg: &GenericArray<u8, MsgSize>,
+    ) -> (
+        GenericArray<u8, Self::ComSize>,
+        GenericArray<u8, Self::MaskSize>,
+    ) {
+        // Use one HKDF-Expand to calculate com || mask. This only fails if the output is greater
+        // than 255*HashLen, which is way too big.
+        let mut okm = GenericArray::<u8, TripleKeySize<MaskSize>>::default();
+        self.hkdf
+            .expand_multi_info(&[b"PL", msg], &mut okm)
+            .expect("PRF output size is far too large");
+
+        // Split the output. The mask is used as a key, so clear it from the buffer.
+        let com_size = Self::ComSize::USIZE;
+        let com = GenericArray::clone_from_slice(&okm[..com_size]);
+        let mask = GenericArray::clone_from_slice(&okm[com_size..]);
+        okm.zeroize();
+
+        (com, mask)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::hex;
+
+    use digest::typenum::{U12, U16, U32};
+    use sha2::{Sha256, Sha512};
+
+    // Runs the given PRF on the key 0x00 0x01 ... and nonce 0xa0 0xa1 ... 0xab, and checks the
+    // output against the given commitment and mask
+    fn check_kat<F: CommittingPrf>(expected_com: &str, expected_mask: &str) {
+        let key = Key::<F>::from_exact_iter(0..F::KeySize::U8).unwrap();
+        let nonce = GenericArray::<u8, F::MsgSize>::from_exact_iter(0xa0..0xac).unwrap();
+
+        let (com, mask) = F::new(&key).prf(&nonce);
+        assert_eq!(com.as_slice(), hex(expected_com));
+        assert_eq!(mask.as_slice(), hex(expected_mask));
+    }
+
+    // These KATs pin the encodings. If they change, existing ciphertexts will no longer decrypt.
+
+    #[test]
+    fn hkdf_com_prf_sha256_kat() {
+        check_kat::<HkdfComPrf<Sha256, U16, U12>>(
+            "b90c72bb80c0fd79077d670c705c9b3d19bb9ca36a24513cc4d7be46b03227e0",
+            "15bd49f8dbd4a6a64e52bf1e751c19c0",
+        );
+    }
+
+    #[test]
+    fn hkdf_com_prf_sha512_kat() {
+        check_kat::<HkdfComPrf<Sha512, U32, U12>>(
+            "8a21c050b401696c68565ac9baa860440bccd330fbf69e7f32aee387da4e665c\
+             e4484157986485f36e62f258d48ebb7327fa627c15b297948a482142862610fc",
+            "c6f9d11f3f53960da93242ffa621679118a7fdce060af715fa6b2b5e21842abf",
+        );
+    }
+
+    #[test]
+    fn hkdf_com_prf_v2_sha256_kat() {
+        check_kat::<HkdfComPrfV2<Sha256, U16, U12>>(
+            "ce9b16242ddc77c227b66cfb41155a21c6c9ca3183c02cfc22190c028c110d03",
+            "e70b7b0a29741c932f9e9c4d63a781c9",
+        );
+    }
+
+    #[test]
+    fn hkdf_com_prf_v2_sha512_kat() {
+        check_kat::<HkdfComPrfV2<Sha512, U32, U12>>(
+            "7417124857a1307d6bcb49fd2a685cb3cd1e4825bc3141d77e600d756e6ef1db\
+             32ac1f87f00640c3a71a626fdc3e510e9963a7f8f7649a82679978187dcdf9f9",
+            "85da85417bfff8a841243c70c488d223cac805a5248b34b8fe15eeb08bebad10",
+        );
+    }
+}
@@ -2,7 +2,7 @@
 //! described in <https://eprint.iacr.org/2022/268> §3. This version of `HtE` takes a hash function
 //! and builds a MAC from the HKDF of that hash.
 
-use crate::utc_transform::{UtcAes128Gcm, UtcAes256Gcm};
+use crate::utc_transform::{Commitment, DetachedCommitment, InnerTag, UtcAes128Gcm, UtcAes256Gcm};
 
 use core::marker::PhantomData;
 
@@ -38,6 +38,7 @@ const EXTRACT_DOMAIN_SEP: &[u8] = b"HkdfHte";
 /// key-committing AEAD to a context-committing AEAD (i.e., CMTD-1 → CMTD-4), using the HKDF of the
 /// given hash. Its construction is described in Figure 6 of [Bellare and
 /// Hoang](https://eprint.iacr.org/2022/268).
+#[derive(Clone)]
 pub struct HkdfHte<A, H>
 where
     A: AeadInPlace + NewAead,
@@ -72,6 +73,23 @@ where
     }
 }
 
+impl<A, H> HkdfHte<A, H>
+where
+    A: AeadInPlace + NewAead,
+    H: BlockSizeUser + Clone + Digest + OutputSizeUser,
+{
+    /// Derives the encryption key `L ← H(K, (N, A))`
+    fn derive_key(&self, nonce: &Nonce<Self>, associated_data: &[u8]) -> Key<A> {
+        // This only fails if A::KeySize is greater than 255*HashLen, which is way too big.
+        let mut enc_key = Key::<A>::default();
+        self.mac
+            .expand_multi_info(&[nonce, associated_data], &mut enc_key)
+            .expect("key size is far too large");
+
+        enc_key
+    }
+}
+
 impl<A, H> AeadInPlace for HkdfHte<A, H>
 where
     A: AeadInPlace + NewAead,
@@ -88,12 +106,8 @@ where
         associated_data: &[u8],
         buffer: &mut [u8],
     ) -> Result<Tag<Self>, Error> {
-        // Derive the encryption key L. This only fails if Self::ComSize is greater than
-        // 255*HashLen, which is way too big.
-        let mut enc_key = Key::<A>::default();
-        self.mac
-            .expand_multi_info(&[nonce, associated_data], &mut enc_key)
-            .expect("key size is far too large");
+        // Derive the encryption key L
+        let enc_key = self.derive_key(nonce, associated_data);
 
         // Now use L to encrypt the message. The associated data is excluded
         let ciph = A::new(&enc_key);
@@ -112,12 +126,8 @@ where
         buffer: &mut [u8],
         tag: &Tag<Self>,
     ) -> Result<(), Error> {
-        // Derive the encryption key L. This only fails if Self::ComSize is greater than
-        // 255*HashLen, which is way too big.
-        let mut enc_key = Key::<A>::default();
-        self.mac
-            .expand_multi_info(&[nonce, associated_data], &mut enc_key)
-            .expect("key size is far too large");
+        // Derive the encryption key L
+        let enc_key = self.derive_key(nonce, associated_data);
 
         // Now use L to decrypt the message. The associated data is excluded
         let ciph = A::new(&enc_key);
@@ -125,11 +135,55 @@ where
     }
 }
 
+// If the underlying AEAD has a detachable commitment, then so does HkdfHte. The commitment is
+// computed by the underlying AEAD under the derived key L.
+impl<A, H> DetachedCommitment for HkdfHte<A, H>
+where
+    A: AeadInPlace + DetachedCommitment + NewAead,
+    H: BlockSizeUser + Clone + Digest + OutputSizeUser,
+{
+    type InnerTagSize = A::InnerTagSize;
+    type ComSize = A::ComSize;
+
+    fn encrypt_in_place_detached_with_commitment(
+        &self,
+        nonce: &Nonce<Self>,
+        associated_data: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<(InnerTag<Self>, Commitment<Self>), Error> {
+        let enc_key = self.derive_key(nonce, associated_data);
+        let ciph = A::new(&enc_key);
+        ciph.encrypt_in_place_detached_with_commitment(nonce, &[], buffer)
+    }
+
+    fn decrypt_in_place_detached_with_commitment(
+        &self,
+        nonce: &Nonce<Self>,
+        associated_data: &[u8],
+        buffer: &mut [u8],
+        tag: &InnerTag<Self>,
+        com: &Commitment<Self>,
+    ) -> Result<(), Error> {
+        let enc_key = self.derive_key(nonce, associated_data);
+        let ciph = A::new(&enc_key);
+        ciph.decrypt_in_place_detached_with_commitment(nonce, &[], buffer, tag, com)
+    }
+
+    fn commitment(&self, nonce: &Nonce<Self>, associated_data: &[u8]) -> Commitment<Self> {
+        let enc_key = self.derive_key(nonce, associated_data);
+        let ciph = A::new(&enc_key);
+        ciph.commitment(nonce, &[])
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::util::test_aead_correctness;
+    use crate::util::{test_aead_correctness, test_detached_commitment};
 
     test_aead_correctness!(HkdfHteUtcAes128Gcm, hkdfhte_utc_aes128_correctness);
     test_aead_correctness!(HkdfHteUtcAes256Gcm, hkdfhte_utc_aes256_correctness);
+
+    test_detached_commitment!(HkdfHteUtcAes128Gcm, hkdfhte_utc_aes128_detached_commitment);
+    test_detached_commitment!(HkdfHteUtcAes256Gcm, hkdfhte_utc_aes256_detached_commitment);
 }
@@ -0,0 +1,265 @@
+//! Implements the [`hpke`] crate's `Aead` trait for the UtC and HtE aliases, so they can be used
+//! as the AEAD of an HPKE context. Since the payload AEAD is committing, an HPKE ciphertext then
+//! decrypts under at most one key schedule, i.e., one choice of shared secret, PSK, and info.
+//!
+//! RFC 9180 doesn't assign identifiers to these AEADs, so we use identifiers in `0xFF01..=0xFF06`,
+//! which is `0xFF00` plus the crate's [`AlgorithmId`]. These are unassigned and chosen by this
+//! crate, so both parties must agree on them out of band.
+//!
+//! The AEADs work with any KEM, but this crate can't enable hpke 0.8's X25519 KEM. Its `x25519`
+//! feature depends on x25519-dalek 1.2, which pins `zeroize = "=1.3"`, while this crate needs a
+//! newer zeroize and uses x25519-dalek 2 for sealed boxes. So the tests use the P-256 KEM instead.
+
+use crate::{
+    AlgorithmId, HkdfHteUtcAes128Gcm, HkdfHteUtcAes256Gcm, MacHteUtcAes128Gcm, MacHteUtcAes256Gcm,
+    UtcAes128Gcm, UtcAes256Gcm,
+};
+
+/// The base of the HPKE AEAD identifiers this crate chooses
+const AEAD_ID_BASE: u16 = 0xFF00;
+
+impl hpke::aead::Aead for UtcAes128Gcm {
+    type AeadImpl = Self;
+    const AEAD_ID: u16 = AEAD_ID_BASE | AlgorithmId::UtcAes128Gcm as u16;
+}
+
+impl hpke::aead::Aead for UtcAes256Gcm {
+    type AeadImpl = Self;
+    const AEAD_ID: u16 = AEAD_ID_BASE | AlgorithmId::UtcAes256Gcm as u16;
+}
+
+impl hpke::aead::Aead for MacHteUtcAes128Gcm {
+    type AeadImpl = Self;
+    const AEAD_ID: u16 = AEAD_ID_BASE | AlgorithmId::MacHteUtcAes128Gcm as u16;
+}
+
+impl hpke::aead::Aead for MacHteUtcAes256Gcm {
+    type AeadImpl = Self;
+    const AEAD_ID: u16 = AEAD_ID_BASE | AlgorithmId::MacHteUtcAes256Gcm as u16;
+}
+
+impl hpke::aead::Aead for HkdfHteUtcAes128Gcm {
+    type AeadImpl = Self;
+    const AEAD_ID: u16 = AEAD_ID_BASE | AlgorithmId::HkdfHteUtcAes128Gcm as u16;
+}
+
+impl hpke::aead::Aead for HkdfHteUtcAes256Gcm {
+    type AeadImpl = Self;
+    const AEAD_ID: u16 = AEAD_ID_BASE | AlgorithmId::HkdfHteUtcAes256Gcm as u16;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use core::{cell::RefCell, marker::PhantomData};
+
+    use aead::{AeadCore, AeadInPlace, Error, Key, NewAead, Nonce, Tag};
+    use cipher::typenum::Unsigned;
+    use hpke::{
+        aead::Aead as HpkeAead, kdf::HkdfSha256, kem::DhP256HkdfSha256, Kem, OpModeR, OpModeS,
+        PskBundle,
+    };
+
+    // Not X25519HkdfSha256, since hpke's x25519 feature can't be enabled. See the module docs.
+    type TestKem = DhP256HkdfSha256;
+
+    std::thread_local! {
+        // The nonces that NonceSpy has been asked to encrypt under, in order
+        static SEEN_NONCES: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+    }
+
+    // Wraps the payload AEAD of an HPKE context, and records every nonce the context encrypts
+    // under. The key schedule's base nonce isn't public, but it's the nonce used at sequence
+    // number 0.
+    #[derive(Clone)]
+    struct NonceSpy<A>(A);
+
+    impl<A: NewAead> NewAead for NonceSpy<A> {
+        type KeySize = A::KeySize;
+
+        fn new(key: &Key<Self>) -> Self {
+            NonceSpy(A::new(key))
+        }
+    }
+
+    impl<A: AeadCore> AeadCore for NonceSpy<A> {
+        type NonceSize = A::NonceSize;
+        type TagSize = A::TagSize;
+        type CiphertextOverhead = A::CiphertextOverhead;
+    }
+
+    impl<A: AeadInPlace> AeadInPlace for NonceSpy<A> {
+        fn encrypt_in_place_detached(
+            &self,
+            nonce: &Nonce<Self>,
+            associated_data: &[u8],
+            buffer: &mut [u8],
+        ) -> Result<Tag<Self>, Error> {
+            SEEN_NONCES.with(|seen| seen.borrow_mut().push(nonce.to_vec()));
+            self.0
+                .encrypt_in_place_detached(nonce, associated_data, buffer)
+        }
+
+        fn decrypt_in_place_detached(
+            &self,
+            nonce: &Nonce<Self>,
+            associated_data: &[u8],
+            buffer: &mut [u8],
+            tag: &Tag<Self>,
+        ) -> Result<(), Error> {
+            self.0
+                .decrypt_in_place_detached(nonce, associated_data, buffer, tag)
+        }
+    }
+
+    // The HPKE AEAD A, with its payload AEAD wrapped in a NonceSpy. It has the same AEAD_ID, so
+    // the key schedule derives the same key and base nonce as it does for A.
+    struct Spied<A>(PhantomData<A>);
+
+    impl<A: HpkeAead> HpkeAead for Spied<A> {
+        type AeadImpl = NonceSpy<A::AeadImpl>;
+        const AEAD_ID: u16 = A::AEAD_ID;
+    }
+
+    // Checks that the base nonce an HPKE context derives is Nn bytes, where Nn is A's nonce size,
+    // and that the per-message nonces are the base nonce XORed with the sequence number
+    fn check_base_nonce<A: HpkeAead>() {
+        let mut rng = rand::thread_rng();
+        let (sk, pk) = TestKem::gen_keypair(&mut rng);
+
+        SEEN_NONCES.with(|seen| seen.borrow_mut().clear());
+        let (encapped_key, mut sender) = hpke::setup_sender::<Spied<A>, HkdfSha256, TestKem, _>(
+            &OpModeS::Base,
+            &pk,
+            b"",
+            &mut rng,
+        )
+        .unwrap();
+        let cts: Vec<_> = (0..3)
+            .map(|_| sender.seal(b"hello world", b"").unwrap())
+            .collect();
+        let nonces = SEEN_NONCES.with(|seen| seen.take());
+
+        let nonce_len = <A::AeadImpl as AeadCore>::NonceSize::USIZE;
+        assert_eq!(nonces.len(), 3);
+        let base_nonce = &nonces[0];
+        assert_eq!(base_nonce.len(), nonce_len);
+        for (seq, nonce) in nonces.iter().enumerate() {
+            let mut expected = base_nonce.clone();
+            expected[nonce_len - 1] ^= seq as u8;
+            assert_eq!(nonce, &expected);
+        }
+
+        // A receiver for the unwrapped AEAD derives the same base nonce, so it opens everything
+        let mut receiver =
+            hpke::setup_receiver::<A, HkdfSha256, TestKem>(&OpModeR::Base, &sk, &encapped_key, b"")
+                .unwrap();
+        for ct in &cts {
+            assert_eq!(receiver.open(ct, b"").unwrap(), b"hello world");
+        }
+    }
+
+    // Runs an HPKE exchange in the given modes, and checks that several messages round-trip in
+    // order, and that the contexts' nonces stay in sync
+    fn roundtrip<A: HpkeAead>(mode_s: &OpModeS<TestKem>, mode_r: &OpModeR<TestKem>) {
+        let mut rng = rand::thread_rng();
+        let (sk, pk) = TestKem::gen_keypair(&mut rng);
+        let info = b"kc-aeads hpke test";
+
+        let (encapped_key, mut sender) =
+            hpke::setup_sender::<A, HkdfSha256, TestKem, _>(mode_s, &pk, info, &mut rng).unwrap();
+        let mut receiver =
+            hpke::setup_receiver::<A, HkdfSha256, TestKem>(mode_r, &sk, &encapped_key, info)
+                .unwrap();
+
+        let cts: Vec<_> = (0..3)
+            .map(|_| sender.seal(b"hello world", b"aad").unwrap())
+            .collect();
+
+        // Every sequence number uses a different nonce, so the same plaintext encrypts differently
+        assert_ne!(cts[0], cts[1]);
+        assert_ne!(cts[1], cts[2]);
+
+        // Opening out of order fails, since the receiver derives the nonce from its own counter.
+        // A failed open doesn't advance the counter, so the in-order opens below still work.
+        assert!(receiver.open(&cts[1], b"aad").is_err());
+        for ct in &cts {
+            assert_eq!(receiver.open(ct, b"aad").unwrap(), b"hello world");
+        }
+    }
+
+    macro_rules! test_hpke {
+        ($aead:ty, $alg:expr, $test_name:ident) => {
+            #[test]
+            fn $test_name() {
+                let nonce_size = <<$aead as HpkeAead>::AeadImpl as AeadCore>::NonceSize::USIZE;
+                assert_eq!(nonce_size, $alg.nonce_len());
+                assert_eq!(<$aead as HpkeAead>::AEAD_ID, 0xFF00 | $alg.to_u16());
+
+                check_base_nonce::<$aead>();
+                roundtrip::<$aead>(&OpModeS::Base, &OpModeR::Base);
+
+                let psk_bundle = || PskBundle {
+                    psk: &[7u8; 32],
+                    psk_id: b"psk id",
+                };
+                roundtrip::<$aead>(&OpModeS::Psk(psk_bundle()), &OpModeR::Psk(psk_bundle()));
+            }
+        };
+    }
+
+    test_hpke!(UtcAes128Gcm, AlgorithmId::UtcAes128Gcm, hpke_utc_aes128);
+    test_hpke!(UtcAes256Gcm, AlgorithmId::UtcAes256Gcm, hpke_utc_aes256);
+    test_hpke!(
+        MacHteUtcAes128Gcm,
+        AlgorithmId::MacHteUtcAes128Gcm,
+        hpke_machte_utc_aes128
+    );
+    test_hpke!(
+        MacHteUtcAes256Gcm,
+        AlgorithmId::MacHteUtcAes256Gcm,
+        hpke_machte_utc_aes256
+    );
+    test_hpke!(
+        HkdfHteUtcAes128Gcm,
+        AlgorithmId::HkdfHteUtcAes128Gcm,
+        hpke_hkdfhte_utc_aes128
+    );
+    test_hpke!(
+        HkdfHteUtcAes256Gcm,
+        AlgorithmId::HkdfHteUtcAes256Gcm,
+        hpke_hkdfhte_utc_aes256
+    );
+
+    // A ciphertext from one PSK doesn't open under another, since the payload AEAD commits to the
+    // key the schedule derives
+    #[test]
+    fn hpke_wrong_psk() {
+        let mut rng = rand::thread_rng();
+        let (sk, pk) = TestKem::gen_keypair(&mut rng);
+        let bundle = |psk| PskBundle {
+            psk,
+            psk_id: b"psk id",
+        };
+
+        let (encapped_key, mut sender) =
+            hpke::setup_sender::<HkdfHteUtcAes256Gcm, HkdfSha256, TestKem, _>(
+                &OpModeS::Psk(bundle(&[1u8; 32])),
+                &pk,
+                b"",
+                &mut rng,
+            )
+            .unwrap();
+        let ct = sender.seal(b"hello world", b"").unwrap();
+
+        let mut receiver = hpke::setup_receiver::<HkdfHteUtcAes256Gcm, HkdfSha256, TestKem>(
+            &OpModeR::Psk(bundle(&[2u8; 32])),
+            &sk,
+            &encapped_key,
+            b"",
+        )
+        .unwrap();
+        assert!(receiver.open(&ct, b"").is_err());
+    }
+}
@@ -0,0 +1,437 @@
+//! Defines [`std::io`] adapters which encrypt and decrypt a byte stream with STREAM over one of
+//! the committing AEADs in this crate. See the [`stream`](crate::stream) module for details on
+//! STREAM.
+//!
+//! The ciphertext format is a sequence of segments with no header. Every segment but the last
+//! encrypts exactly `chunk_size` bytes of plaintext, and so is `chunk_size + A::TagSize` bytes
+//! long. The last segment encrypts the remaining `0..=chunk_size` bytes and is flagged as the last
+//! one, so truncating the ciphertext at any point causes decryption to fail. The caller is
+//! responsible for storing the STREAM nonce, e.g., by writing it before the ciphertext.
+
+use crate::stream::{DecryptorBE32, EncryptorBE32, Nonce, StreamBE32};
+
+use core::ops::Sub;
+use std::io::{self, Read, Write};
+
+use aead::{AeadInPlace, Key, NewAead};
+use cipher::{
+    generic_array::ArrayLength,
+    typenum::{Unsigned, U5},
+};
+
+/// The default number of plaintext bytes per segment
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+fn aead_error() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "committing AEAD stream error")
+}
+
+fn poisoned_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::BrokenPipe,
+        "committing AEAD stream writer failed on an earlier write",
+    )
+}
+
+/// A writer which encrypts everything written to it and writes the ciphertext to an inner writer.
+/// The caller MUST call [`EncryptingWriter::finish`] when done, otherwise the ciphertext is
+/// truncated and will fail to decrypt. If writing to the inner writer fails, the ciphertext
+/// written so far can't be completed, so every later call returns an error.
+pub struct EncryptingWriter<W, A>
+where
+    W: Write,
+    A: AeadInPlace + NewAead,
+    A::NonceSize: Sub<U5>,
+    <A::NonceSize as Sub<U5>>::Output: ArrayLength<u8>,
+{
+    inner: W,
+    // This is None once the last segment has been written
+    encryptor: Option<EncryptorBE32<A>>,
+    // Set when encrypting or writing a segment fails. The encryptor has moved past that segment,
+    // so nothing more can be written.
+    failed: bool,
+    // The plaintext of the current segment. It has capacity chunk_size + tag size, so the tag can
+    // be appended without reallocating.
+    buf: Vec<u8>,
+    chunk_size: usize,
+}
+
+impl<W, A> EncryptingWriter<W, A>
+where
+    W: Write,
+    A: AeadInPlace + NewAead,
+    A::NonceSize: Sub<U5>,
+    <A::NonceSize as Sub<U5>>::Output: ArrayLength<u8>,
+{
+    /// Makes a new encrypting writer with [`DEFAULT_CHUNK_SIZE`]-byte segments. The nonce MUST
+    /// NOT be reused under the same key.
+    pub fn new(inner: W, key: &Key<A>, nonce: &Nonce<A, StreamBE32<A>>) -> Self {
+        Self::with_chunk_size(inner, key, nonce, DEFAULT_CHUNK_SIZE)
+    }
+
+    /// Makes a new encrypting writer with `chunk_size`-byte segments. The reader must use the same
+    /// chunk size. The nonce MUST NOT be reused under the same key.
+    ///
+    /// Panics if `chunk_size` is 0.
+    pub fn with_chunk_size(
+        inner: W,
+        key: &Key<A>,
+        nonce: &Nonce<A, StreamBE32<A>>,
+        chunk_size: usize,
+    ) -> Self {
+        assert!(chunk_size > 0, "chunk size must be nonzero");
+
+        EncryptingWriter {
+            inner,
+            encryptor: Some(EncryptorBE32::new(key, nonce)),
+            failed: false,
+            buf: Vec::with_capacity(chunk_size + A::TagSize::USIZE),
+            chunk_size,
+        }
+    }
+
+    /// Encrypts and writes the last segment, flushes the inner writer, and returns it
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.failed {
+            return Err(poisoned_error());
+        }
+        let encryptor = self.encryptor.take().ok_or_else(aead_error)?;
+        encryptor
+            .encrypt_last_in_place(&[], &mut self.buf)
+            .map_err(|_| aead_error())?;
+        self.inner.write_all(&self.buf)?;
+        self.inner.flush()?;
+
+        Ok(self.inner)
+    }
+
+    /// Encrypts the full segment in `buf` and writes it to the inner writer
+    fn write_segment(&mut self) -> io::Result<()> {
+        let encryptor = self.encryptor.as_mut().ok_or_else(aead_error)?;
+        encryptor
+            .encrypt_next_in_place(&[], &mut self.buf)
+            .map_err(|_| aead_error())?;
+        self.inner.write_all(&self.buf)?;
+        self.buf.clear();
+        Ok(())
+    }
+}
+
+impl<W, A> Write for EncryptingWriter<W, A>
+where
+    W: Write,
+    A: AeadInPlace + NewAead,
+    A::NonceSize: Sub<U5>,
+    <A::NonceSize as Sub<U5>>::Output: ArrayLength<u8>,
+{
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.failed {
+            return Err(poisoned_error());
+        }
+        if data.is_empty() {
+            return Ok(0);
+        }
+
+        // A full segment is only written once we know more data follows it. Otherwise it might
+        // have to be the last segment.
+        if self.buf.len() == self.chunk_size {
+            if let Err(e) = self.write_segment() {
+                // buf holds a segment that the encryptor is already past, and may have been
+                // partially written, so we can't continue
+                self.buf.clear();
+                self.failed = true;
+                return Err(e);
+            }
+        }
+
+        let n = core::cmp::min(data.len(), self.chunk_size - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+        Ok(n)
+    }
+
+    // This only flushes the inner writer. The current segment can't be written until it's full or
+    // until finish() is called.
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A reader which reads ciphertext from an inner reader and returns the decrypted plaintext. A
+/// read returns an error of kind [`io::ErrorKind::InvalidData`] if the ciphertext was modified or
+/// truncated. Plaintext is only returned once its segment has been authenticated.
+pub struct DecryptingReader<R, A>
+where
+    R: Read,
+    A: AeadInPlace + NewAead,
+    A::NonceSize: Sub<U5>,
+    <A::NonceSize as Sub<U5>>::Output: ArrayLength<u8>,
+{
+    inner: R,
+    // This is None once the last segment has been decrypted, or decryption has failed
+    decryptor: Option<DecryptorBE32<A>>,
+    failed: bool,
+    // Ciphertext read from the inner reader. We read one byte past the end of a segment to find
+    // out whether it is the last segment.
+    ct_buf: Vec<u8>,
+    // The plaintext of the current segment, and how much of it has been returned
+    pt_buf: Vec<u8>,
+    pt_pos: usize,
+    chunk_size: usize,
+}
+
+impl<R, A> DecryptingReader<R, A>
+where
+    R: Read,
+    A: AeadInPlace + NewAead,
+    A::NonceSize: Sub<U5>,
+    <A::NonceSize as Sub<U5>>::Output: ArrayLength<u8>,
+{
+    /// Makes a new decrypting reader with [`DEFAULT_CHUNK_SIZE`]-byte segments
+    pub fn new(inner: R, key: &Key<A>, nonce: &Nonce<A, StreamBE32<A>>) -> Self {
+        Self::with_chunk_size(inner, key, nonce, DEFAULT_CHUNK_SIZE)
+    }
+
+    /// Makes a new decrypting reader with `chunk_size`-byte segments. This must match the chunk
+    /// size used by the writer.
+    ///
+    /// Panics if `chunk_size` is 0.
+    pub fn with_chunk_size(
+        inner: R,
+        key: &Key<A>,
+        nonce: &Nonce<A, StreamBE32<A>>,
+        chunk_size: usize,
+    ) -> Self {
+        assert!(chunk_size > 0, "chunk size must be nonzero");
+
+        let segment_size = chunk_size + A::TagSize::USIZE;
+        DecryptingReader {
+            inner,
+            decryptor: Some(DecryptorBE32::new(key, nonce)),
+            failed: false,
+            ct_buf: Vec::with_capacity(segment_size + 1),
+            pt_buf: Vec::with_capacity(segment_size + 1),
+            pt_pos: 0,
+            chunk_size,
+        }
+    }
+
+    /// Returns the inner reader
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Reads and decrypts the next segment into `pt_buf`
+    fn next_segment(&mut self) -> io::Result<()> {
+        let segment_size = self.chunk_size + A::TagSize::USIZE;
+
+        // Read until we have one byte more than a full segment, or until EOF
+        while self.ct_buf.len() < segment_size + 1 {
+            let old_len = self.ct_buf.len();
+            self.ct_buf.resize(segment_size + 1, 0);
+            match self.inner.read(&mut self.ct_buf[old_len..]) {
+                Ok(0) => {
+                    self.ct_buf.truncate(old_len);
+                    break;
+                }
+                Ok(n) => self.ct_buf.truncate(old_len + n),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => self.ct_buf.truncate(old_len),
+                Err(e) => {
+                    self.ct_buf.truncate(old_len);
+                    return Err(e);
+                }
+            }
+        }
+
+        // Swap the buffers, so that the segment is decrypted in place in pt_buf and the old
+        // plaintext buffer is reused for the next ciphertext
+        core::mem::swap(&mut self.ct_buf, &mut self.pt_buf);
+        self.ct_buf.clear();
+        self.pt_pos = 0;
+
+        if self.pt_buf.len() > segment_size {
+            // There's more data, so this isn't the last segment. Move the extra byte over.
+            let extra = self.pt_buf.pop().unwrap();
+            self.ct_buf.push(extra);
+
+            let decryptor = self.decryptor.as_mut().ok_or_else(aead_error)?;
+            decryptor
+                .decrypt_next_in_place(&[], &mut self.pt_buf)
+                .map_err(|_| aead_error())
+        } else {
+            // We hit EOF, so this is the last segment
+            let decryptor = self.decryptor.take().ok_or_else(aead_error)?;
+            decryptor
+                .decrypt_last_in_place(&[], &mut self.pt_buf)
+                .map_err(|_| aead_error())
+        }
+    }
+}
+
+impl<R, A> Read for DecryptingReader<R, A>
+where
+    R: Read,
+    A: AeadInPlace + NewAead,
+    A::NonceSize: Sub<U5>,
+    <A::NonceSize as Sub<U5>>::Output: ArrayLength<u8>,
+{
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        // Get a new segment if we've returned all of the current one. Segments can be empty, so
+        // loop until we get some plaintext or finish the stream.
+        while self.pt_pos == self.pt_buf.len() {
+            if self.failed {
+                return Err(aead_error());
+            }
+            if self.decryptor.is_none() {
+                return Ok(0);
+            }
+            if let Err(e) = self.next_segment() {
+                // Don't leave unauthenticated plaintext around, and don't decrypt anything else
+                self.pt_buf.clear();
+                self.pt_pos = 0;
+                self.decryptor = None;
+                self.failed = true;
+                return Err(e);
+            }
+        }
+
+        let n = core::cmp::min(out.len(), self.pt_buf.len() - self.pt_pos);
+        out[..n].copy_from_slice(&self.pt_buf[self.pt_pos..self.pt_pos + n]);
+        self.pt_pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{MacHteUtcAes256Gcm, UtcAes256Gcm};
+
+    use rand::RngCore;
+
+    const CHUNK_SIZE: usize = 16;
+
+    fn encrypt<A>(key: &Key<A>, nonce: &Nonce<A, StreamBE32<A>>, msg: &[u8]) -> Vec<u8>
+    where
+        A: AeadInPlace + NewAead,
+        A::NonceSize: Sub<U5>,
+        <A::NonceSize as Sub<U5>>::Output: ArrayLength<u8>,
+    {
+        let mut writer =
+            EncryptingWriter::<_, A>::with_chunk_size(Vec::new(), key, nonce, CHUNK_SIZE);
+        // Write in odd-sized pieces to exercise the buffering
+        for piece in msg.chunks(7) {
+            writer.write_all(piece).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    fn decrypt<A>(key: &Key<A>, nonce: &Nonce<A, StreamBE32<A>>, ct: &[u8]) -> io::Result<Vec<u8>>
+    where
+        A: AeadInPlace + NewAead,
+        A::NonceSize: Sub<U5>,
+        <A::NonceSize as Sub<U5>>::Output: ArrayLength<u8>,
+    {
+        let mut reader = DecryptingReader::<_, A>::with_chunk_size(ct, key, nonce, CHUNK_SIZE);
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    macro_rules! test_io {
+        ($aead:ty, $test_name:ident) => {
+            #[test]
+            fn $test_name() {
+                let mut rng = rand::thread_rng();
+                let key = <$aead>::generate_key(&mut rng);
+                let nonce = Nonce::<$aead, StreamBE32<$aead>>::default();
+                let tag_size = <$aead as aead::AeadCore>::TagSize::USIZE;
+
+                for msg_len in [
+                    0,
+                    1,
+                    CHUNK_SIZE - 1,
+                    CHUNK_SIZE,
+                    CHUNK_SIZE + 1,
+                    3 * CHUNK_SIZE,
+                ] {
+                    let mut msg = vec![0u8; msg_len];
+                    rng.fill_bytes(&mut msg);
+
+                    // Round trip
+                    let ct = encrypt::<$aead>(&key, &nonce, &msg);
+                    let num_segments = core::cmp::max(1, msg_len.div_ceil(CHUNK_SIZE));
+                    assert_eq!(ct.len(), msg_len + num_segments * tag_size);
+                    assert_eq!(decrypt::<$aead>(&key, &nonce, &ct).unwrap(), msg);
+
+                    // Truncating at any segment boundary fails
+                    for i in 1..num_segments {
+                        let trunc = &ct[..i * (CHUNK_SIZE + tag_size)];
+                        assert!(decrypt::<$aead>(&key, &nonce, trunc).is_err());
+                    }
+
+                    // Modifying any byte fails
+                    let mut bad_ct = ct.clone();
+                    bad_ct[ct.len() / 2] ^= 1;
+                    assert!(decrypt::<$aead>(&key, &nonce, &bad_ct).is_err());
+
+                    // Reading again after an error fails too, rather than looking like EOF
+                    let mut reader = DecryptingReader::<_, $aead>::with_chunk_size(
+                        &bad_ct[..],
+                        &key,
+                        &nonce,
+                        CHUNK_SIZE,
+                    );
+                    assert!(reader.read_to_end(&mut Vec::new()).is_err());
+                    for _ in 0..2 {
+                        let err = reader.read(&mut [0u8; 1]).unwrap_err();
+                        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+                    }
+
+                    // A different key fails
+                    let other_key = <$aead>::generate_key(&mut rng);
+                    assert!(decrypt::<$aead>(&other_key, &nonce, &ct).is_err());
+                }
+            }
+        };
+    }
+
+    test_io!(UtcAes256Gcm, io_utc_aes256);
+    test_io!(MacHteUtcAes256Gcm, io_machte_utc_aes256);
+
+    // A writer whose first write fails, and whose later writes succeed
+    struct FailOnce {
+        failed: bool,
+    }
+
+    impl Write for FailOnce {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            if !self.failed {
+                self.failed = true;
+                return Err(io::ErrorKind::Other.into());
+            }
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Once writing a segment fails, the writer refuses to write anything else, even if the inner
+    // writer recovers
+    #[test]
+    fn io_writer_poisoned() {
+        let key = UtcAes256Gcm::generate_key(&mut rand::thread_rng());
+        let nonce = Nonce::<UtcAes256Gcm, StreamBE32<UtcAes256Gcm>>::default();
+        let inner = FailOnce { failed: false };
+        let mut writer =
+            EncryptingWriter::<_, UtcAes256Gcm>::with_chunk_size(inner, &key, &nonce, CHUNK_SIZE);
+
+        // The first segment is only written once more data arrives
+        writer.write_all(&[0u8; CHUNK_SIZE]).unwrap();
+        assert!(writer.write(b"a").is_err());
+
+        assert!(writer.write(b"a").is_err());
+        assert!(writer.finish().is_err());
+    }
+}
@@ -0,0 +1,230 @@
+//! Defines JWE compact serialization (RFC 7516 §7.1) with committing content encryption.
+//!
+//! JOSE has no registered committing `enc` values, so we use the private values
+//!
+//! * `A256GCM-UtC` for [`UtcAes256Gcm`](crate::UtcAes256Gcm), and
+//! * `A256GCM-HtE-UtC` for [`HkdfHteUtcAes256Gcm`](crate::HkdfHteUtcAes256Gcm).
+//!
+//! Both sides must agree on them out of band. Only direct encryption (`"alg": "dir"`) is
+//! supported, so the content encryption key is the shared key, and the JWE Encrypted Key is empty.
+//! As in RFC 7516 §5.1, the associated data is the ASCII of the encoded protected header.
+//!
+//! Note that these tags are longer than the 16 bytes of `A256GCM`, since they end in a commitment.
+//! A token with a 16-byte tag is rejected.
+
+use crate::{AlgorithmId, AnyKcAead};
+
+use aead::Error;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand_core::{CryptoRng, RngCore};
+use serde_json::{json, Value};
+
+/// A committing JWE content encryption algorithm, i.e., an `enc` value
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContentEncryption {
+    /// `A256GCM-UtC`, using `UtcAes256Gcm`
+    A256GcmUtc,
+    /// `A256GCM-HtE-UtC`, using `HkdfHteUtcAes256Gcm`
+    A256GcmHteUtc,
+}
+
+impl ContentEncryption {
+    /// Returns the `enc` header value
+    pub fn name(self) -> &'static str {
+        match self {
+            ContentEncryption::A256GcmUtc => "A256GCM-UtC",
+            ContentEncryption::A256GcmHteUtc => "A256GCM-HtE-UtC",
+        }
+    }
+
+    /// Returns the AEAD this uses
+    pub fn algorithm(self) -> AlgorithmId {
+        match self {
+            ContentEncryption::A256GcmUtc => AlgorithmId::UtcAes256Gcm,
+            ContentEncryption::A256GcmHteUtc => AlgorithmId::HkdfHteUtcAes256Gcm,
+        }
+    }
+}
+
+/// Encrypts `plaintext` under `key`, and returns the JWE in compact serialization. Returns an error
+/// if `key` is the wrong length for `enc`.
+pub fn encrypt<R>(
+    rng: &mut R,
+    enc: ContentEncryption,
+    key: &[u8],
+    plaintext: &[u8],
+) -> Result<String, Error>
+where
+    R: CryptoRng + RngCore,
+{
+    let ciph = AnyKcAead::new(enc.algorithm(), key)?;
+
+    let header = json!({ "alg": "dir", "enc": enc.name() }).to_string();
+    let encoded_header = URL_SAFE_NO_PAD.encode(header);
+
+    let mut iv = vec![0u8; ciph.nonce_len()];
+    rng.fill_bytes(&mut iv);
+    let mut buf = plaintext.to_vec();
+    let mut tag = vec![0u8; ciph.tag_len()];
+    ciph.encrypt_in_place_detached(&iv, encoded_header.as_bytes(), &mut buf, &mut tag)?;
+
+    // BASE64URL(header) || '.' || BASE64URL(encrypted key) || '.' || BASE64URL(iv) || '.' ||
+    // BASE64URL(ciphertext) || '.' || BASE64URL(tag), where the encrypted key is empty
+    Ok([
+        encoded_header,
+        String::new(),
+        URL_SAFE_NO_PAD.encode(iv),
+        URL_SAFE_NO_PAD.encode(buf),
+        URL_SAFE_NO_PAD.encode(tag),
+    ]
+    .join("."))
+}
+
+/// Decrypts a compact JWE under `key`, and returns the plaintext. The JWE's `enc` must be
+/// `expected_enc`, which stops an attacker from choosing the algorithm. Returns an error if the
+/// JWE is malformed, uses another `alg` or `enc`, has a `crit` or `zip` header, has an IV or tag
+/// of the wrong length, or fails to decrypt.
+pub fn decrypt(expected_enc: ContentEncryption, key: &[u8], jwe: &str) -> Result<Vec<u8>, Error> {
+    let ciph = AnyKcAead::new(expected_enc.algorithm(), key)?;
+
+    let parts: Vec<&str> = jwe.split('.').collect();
+    let [encoded_header, encrypted_key, iv, ciphertext, tag] = parts[..] else {
+        return Err(Error);
+    };
+    let decode = |s: &str| URL_SAFE_NO_PAD.decode(s).map_err(|_| Error);
+
+    // Check the header. We understand no extensions, and don't do compression.
+    let header: Value = serde_json::from_slice(&decode(encoded_header)?).map_err(|_| Error)?;
+    let header = header.as_object().ok_or(Error)?;
+    if header.get("alg") != Some(&json!("dir"))
+        || header.get("enc") != Some(&json!(expected_enc.name()))
+        || header.contains_key("crit")
+        || header.contains_key("zip")
+    {
+        return Err(Error);
+    }
+
+    // Direct encryption has an empty encrypted key
+    if !encrypted_key.is_empty() {
+        return Err(Error);
+    }
+
+    let iv = decode(iv)?;
+    let tag = decode(tag)?;
+    if iv.len() != ciph.nonce_len() || tag.len() != ciph.tag_len() {
+        return Err(Error);
+    }
+
+    let mut buf = decode(ciphertext)?;
+    ciph.decrypt_in_place_detached(&iv, encoded_header.as_bytes(), &mut buf, &tag)?;
+
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ENCS: [ContentEncryption; 2] = [
+        ContentEncryption::A256GcmUtc,
+        ContentEncryption::A256GcmHteUtc,
+    ];
+
+    // Replaces the `i`-th part of a compact JWE
+    fn replace_part(jwe: &str, i: usize, part: &str) -> String {
+        let mut parts: Vec<&str> = jwe.split('.').collect();
+        parts[i] = part;
+        parts.join(".")
+    }
+
+    #[test]
+    fn jwe_correctness() {
+        let mut rng = rand::thread_rng();
+        let key = [0x42; 32];
+
+        for enc in ENCS {
+            let jwe = encrypt(&mut rng, enc, &key, b"hello world").unwrap();
+            assert_eq!(jwe.split('.').count(), 5);
+            assert_eq!(decrypt(enc, &key, &jwe).unwrap(), b"hello world");
+
+            // The key and enc must match
+            assert!(decrypt(enc, &[0x43; 32], &jwe).is_err());
+            let other_enc = ENCS.into_iter().find(|&e| e != enc).unwrap();
+            assert!(decrypt(other_enc, &key, &jwe).is_err());
+
+            // Keys of the wrong length are rejected
+            assert!(encrypt(&mut rng, enc, &[0x42; 16], b"").is_err());
+            assert!(decrypt(enc, &[0x42; 16], &jwe).is_err());
+
+            // Modifying any character fails
+            for i in 0..jwe.len() {
+                let mut bad = jwe.clone().into_bytes();
+                bad[i] = if bad[i] == b'A' { b'B' } else { b'A' };
+                let bad = String::from_utf8(bad).unwrap();
+                assert!(decrypt(enc, &key, &bad).is_err());
+            }
+
+            // Too few or too many parts fail
+            let parts: Vec<&str> = jwe.split('.').collect();
+            assert!(decrypt(enc, &key, &parts[..4].join(".")).is_err());
+            assert!(decrypt(enc, &key, &format!("{}.", jwe)).is_err());
+        }
+    }
+
+    // A header can't be swapped, even for an equivalent one with the same alg and enc, since the
+    // encoded header is the associated data
+    #[test]
+    fn jwe_tampered_header() {
+        let mut rng = rand::thread_rng();
+        let key = [0x42; 32];
+        let enc = ContentEncryption::A256GcmUtc;
+        let jwe = encrypt(&mut rng, enc, &key, b"hello world").unwrap();
+
+        let headers = [
+            r#"{"enc":"A256GCM-UtC","alg":"dir"}"#,
+            r#"{"alg":"dir", "enc":"A256GCM-UtC"}"#,
+            r#"{"alg":"dir","enc":"A256GCM-UtC","kid":"1"}"#,
+            r#"{"alg":"dir","enc":"A256GCM"}"#,
+            r#"{"alg":"A256KW","enc":"A256GCM-UtC"}"#,
+            r#"{"alg":"dir","enc":"A256GCM-UtC","zip":"DEF"}"#,
+            r#"{"alg":"dir","enc":"A256GCM-UtC","crit":["exp"]}"#,
+            r#"["dir","A256GCM-UtC"]"#,
+            r#"{"alg":"dir","enc":"A256GCM-UtC""#,
+        ];
+        for header in headers {
+            let encoded = URL_SAFE_NO_PAD.encode(header);
+            assert!(decrypt(enc, &key, &replace_part(&jwe, 0, &encoded)).is_err());
+        }
+
+        // A non-empty encrypted key is rejected
+        assert!(decrypt(enc, &key, &replace_part(&jwe, 1, "AAAA")).is_err());
+    }
+
+    // UtC tags are 16 bytes of GCM tag followed by the commitment. Anything else is rejected,
+    // including a bare GCM-length tag and a tag with the commitment cut short.
+    #[test]
+    fn jwe_tag_length() {
+        let mut rng = rand::thread_rng();
+        let key = [0x42; 32];
+
+        for enc in ENCS {
+            let jwe = encrypt(&mut rng, enc, &key, b"hello world").unwrap();
+            let tag = URL_SAFE_NO_PAD
+                .decode(jwe.split('.').nth(4).unwrap())
+                .unwrap();
+            assert_eq!(tag.len(), enc.algorithm().tag_len());
+            assert!(tag.len() > 16);
+
+            for len in [0, 16, tag.len() - 1] {
+                let short = URL_SAFE_NO_PAD.encode(&tag[..len]);
+                assert!(decrypt(enc, &key, &replace_part(&jwe, 4, &short)).is_err());
+            }
+            let long = URL_SAFE_NO_PAD.encode([tag.as_slice(), &[0]].concat());
+            assert!(decrypt(enc, &key, &replace_part(&jwe, 4, &long)).is_err());
+
+            // The same goes for the IV
+            let iv = URL_SAFE_NO_PAD.encode([0u8; 16]);
+            assert!(decrypt(enc, &key, &replace_part(&jwe, 2, &iv)).is_err());
+        }
+    }
+}
@@ -0,0 +1,201 @@
+//! Defines `Keyring`, which decrypts a ciphertext made by any one of several keys without trial
+//! decryption
+
+use crate::utc_transform::{Commitment, DetachedCommitment, InnerTag};
+
+use aead::{AeadInPlace, Error, Key, NewAead, Nonce, Tag};
+use cipher::typenum::Unsigned;
+use subtle::{Choice, ConditionallySelectable, ConstantTimeEq};
+
+/// A set of keys for a committing AEAD whose tag ends in a key commitment, e.g., any `Utc` or
+/// `HkdfHte` alias. Since the commitment identifies the key, decryption recomputes the commitment
+/// under every key, selects the matching key in constant time, and then decrypts exactly once.
+///
+/// This avoids trial decryption. In particular, since at most one decryption happens per
+/// ciphertext, and its cost doesn't depend on which key matched, an attacker can't learn which
+/// keys are in the ring by timing or counting failed decryptions (a _partitioning oracle_).
+pub struct Keyring<A>
+where
+    A: AeadInPlace + DetachedCommitment + NewAead,
+{
+    keys: Vec<A>,
+}
+
+impl<A> Default for Keyring<A>
+where
+    A: AeadInPlace + DetachedCommitment + NewAead,
+{
+    fn default() -> Self {
+        Keyring { keys: Vec::new() }
+    }
+}
+
+impl<A> Keyring<A>
+where
+    A: AeadInPlace + DetachedCommitment + NewAead,
+{
+    /// Makes an empty keyring
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a key to the ring and returns its index
+    pub fn push(&mut self, key: &Key<A>) -> usize {
+        self.keys.push(A::new(key));
+        self.keys.len() - 1
+    }
+
+    /// Returns the number of keys in the ring
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Returns whether the ring has no keys
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Returns the AEAD for the key at the given index, e.g., to encrypt with it
+    pub fn get(&self, index: usize) -> Option<&A> {
+        self.keys.get(index)
+    }
+
+    /// Finds the key whose commitment matches `com`, and returns its index. Every key's commitment
+    /// is computed and compared in constant time, so the time taken doesn't depend on which key
+    /// matches, if any. If more than one key matches, i.e., the same key was pushed twice, this
+    /// returns the last one.
+    fn find_key(
+        &self,
+        nonce: &Nonce<A>,
+        associated_data: &[u8],
+        com: &Commitment<A>,
+    ) -> Option<usize> {
+        let mut found = Choice::from(0);
+        let mut index = 0u64;
+        for (i, ciph) in self.keys.iter().enumerate() {
+            let matches = ciph.commitment(nonce, associated_data).ct_eq(com);
+            index.conditional_assign(&(i as u64), matches);
+            found |= matches;
+        }
+
+        if found.into() {
+            Some(index as usize)
+        } else {
+            None
+        }
+    }
+
+    /// Decrypts `buffer` in place using whichever key made the ciphertext, and returns that key's
+    /// index. Returns an error if no key's commitment matches or decryption fails, in which case
+    /// `buffer` is left as ciphertext.
+    pub fn decrypt_in_place_detached(
+        &self,
+        nonce: &Nonce<A>,
+        associated_data: &[u8],
+        buffer: &mut [u8],
+        tag: &Tag<A>,
+    ) -> Result<usize, Error> {
+        // The tag is inner_tag || com
+        let inner_tag_size = A::InnerTagSize::USIZE;
+        let inner_tag = InnerTag::<A>::from_slice(&tag[..inner_tag_size]);
+        let com = Commitment::<A>::from_slice(&tag[inner_tag_size..]);
+
+        let index = self.find_key(nonce, associated_data, com).ok_or(Error)?;
+        self.keys[index].decrypt_in_place_detached_with_commitment(
+            nonce,
+            associated_data,
+            buffer,
+            inner_tag,
+            com,
+        )?;
+
+        Ok(index)
+    }
+
+    /// Decrypts a ciphertext with the tag appended, using whichever key made it. Returns the index
+    /// of that key and the plaintext.
+    pub fn decrypt(
+        &self,
+        nonce: &Nonce<A>,
+        associated_data: &[u8],
+        ciphertext: &[u8],
+    ) -> Result<(usize, Vec<u8>), Error> {
+        let ct_len = ciphertext
+            .len()
+            .checked_sub(A::TagSize::USIZE)
+            .ok_or(Error)?;
+        let (ct, tag) = ciphertext.split_at(ct_len);
+
+        let mut buf = ct.to_vec();
+        let index = self.decrypt_in_place_detached(
+            nonce,
+            associated_data,
+            &mut buf,
+            Tag::<A>::from_slice(tag),
+        )?;
+
+        Ok((index, buf))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{FusedHteUtcAes256Gcm, HkdfHteUtcAes128Gcm, MacHteUtcAes256Gcm, UtcAes128Gcm};
+
+    use aead::{Aead, Payload};
+    use rand::RngCore;
+
+    macro_rules! test_keyring {
+        ($aead:ty, $test_name:ident) => {
+            #[test]
+            fn $test_name() {
+                let mut rng = rand::thread_rng();
+                let mut keyring = Keyring::<$aead>::new();
+                assert!(keyring.is_empty());
+                for i in 0..5 {
+                    assert_eq!(keyring.push(&<$aead>::generate_key(&mut rng)), i);
+                }
+                assert_eq!(keyring.len(), 5);
+
+                let mut nonce = Nonce::<$aead>::default();
+                rng.fill_bytes(&mut nonce);
+                let payload = || Payload {
+                    msg: b"hello world",
+                    aad: b"aad",
+                };
+
+                // Every key's ciphertexts decrypt to the right key
+                for i in 0..keyring.len() {
+                    let ct = keyring.get(i).unwrap().encrypt(&nonce, payload()).unwrap();
+                    let (index, pt) = keyring.decrypt(&nonce, b"aad", &ct).unwrap();
+                    assert_eq!(index, i);
+                    assert_eq!(pt, b"hello world");
+
+                    // Modifying any byte fails
+                    for j in 0..ct.len() {
+                        let mut bad_ct = ct.clone();
+                        bad_ct[j] ^= 1;
+                        assert!(keyring.decrypt(&nonce, b"aad", &bad_ct).is_err());
+                    }
+                }
+
+                // A ciphertext under a key that's not in the ring fails
+                let other = <$aead>::new(&<$aead>::generate_key(&mut rng));
+                let ct = other.encrypt(&nonce, payload()).unwrap();
+                assert!(keyring.decrypt(&nonce, b"aad", &ct).is_err());
+
+                // As does one that's too short to hold a tag, and anything on an empty ring
+                assert!(keyring.decrypt(&nonce, b"aad", &ct[..5]).is_err());
+                assert!(Keyring::<$aead>::new()
+                    .decrypt(&nonce, b"aad", &ct)
+                    .is_err());
+            }
+        };
+    }
+
+    test_keyring!(UtcAes128Gcm, keyring_utc_aes128);
+    test_keyring!(HkdfHteUtcAes128Gcm, keyring_hkdfhte_utc_aes128);
+    test_keyring!(MacHteUtcAes256Gcm, keyring_machte_utc_aes256);
+    test_keyring!(FusedHteUtcAes256Gcm, keyring_fusedhte_utc_aes256);
+}
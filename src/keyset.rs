@@ -0,0 +1,509 @@
+//! Defines `Keyset`, a versioned set of keys in the style of Tink. Every key has an ID, an
+//! algorithm, and a status. One enabled key is the _primary_ key, which is used for encryption.
+//! Every ciphertext starts with the ID of the key that made it, so decryption goes straight to the
+//! right key, and keys can be rotated without breaking old ciphertexts.
+//!
+//! # Ciphertext format
+//!
+//! ```text
+//! prefix = 0x01 || be32(key_id)
+//! ciphertext = prefix || nonce || C || tag
+//! ```
+//!
+//! The nonce is random, and its length and the tag length are those of the key's algorithm. The
+//! prefix is prepended to the caller's associated data, so it can't be changed.
+//!
+//! # Serialization format
+//!
+//! ```text
+//! keyset = 0x01 || be32(primary_key_id) || be32(num_keys) || entry_1 || ... || entry_n
+//! entry = be32(key_id) || be16(algorithm_id) || status || be16(key_len) || key
+//! ```
+//!
+//! where `algorithm_id` is an [`AlgorithmId`], `status` is one byte, 1 for enabled and 2 for
+//! disabled, and `key_len` must equal the algorithm's key length. Key IDs must be unique, and the
+//! primary key must be present and enabled. Parsing rejects anything else, including trailing
+//! bytes.
+//!
+//! The serialized keyset contains the raw keys. It MUST be stored encrypted or otherwise
+//! protected.
+
+use crate::{AlgorithmId, AnyKcAead};
+
+use core::fmt;
+
+use aead::Error;
+use rand_core::{CryptoRng, RngCore};
+use zeroize::Zeroizing;
+
+// The version bytes of the ciphertext prefix and the serialization
+const PREFIX_VERSION: u8 = 0x01;
+const SERIALIZATION_VERSION: u8 = 0x01;
+
+/// The length of the key ID prefix on every ciphertext
+pub const PREFIX_LEN: usize = 5;
+
+/// The status of a key in a [`Keyset`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum KeyStatus {
+    /// The key can decrypt, and can encrypt if it's the primary key
+    Enabled = 1,
+    /// The key can't encrypt or decrypt, but is kept so it can be re-enabled
+    Disabled = 2,
+}
+
+impl KeyStatus {
+    fn from_u8(status: u8) -> Option<Self> {
+        match status {
+            1 => Some(KeyStatus::Enabled),
+            2 => Some(KeyStatus::Disabled),
+            _ => None,
+        }
+    }
+}
+
+/// An error from a [`Keyset`] operation
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeysetError {
+    /// No key has the given ID
+    UnknownKeyId,
+    /// The key is disabled
+    KeyDisabled,
+    /// The operation can't be done to the primary key
+    PrimaryKey,
+    /// A serialized keyset or ciphertext is malformed
+    Malformed,
+    /// The underlying AEAD returned an error
+    Aead(Error),
+}
+
+impl From<Error> for KeysetError {
+    fn from(err: Error) -> Self {
+        KeysetError::Aead(err)
+    }
+}
+
+impl fmt::Display for KeysetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeysetError::UnknownKeyId => f.write_str("unknown key ID"),
+            KeysetError::KeyDisabled => f.write_str("key is disabled"),
+            KeysetError::PrimaryKey => f.write_str("operation not allowed on the primary key"),
+            KeysetError::Malformed => f.write_str("malformed keyset or ciphertext"),
+            KeysetError::Aead(err) => err.fmt(f),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for KeysetError {}
+
+struct KeyEntry {
+    id: u32,
+    status: KeyStatus,
+    key: Zeroizing<Vec<u8>>,
+    ciph: AnyKcAead,
+}
+
+impl KeyEntry {
+    fn new(id: u32, alg: AlgorithmId, status: KeyStatus, key: &[u8]) -> Result<Self, KeysetError> {
+        Ok(KeyEntry {
+            id,
+            status,
+            key: Zeroizing::new(key.to_vec()),
+            ciph: AnyKcAead::new(alg, key).map_err(|_| KeysetError::Malformed)?,
+        })
+    }
+}
+
+/// A set of keys with a primary key. See the [module documentation](self) for the formats.
+pub struct Keyset {
+    primary_id: u32,
+    entries: Vec<KeyEntry>,
+}
+
+impl Keyset {
+    /// Makes a new keyset with a single random key of the given algorithm, which is the primary
+    /// key
+    pub fn new<R: CryptoRng + RngCore>(rng: &mut R, alg: AlgorithmId) -> Self {
+        let mut keyset = Keyset {
+            primary_id: 0,
+            entries: Vec::new(),
+        };
+        keyset.primary_id = keyset.add_key(rng, alg);
+        keyset
+    }
+
+    /// Adds a random enabled key of the given algorithm, and returns its ID. The new key is not
+    /// the primary key until [`Keyset::set_primary`] is called.
+    pub fn add_key<R: CryptoRng + RngCore>(&mut self, rng: &mut R, alg: AlgorithmId) -> u32 {
+        // Pick a random ID that isn't already taken
+        let id = loop {
+            let id = rng.next_u32();
+            if self.entry(id).is_none() {
+                break id;
+            }
+        };
+
+        let mut key = Zeroizing::new(vec![0u8; alg.key_len()]);
+        rng.fill_bytes(&mut key);
+        let entry = KeyEntry::new(id, alg, KeyStatus::Enabled, &key)
+            .expect("generated key has the right length");
+        self.entries.push(entry);
+
+        id
+    }
+
+    fn entry(&self, id: u32) -> Option<&KeyEntry> {
+        self.entries.iter().find(|e| e.id == id)
+    }
+
+    fn entry_mut(&mut self, id: u32) -> Result<&mut KeyEntry, KeysetError> {
+        self.entries
+            .iter_mut()
+            .find(|e| e.id == id)
+            .ok_or(KeysetError::UnknownKeyId)
+    }
+
+    /// Returns the ID of the primary key
+    pub fn primary_id(&self) -> u32 {
+        self.primary_id
+    }
+
+    /// Returns the IDs of all the keys, in the order they were added
+    pub fn key_ids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.entries.iter().map(|e| e.id)
+    }
+
+    /// Returns the algorithm and status of the key with the given ID
+    pub fn key_info(&self, id: u32) -> Option<(AlgorithmId, KeyStatus)> {
+        self.entry(id).map(|e| (e.ciph.algorithm(), e.status))
+    }
+
+    /// Makes the given key the primary key. The key must be enabled.
+    pub fn set_primary(&mut self, id: u32) -> Result<(), KeysetError> {
+        if self.entry_mut(id)?.status != KeyStatus::Enabled {
+            return Err(KeysetError::KeyDisabled);
+        }
+        self.primary_id = id;
+        Ok(())
+    }
+
+    /// Enables the given key
+    pub fn enable(&mut self, id: u32) -> Result<(), KeysetError> {
+        self.entry_mut(id)?.status = KeyStatus::Enabled;
+        Ok(())
+    }
+
+    /// Disables the given key, so it can no longer decrypt. The primary key can't be disabled.
+    pub fn disable(&mut self, id: u32) -> Result<(), KeysetError> {
+        if id == self.primary_id {
+            return Err(KeysetError::PrimaryKey);
+        }
+        self.entry_mut(id)?.status = KeyStatus::Disabled;
+        Ok(())
+    }
+
+    /// Removes the given key. Ciphertexts made by it can no longer be decrypted. The primary key
+    /// can't be removed.
+    pub fn remove(&mut self, id: u32) -> Result<(), KeysetError> {
+        if id == self.primary_id {
+            return Err(KeysetError::PrimaryKey);
+        }
+        self.entry_mut(id)?;
+        self.entries.retain(|e| e.id != id);
+        Ok(())
+    }
+
+    /// Encrypts `msg` with the primary key under a random nonce, and returns the ciphertext with
+    /// the key ID prefix
+    pub fn encrypt<R: CryptoRng + RngCore>(
+        &self,
+        rng: &mut R,
+        associated_data: &[u8],
+        msg: &[u8],
+    ) -> Result<Vec<u8>, KeysetError> {
+        let entry = self
+            .entry(self.primary_id)
+            .expect("primary key is always present");
+
+        let mut out = Vec::with_capacity(PREFIX_LEN);
+        out.push(PREFIX_VERSION);
+        out.extend_from_slice(&entry.id.to_be_bytes());
+
+        let aad = [&out[..], associated_data].concat();
+        let sealed = entry.ciph.seal(rng, &aad, msg)?;
+        out.extend_from_slice(&sealed);
+
+        Ok(out)
+    }
+
+    /// Decrypts a ciphertext with whichever key its prefix names. Returns an error if the key is
+    /// unknown or disabled, or decryption fails.
+    pub fn decrypt(
+        &self,
+        associated_data: &[u8],
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>, KeysetError> {
+        if ciphertext.len() < PREFIX_LEN || ciphertext[0] != PREFIX_VERSION {
+            return Err(KeysetError::Malformed);
+        }
+        let (prefix, sealed) = ciphertext.split_at(PREFIX_LEN);
+        let id = u32::from_be_bytes(prefix[1..].try_into().unwrap());
+
+        let entry = self.entry(id).ok_or(KeysetError::UnknownKeyId)?;
+        if entry.status != KeyStatus::Enabled {
+            return Err(KeysetError::KeyDisabled);
+        }
+
+        let aad = [prefix, associated_data].concat();
+        Ok(entry.ciph.open(&aad, sealed)?)
+    }
+
+    /// Serializes the keyset, including the raw keys. The output MUST be protected.
+    pub fn to_bytes(&self) -> Zeroizing<Vec<u8>> {
+        let mut out = Zeroizing::new(Vec::new());
+        out.push(SERIALIZATION_VERSION);
+        out.extend_from_slice(&self.primary_id.to_be_bytes());
+        out.extend_from_slice(&(self.entries.len() as u32).to_be_bytes());
+
+        for entry in &self.entries {
+            out.extend_from_slice(&entry.id.to_be_bytes());
+            out.extend_from_slice(&entry.ciph.algorithm().to_u16().to_be_bytes());
+            out.push(entry.status as u8);
+            out.extend_from_slice(&(entry.key.len() as u16).to_be_bytes());
+            out.extend_from_slice(&entry.key);
+        }
+
+        out
+    }
+
+    /// Parses a serialized keyset. Returns [`KeysetError::Malformed`] if it isn't exactly in the
+    /// documented format.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, KeysetError> {
+        let mut reader = Reader(bytes);
+        if reader.read_u8()? != SERIALIZATION_VERSION {
+            return Err(KeysetError::Malformed);
+        }
+        let primary_id = reader.read_u32()?;
+        let num_keys = reader.read_u32()?;
+
+        let mut entries: Vec<KeyEntry> = Vec::new();
+        for _ in 0..num_keys {
+            let id = reader.read_u32()?;
+            let alg = AlgorithmId::from_u16(reader.read_u16()?).ok_or(KeysetError::Malformed)?;
+            let status = KeyStatus::from_u8(reader.read_u8()?).ok_or(KeysetError::Malformed)?;
+            let key_len = reader.read_u16()? as usize;
+            if key_len != alg.key_len() || entries.iter().any(|e| e.id == id) {
+                return Err(KeysetError::Malformed);
+            }
+            let key = reader.read_bytes(key_len)?;
+
+            entries.push(KeyEntry::new(id, alg, status, key)?);
+        }
+
+        // No trailing bytes, and the primary key is present and enabled
+        if !reader.0.is_empty() {
+            return Err(KeysetError::Malformed);
+        }
+        let primary = entries.iter().find(|e| e.id == primary_id);
+        if primary.map(|e| e.status) != Some(KeyStatus::Enabled) {
+            return Err(KeysetError::Malformed);
+        }
+
+        Ok(Keyset {
+            primary_id,
+            entries,
+        })
+    }
+}
+
+// A minimal reader over a byte slice, for parsing
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], KeysetError> {
+        if self.0.len() < len {
+            return Err(KeysetError::Malformed);
+        }
+        let (out, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(out)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, KeysetError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_u16(&mut self) -> Result<u16, KeysetError> {
+        Ok(u16::from_be_bytes(self.read_bytes(2)?.try_into().unwrap()))
+    }
+
+    fn read_u32(&mut self) -> Result<u32, KeysetError> {
+        Ok(u32::from_be_bytes(self.read_bytes(4)?.try_into().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn keyset_rotation() {
+        let mut rng = rand::thread_rng();
+        let mut keyset = Keyset::new(&mut rng, AlgorithmId::UtcAes128Gcm);
+        let old_id = keyset.primary_id();
+
+        let old_ct = keyset.encrypt(&mut rng, b"aad", b"old message").unwrap();
+        assert_eq!(old_ct[0], 0x01);
+        assert_eq!(&old_ct[1..PREFIX_LEN], old_id.to_be_bytes());
+        assert_eq!(keyset.decrypt(b"aad", &old_ct).unwrap(), b"old message");
+        assert!(keyset.decrypt(b"aae", &old_ct).is_err());
+
+        // Add a new key of a different algorithm. It doesn't encrypt until it's the primary.
+        let new_id = keyset.add_key(&mut rng, AlgorithmId::MacHteUtcAes256Gcm);
+        assert_ne!(new_id, old_id);
+        let ct = keyset.encrypt(&mut rng, b"", b"").unwrap();
+        assert_eq!(&ct[1..PREFIX_LEN], old_id.to_be_bytes());
+
+        // Rotate. New ciphertexts use the new key, and old ones still decrypt.
+        keyset.set_primary(new_id).unwrap();
+        let new_ct = keyset.encrypt(&mut rng, b"aad", b"new message").unwrap();
+        assert_eq!(&new_ct[1..PREFIX_LEN], new_id.to_be_bytes());
+        assert_eq!(keyset.decrypt(b"aad", &new_ct).unwrap(), b"new message");
+        assert_eq!(keyset.decrypt(b"aad", &old_ct).unwrap(), b"old message");
+
+        // Changing the prefix to the other key fails
+        let mut bad_ct = new_ct.clone();
+        bad_ct[1..PREFIX_LEN].copy_from_slice(&old_id.to_be_bytes());
+        assert!(keyset.decrypt(b"aad", &bad_ct).is_err());
+
+        // Once the old key is removed, its ciphertexts are gone for good
+        keyset.remove(old_id).unwrap();
+        assert_eq!(
+            keyset.decrypt(b"aad", &old_ct),
+            Err(KeysetError::UnknownKeyId)
+        );
+        assert_eq!(keyset.key_ids().collect::<Vec<_>>(), [new_id]);
+    }
+
+    #[test]
+    fn keyset_disabled_keys() {
+        let mut rng = rand::thread_rng();
+        let mut keyset = Keyset::new(&mut rng, AlgorithmId::UtcAes256Gcm);
+        let primary_id = keyset.primary_id();
+        let other_id = keyset.add_key(&mut rng, AlgorithmId::UtcAes256Gcm);
+
+        keyset.set_primary(other_id).unwrap();
+        let other_ct = keyset.encrypt(&mut rng, b"", b"hello").unwrap();
+        keyset.set_primary(primary_id).unwrap();
+
+        // Disabled keys can't decrypt or become primary, until they're re-enabled
+        keyset.disable(other_id).unwrap();
+        assert_eq!(
+            keyset.key_info(other_id),
+            Some((AlgorithmId::UtcAes256Gcm, KeyStatus::Disabled))
+        );
+        assert_eq!(
+            keyset.decrypt(b"", &other_ct),
+            Err(KeysetError::KeyDisabled)
+        );
+        assert_eq!(keyset.set_primary(other_id), Err(KeysetError::KeyDisabled));
+        keyset.enable(other_id).unwrap();
+        assert_eq!(keyset.decrypt(b"", &other_ct).unwrap(), b"hello");
+
+        // The primary key can't be disabled or removed
+        assert_eq!(keyset.disable(primary_id), Err(KeysetError::PrimaryKey));
+        assert_eq!(keyset.remove(primary_id), Err(KeysetError::PrimaryKey));
+
+        // Unknown keys are errors
+        let unknown_id = primary_id ^ other_id ^ 1;
+        assert_eq!(keyset.disable(unknown_id), Err(KeysetError::UnknownKeyId));
+        assert_eq!(
+            keyset.set_primary(unknown_id),
+            Err(KeysetError::UnknownKeyId)
+        );
+    }
+
+    #[test]
+    fn keyset_serialization() {
+        let mut rng = rand::thread_rng();
+        let mut keyset = Keyset::new(&mut rng, AlgorithmId::HkdfHteUtcAes128Gcm);
+        let disabled_id = keyset.add_key(&mut rng, AlgorithmId::FusedHteUtcAes256Gcm);
+        keyset.disable(disabled_id).unwrap();
+        let ct = keyset.encrypt(&mut rng, b"aad", b"hello").unwrap();
+
+        let bytes = keyset.to_bytes();
+        assert_eq!(
+            bytes.len(),
+            1 + 4 + 4 + (4 + 2 + 1 + 2 + 16) + (4 + 2 + 1 + 2 + 32)
+        );
+        let parsed = Keyset::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.primary_id(), keyset.primary_id());
+        assert_eq!(
+            parsed.key_info(disabled_id),
+            Some((AlgorithmId::FusedHteUtcAes256Gcm, KeyStatus::Disabled))
+        );
+        assert_eq!(parsed.decrypt(b"aad", &ct).unwrap(), b"hello");
+        assert_eq!(*parsed.to_bytes(), *bytes);
+    }
+
+    #[test]
+    fn keyset_malformed() {
+        let mut rng = rand::thread_rng();
+        let mut keyset = Keyset::new(&mut rng, AlgorithmId::UtcAes128Gcm);
+        let other_id = keyset.add_key(&mut rng, AlgorithmId::UtcAes128Gcm);
+        let bytes = keyset.to_bytes();
+        let first_entry = 1 + 4 + 4;
+        let second_entry = first_entry + 4 + 2 + 1 + 2 + 16;
+
+        let modified = |f: &dyn Fn(&mut Vec<u8>)| {
+            let mut bad = bytes.to_vec();
+            f(&mut bad);
+            Keyset::from_bytes(&bad).err()
+        };
+        let malformed = Some(KeysetError::Malformed);
+
+        // Truncation, trailing bytes, and bad versions
+        for len in 0..bytes.len() {
+            assert_eq!(Keyset::from_bytes(&bytes[..len]).err(), malformed);
+        }
+        assert_eq!(modified(&|b| b.push(0)), malformed);
+        assert_eq!(modified(&|b| b[0] = 2), malformed);
+
+        // Unknown algorithms and statuses, and wrong key lengths
+        assert_eq!(modified(&|b| b[first_entry + 5] = 0), malformed);
+        assert_eq!(modified(&|b| b[first_entry + 6] = 0), malformed);
+        assert_eq!(modified(&|b| b[first_entry + 6] = 3), malformed);
+        assert_eq!(modified(&|b| b[first_entry + 8] = 32), malformed);
+
+        // Duplicate IDs
+        assert_eq!(
+            modified(&|b| {
+                let id = b[first_entry..first_entry + 4].to_vec();
+                b[second_entry..second_entry + 4].copy_from_slice(&id);
+            }),
+            malformed
+        );
+
+        // The primary key must be present and enabled
+        let primary_id = keyset.primary_id();
+        assert_eq!(
+            modified(&|b| b[1..5].copy_from_slice(&(primary_id ^ other_id ^ 1).to_be_bytes())),
+            malformed
+        );
+        assert_eq!(modified(&|b| b[first_entry + 6] = 2), malformed);
+
+        // Bad ciphertext prefixes
+        let ct = keyset.encrypt(&mut rng, b"", b"").unwrap();
+        let mut bad_ct = ct.clone();
+        bad_ct[0] = 0;
+        assert_eq!(keyset.decrypt(b"", &bad_ct), Err(KeysetError::Malformed));
+        assert_eq!(keyset.decrypt(b"", &ct[..4]), Err(KeysetError::Malformed));
+        assert_eq!(
+            keyset.decrypt(b"", &ct[..PREFIX_LEN]),
+            Err(KeysetError::Aead(Error))
+        );
+    }
+}
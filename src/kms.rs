@@ -0,0 +1,260 @@
+//! Defines envelope encryption, where every object is encrypted under a fresh data encryption key
+//! (DEK), and the DEK is wrapped by a long-lived key encryption key (KEK), e.g., one held in a KMS.
+//! Both layers use [`HkdfHteUtcAes256Gcm`], which is context-committing. So a wrapped DEK opens
+//! under exactly one KEK and object AAD, and a payload opens under exactly one DEK, which rules
+//! out the attacks where a malicious wrapper makes one ciphertext decrypt two ways.
+//!
+//! The sealed format is
+//!
+//! ```text
+//! sealed = be16(|wrapped_dek|) || wrapped_dek || C || tag
+//! ```
+//!
+//! where `C || tag` is the payload encrypted under the DEK with the object's AAD. Since every DEK
+//! encrypts exactly one payload, the payload nonce is fixed to zero.
+
+use crate::{seal::SealExt, HkdfHteUtcAes256Gcm};
+
+use aead::{AeadInPlace, Error, Key, NewAead, Nonce, Tag};
+use cipher::typenum::Unsigned;
+use rand_core::{CryptoRng, CryptoRngCore, RngCore};
+use zeroize::Zeroizing;
+
+// Separates DEK wrapping from any other use of a LocalKek key
+const WRAP_DOMAIN_SEP: &[u8] = b"kc-aeads kms DEK";
+
+// The size of a DEK, or a LocalKek key
+const DEK_LEN: usize = 32;
+
+// The size of the wrapped DEK length field
+const WRAPPED_LEN_LEN: usize = 2;
+
+/// A key encryption key, which wraps and unwraps data encryption keys. A KMS client would
+/// implement this by calling out to the KMS. Implementations MUST use a committing AEAD and bind
+/// the associated data, like [`LocalKek`] does.
+pub trait KeyEncryptionKey {
+    /// Wraps `dek`, binding it to `associated_data`. Implementations which need randomness use
+    /// `rng`. Ones that don't, e.g., remote KMSs, can ignore it.
+    fn wrap_key(
+        &self,
+        rng: &mut dyn CryptoRngCore,
+        dek: &[u8],
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, Error>;
+
+    /// Unwraps a DEK which was wrapped with the same associated data
+    fn unwrap_key(
+        &self,
+        wrapped_dek: &[u8],
+        associated_data: &[u8],
+    ) -> Result<Zeroizing<Vec<u8>>, Error>;
+}
+
+/// A KEK held in memory, which wraps DEKs with [`HkdfHteUtcAes256Gcm`] under random nonces. This
+/// is meant for tests and for deployments without a KMS.
+pub struct LocalKek {
+    ciph: HkdfHteUtcAes256Gcm,
+}
+
+impl LocalKek {
+    /// Makes a KEK from the given key
+    pub fn new(key: &Key<HkdfHteUtcAes256Gcm>) -> Self {
+        LocalKek {
+            ciph: HkdfHteUtcAes256Gcm::new(key),
+        }
+    }
+
+    /// Makes a KEK from a random key
+    pub fn generate<R: CryptoRng + RngCore>(rng: &mut R) -> Self {
+        let mut key = Zeroizing::new([0u8; DEK_LEN]);
+        rng.fill_bytes(key.as_mut());
+        Self::new(Key::<HkdfHteUtcAes256Gcm>::from_slice(key.as_ref()))
+    }
+}
+
+impl KeyEncryptionKey for LocalKek {
+    fn wrap_key(
+        &self,
+        mut rng: &mut dyn CryptoRngCore,
+        dek: &[u8],
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let aad = [WRAP_DOMAIN_SEP, associated_data].concat();
+        self.ciph.seal(&mut rng, &aad, dek)
+    }
+
+    fn unwrap_key(
+        &self,
+        wrapped_dek: &[u8],
+        associated_data: &[u8],
+    ) -> Result<Zeroizing<Vec<u8>>, Error> {
+        let aad = [WRAP_DOMAIN_SEP, associated_data].concat();
+        self.ciph.open(&aad, wrapped_dek).map(Zeroizing::new)
+    }
+}
+
+/// Encrypts `msg` under a fresh DEK, wraps the DEK with `kek`, and returns the sealed object. Both
+/// the payload and the wrapped DEK are bound to `associated_data`.
+pub fn seal<K, R>(
+    kek: &K,
+    rng: &mut R,
+    associated_data: &[u8],
+    msg: &[u8],
+) -> Result<Vec<u8>, Error>
+where
+    K: KeyEncryptionKey + ?Sized,
+    R: CryptoRng + RngCore,
+{
+    let mut dek = Zeroizing::new([0u8; DEK_LEN]);
+    rng.fill_bytes(dek.as_mut());
+    let wrapped_dek = kek.wrap_key(rng, dek.as_ref(), associated_data)?;
+    let wrapped_len = u16::try_from(wrapped_dek.len()).map_err(|_| Error)?;
+
+    let mut out = Vec::with_capacity(
+        WRAPPED_LEN_LEN
+            + wrapped_dek.len()
+            + msg.len()
+            + <HkdfHteUtcAes256Gcm as aead::AeadCore>::TagSize::USIZE,
+    );
+    out.extend_from_slice(&wrapped_len.to_be_bytes());
+    out.extend_from_slice(&wrapped_dek);
+
+    // Encrypt the payload under the DEK. The DEK is only ever used once, so the nonce can be fixed.
+    let start = out.len();
+    out.extend_from_slice(msg);
+    let tag = HkdfHteUtcAes256Gcm::new(Key::<HkdfHteUtcAes256Gcm>::from_slice(dek.as_ref()))
+        .encrypt_in_place_detached(
+            &Nonce::<HkdfHteUtcAes256Gcm>::default(),
+            associated_data,
+            &mut out[start..],
+        )?;
+    out.extend_from_slice(&tag);
+
+    Ok(out)
+}
+
+/// Unwraps the DEK of a sealed object with `kek`, and decrypts the payload. Returns an error if
+/// the object is malformed, or either layer fails to decrypt.
+pub fn open<K>(kek: &K, associated_data: &[u8], sealed: &[u8]) -> Result<Vec<u8>, Error>
+where
+    K: KeyEncryptionKey + ?Sized,
+{
+    let tag_len = <HkdfHteUtcAes256Gcm as aead::AeadCore>::TagSize::USIZE;
+
+    // Split off the wrapped DEK
+    if sealed.len() < WRAPPED_LEN_LEN {
+        return Err(Error);
+    }
+    let (wrapped_len, rest) = sealed.split_at(WRAPPED_LEN_LEN);
+    let wrapped_len = u16::from_be_bytes([wrapped_len[0], wrapped_len[1]]) as usize;
+    if rest.len() < wrapped_len + tag_len {
+        return Err(Error);
+    }
+    let (wrapped_dek, payload) = rest.split_at(wrapped_len);
+    let (ciphertext, tag) = payload.split_at(payload.len() - tag_len);
+
+    let dek = kek.unwrap_key(wrapped_dek, associated_data)?;
+    if dek.len() != DEK_LEN {
+        return Err(Error);
+    }
+
+    let mut buf = ciphertext.to_vec();
+    HkdfHteUtcAes256Gcm::new(Key::<HkdfHteUtcAes256Gcm>::from_slice(&dek))
+        .decrypt_in_place_detached(
+            &Nonce::<HkdfHteUtcAes256Gcm>::default(),
+            associated_data,
+            &mut buf,
+            Tag::<HkdfHteUtcAes256Gcm>::from_slice(tag),
+        )?;
+
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use core::cell::Cell;
+
+    #[test]
+    fn kms_correctness() {
+        let mut rng = rand::thread_rng();
+        let kek = LocalKek::generate(&mut rng);
+
+        for msg_len in [0, 1, 100] {
+            let msg = vec![0x42; msg_len];
+            let sealed = seal(&kek, &mut rng, b"object 1", &msg).unwrap();
+            assert_eq!(open(&kek, b"object 1", &sealed).unwrap(), msg);
+
+            // The AAD and KEK must match
+            assert!(open(&kek, b"object 2", &sealed).is_err());
+            assert!(open(&LocalKek::generate(&mut rng), b"object 1", &sealed).is_err());
+
+            // Modifying any byte fails
+            for i in 0..sealed.len() {
+                let mut bad = sealed.clone();
+                bad[i] ^= 1;
+                assert!(open(&kek, b"object 1", &bad).is_err());
+            }
+
+            // As does truncating
+            for len in 0..sealed.len() {
+                assert!(open(&kek, b"object 1", &sealed[..len]).is_err());
+            }
+        }
+    }
+
+    // A wrapped DEK can't be moved to another object, even under the same KEK
+    #[test]
+    fn kms_dek_bound_to_aad() {
+        let mut rng = rand::thread_rng();
+        let kek = LocalKek::generate(&mut rng);
+        let dek = [7u8; 32];
+        let wrapped = kek.wrap_key(&mut rng, &dek, b"object 1").unwrap();
+        assert_eq!(*kek.unwrap_key(&wrapped, b"object 1").unwrap(), dek);
+        assert!(kek.unwrap_key(&wrapped, b"object 2").is_err());
+    }
+
+    // A stand-in for a remote KMS, which counts calls and is used through a trait object
+    struct CountingKek {
+        inner: LocalKek,
+        calls: Cell<usize>,
+    }
+
+    impl KeyEncryptionKey for CountingKek {
+        fn wrap_key(
+            &self,
+            rng: &mut dyn CryptoRngCore,
+            dek: &[u8],
+            associated_data: &[u8],
+        ) -> Result<Vec<u8>, Error> {
+            self.calls.set(self.calls.get() + 1);
+            self.inner.wrap_key(rng, dek, associated_data)
+        }
+
+        fn unwrap_key(
+            &self,
+            wrapped_dek: &[u8],
+            associated_data: &[u8],
+        ) -> Result<Zeroizing<Vec<u8>>, Error> {
+            self.calls.set(self.calls.get() + 1);
+            self.inner.unwrap_key(wrapped_dek, associated_data)
+        }
+    }
+
+    #[test]
+    fn kms_custom_kek() {
+        let mut rng = rand::thread_rng();
+        let kek = CountingKek {
+            inner: LocalKek::generate(&mut rng),
+            calls: Cell::new(0),
+        };
+        let dyn_kek: &dyn KeyEncryptionKey = &kek;
+
+        let sealed = seal(dyn_kek, &mut rng, b"", b"hello world").unwrap();
+        assert_eq!(open(dyn_kek, b"", &sealed).unwrap(), b"hello world");
+
+        // One wrap and one unwrap
+        assert_eq!(kek.calls.get(), 2);
+    }
+}
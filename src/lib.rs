@@ -1,12 +1,49 @@
+mod algorithm;
+mod any_aead;
+#[cfg(feature = "tokio")]
+pub mod async_io;
+#[cfg(feature = "cose")]
+pub mod cose;
 mod cx_prf;
+pub mod envelope;
+mod fused_hte_utc;
 mod hkdf_com_prf;
 mod hkdf_hte_transform;
+#[cfg(feature = "hpke")]
+mod hpke_aead;
+#[cfg(feature = "std")]
+pub mod io;
+#[cfg(feature = "jose")]
+pub mod jwe;
+mod keyring;
+pub mod keyset;
+pub mod kms;
+mod limited;
 mod mac_hte_transform;
+pub mod multi_recipient;
+pub mod noise;
+#[cfg(feature = "password")]
+pub mod password;
+pub mod seal;
+#[cfg(feature = "x25519")]
+pub mod sealed_box;
+pub mod seekable;
+pub mod stream;
+mod utc_chacha20poly1305;
 mod utc_transform;
 
 #[macro_use]
 mod util;
 
+pub use algorithm::AlgorithmId;
+pub use any_aead::AnyKcAead;
+pub use cx_prf::CxPrf;
+pub use fused_hte_utc::*;
+pub use hkdf_com_prf::*;
 pub use hkdf_hte_transform::*;
+pub use keyring::Keyring;
+pub use limited::*;
 pub use mac_hte_transform::*;
+pub use utc_chacha20poly1305::*;
 pub use utc_transform::*;
+pub use util::{CommittingPrf, PrfCom, PrfMask};
@@ -0,0 +1,360 @@
+//! Defines `Limited`, a wrapper which counts the messages and bytes encrypted under a key and
+//! refuses to encrypt once they pass configurable limits, so the caller knows when to rotate keys.
+
+use crate::{
+    FusedHteUtcAes128Gcm, FusedHteUtcAes256Gcm, HkdfHteUtcAes128Gcm, HkdfHteUtcAes256Gcm,
+    MacHteUtcAes128Gcm, MacHteUtcAes256Gcm, UtcAes128Gcm, UtcAes256Gcm,
+};
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use aead::{AeadInPlace, Error, Key, NewAead, Nonce, Tag};
+
+/// Limits on how much a single key may encrypt
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    /// The maximum number of messages that may be encrypted
+    pub max_messages: u64,
+    /// The maximum total number of bytes that may be encrypted, counting both plaintext and
+    /// associated data
+    pub max_bytes: u64,
+}
+
+// How the defaults are derived. We target an adversarial advantage of at most 2⁻³² per key, which
+// is the same target NIST SP 800-38D uses for AES-GCM with random nonces. The defaults assume
+// random nonces, e.g., from `SealExt::seal`. Below, q is the number of messages, σ is the total
+// number of 16-byte blocks encrypted, and σ_H is the total number of hash blocks that H processes.
+//
+// Nonce term (every alias): all the aliases take 96-bit nonces, and with q random nonces some pair
+// repeats with probability about q²/2⁹⁷. That's 2⁻³³ at q = 2³², which is where the message limit
+// comes from.
+//
+// UtC[F, A] (§7): every message is encrypted under its own key (com, mask) ← F(K, N), so the bound
+// is the PRF advantage of F plus the multi-user advantage of AES-GCM with one message per user.
+// For F = HKDF, the PRF term is about q²/2^c where c is the chaining value size of the hash, since
+// the input is just the nonce. The AES-GCM term is Σ(ℓᵢ + 2)²/2¹²⁹ for a message of ℓᵢ blocks.
+// AES-GCM itself caps ℓᵢ at 2³² - 2, so the term is at most 2³²·σ/2¹²⁹. We give it a budget of
+// 2⁻⁴⁰, well under the nonce term, which allows σ = 2⁵⁷ blocks, i.e., 2⁶¹ bytes.
+//
+// HtE[A, H] (§3): on top of the UtC terms, H must be a PRF on (N, A). This is the term that
+// depends on associated data, since H hashes all of it. Its PRF advantage is about σ_H²/2^c, and
+// the byte limit bounds σ_H by about 2⁶¹/b + 3q for a hash with b-byte blocks, counting padding
+// and the HMAC or HKDF key blocks. The derived key L must also not collide for two (N, A) with the
+// same N, which happens with probability about q²/2^(|L|+97).
+//
+// Every other term is computed per alias below. They are all negligible, so every alias comes to
+// about 2⁻³³ + 2⁻⁴⁰ ≤ 2⁻³², and they all share RANDOM_NONCE_LIMITS.
+//
+// Callers who guarantee unique nonces (e.g., with `CounterSealer`) and want a different policy can
+// use `Limited::with_limits`.
+
+// The nonce term sets the message limit, and the AES-GCM term sets the byte limit
+const RANDOM_NONCE_LIMITS: Limits = Limits {
+    max_messages: 1 << 32,
+    max_bytes: 1 << 61,
+};
+
+/// An AEAD with default usage limits
+pub trait DefaultLimits {
+    /// The limits on a single key, assuming random nonces
+    const LIMITS: Limits;
+}
+
+// F = HKDF-SHA256, c = 256: q²/2²⁵⁶ = 2⁻¹⁹². AES-128-GCM: 2⁻⁴⁰ at 2⁶¹ bytes.
+impl DefaultLimits for UtcAes128Gcm {
+    const LIMITS: Limits = RANDOM_NONCE_LIMITS;
+}
+
+// F = HKDF-SHA512, c = 512: q²/2⁵¹² = 2⁻⁴⁴⁸. AES-256-GCM: 2⁻⁴⁰ at 2⁶¹ bytes.
+impl DefaultLimits for UtcAes256Gcm {
+    const LIMITS: Limits = RANDOM_NONCE_LIMITS;
+}
+
+// UtcAes128Gcm's terms, plus H = HMAC-SHA256 with b = 64 and c = 256, so σ_H ≈ 2⁵⁵ and the PRF
+// term is 2⁻¹⁴⁶. |L| = 128, so the L collision term is 2⁻¹⁶¹.
+impl DefaultLimits for MacHteUtcAes128Gcm {
+    const LIMITS: Limits = RANDOM_NONCE_LIMITS;
+}
+
+// UtcAes256Gcm's terms, plus H = HMAC-SHA512 with b = 128 and c = 512, so σ_H ≈ 2⁵⁴ and the PRF
+// term is 2⁻⁴⁰⁴. |L| = 256, so the L collision term is 2⁻²⁸⁹.
+impl DefaultLimits for MacHteUtcAes256Gcm {
+    const LIMITS: Limits = RANDOM_NONCE_LIMITS;
+}
+
+// UtcAes128Gcm's terms, plus H = HKDF-SHA256 with b = 64 and c = 256, so σ_H ≈ 2⁵⁵ and the PRF
+// term is 2⁻¹⁴⁶. |L| = 128, so the L collision term is 2⁻¹⁶¹.
+impl DefaultLimits for HkdfHteUtcAes128Gcm {
+    const LIMITS: Limits = RANDOM_NONCE_LIMITS;
+}
+
+// UtcAes256Gcm's terms, plus H = HKDF-SHA512 with b = 128 and c = 512, so σ_H ≈ 2⁵⁴ and the PRF
+// term is 2⁻⁴⁰⁴. |L| = 256, so the L collision term is 2⁻²⁸⁹.
+impl DefaultLimits for HkdfHteUtcAes256Gcm {
+    const LIMITS: Limits = RANDOM_NONCE_LIMITS;
+}
+
+// FusedHteUtc is UtC[G', A] where G' is one HKDF-SHA256 call on (N, A), so this is
+// HkdfHteUtcAes128Gcm's bound without the separate F term
+impl DefaultLimits for FusedHteUtcAes128Gcm {
+    const LIMITS: Limits = RANDOM_NONCE_LIMITS;
+}
+
+// As above, with HKDF-SHA512, so this is HkdfHteUtcAes256Gcm's bound without the separate F term
+impl DefaultLimits for FusedHteUtcAes256Gcm {
+    const LIMITS: Limits = RANDOM_NONCE_LIMITS;
+}
+
+/// An error from a [`Limited`] AEAD
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LimitError {
+    /// The key has encrypted the maximum number of messages, and should be rotated
+    MessageLimitReached,
+    /// The key has encrypted the maximum number of bytes, and should be rotated
+    ByteLimitReached,
+    /// The underlying AEAD returned an error
+    Aead(Error),
+}
+
+impl From<Error> for LimitError {
+    fn from(err: Error) -> Self {
+        LimitError::Aead(err)
+    }
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitError::MessageLimitReached => f.write_str("key usage limit reached (messages)"),
+            LimitError::ByteLimitReached => f.write_str("key usage limit reached (bytes)"),
+            LimitError::Aead(err) => err.fmt(f),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for LimitError {}
+
+/// An AEAD which counts how many messages and bytes it encrypts, and returns an error once either
+/// exceeds its [`Limits`]. The counters are atomic, so a `Limited` can be shared between threads.
+/// Decryption is not limited.
+pub struct Limited<A: AeadInPlace> {
+    ciph: A,
+    limits: Limits,
+    messages: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl<A> Limited<A>
+where
+    A: AeadInPlace + NewAead + DefaultLimits,
+{
+    /// Makes a new AEAD with the given key and the default limits for `A`
+    pub fn new(key: &Key<A>) -> Self {
+        Self::with_limits(A::new(key), A::LIMITS)
+    }
+}
+
+impl<A: AeadInPlace> Limited<A> {
+    /// Wraps the given AEAD with the given limits
+    pub fn with_limits(ciph: A, limits: Limits) -> Self {
+        Limited {
+            ciph,
+            limits,
+            messages: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the limits of this AEAD
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// Returns the number of messages encrypted so far
+    pub fn messages_encrypted(&self) -> u64 {
+        self.messages.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of bytes encrypted so far, counting both plaintext and associated data
+    pub fn bytes_encrypted(&self) -> u64 {
+        self.bytes.load(Ordering::SeqCst)
+    }
+
+    /// Encrypts `buffer` in place if doing so stays within the limits. Returns an error if either
+    /// limit would be exceeded, in which case the key should be rotated. Nothing is counted if a
+    /// limit is hit.
+    ///
+    /// Once a message is counted, it stays counted, even if encryption later fails. This errs on
+    /// the side of rotating early.
+    pub fn encrypt_in_place_detached(
+        &self,
+        nonce: &Nonce<A>,
+        associated_data: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<Tag<A>, LimitError> {
+        // Reserve a message, then the bytes. Each is a single atomic update, so concurrent callers
+        // can't together exceed a limit. If the bytes don't fit, the message is given back.
+        let max_messages = self.limits.max_messages;
+        self.messages
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |m| {
+                (m < max_messages).then(|| m + 1)
+            })
+            .map_err(|_| LimitError::MessageLimitReached)?;
+
+        let len = (buffer.len() as u64).saturating_add(associated_data.len() as u64);
+        let max_bytes = self.limits.max_bytes;
+        self.bytes
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |b| {
+                b.checked_add(len).filter(|&total| total <= max_bytes)
+            })
+            .map_err(|_| {
+                self.messages.fetch_sub(1, Ordering::SeqCst);
+                LimitError::ByteLimitReached
+            })?;
+
+        Ok(self
+            .ciph
+            .encrypt_in_place_detached(nonce, associated_data, buffer)?)
+    }
+
+    /// Decrypts `buffer` in place. This doesn't count towards the limits.
+    pub fn decrypt_in_place_detached(
+        &self,
+        nonce: &Nonce<A>,
+        associated_data: &[u8],
+        buffer: &mut [u8],
+        tag: &Tag<A>,
+    ) -> Result<(), LimitError> {
+        Ok(self
+            .ciph
+            .decrypt_in_place_detached(nonce, associated_data, buffer, tag)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn default_limits() {
+        // 2³² messages keeps the random nonce collision probability q²/2⁹⁷ at 2⁻³³, and 2⁶¹ bytes
+        // keeps the AES-GCM term at 2⁻⁴⁰
+        let limits = Limited::<UtcAes128Gcm>::new(&Default::default()).limits();
+        assert_eq!(limits.max_messages, 1 << 32);
+        assert_eq!(limits.max_bytes, 1 << 61);
+        assert_eq!(HkdfHteUtcAes256Gcm::LIMITS, limits);
+    }
+
+    #[test]
+    fn message_limit() {
+        let mut rng = rand::thread_rng();
+        let ciph = UtcAes128Gcm::new(&UtcAes128Gcm::generate_key(&mut rng));
+        let limited = Limited::with_limits(
+            ciph,
+            Limits {
+                max_messages: 3,
+                max_bytes: u64::MAX,
+            },
+        );
+        let nonce = Nonce::<UtcAes128Gcm>::default();
+
+        let mut buf = *b"hello world";
+        let mut tag = Tag::<UtcAes128Gcm>::default();
+        for _ in 0..3 {
+            buf = *b"hello world";
+            tag = limited
+                .encrypt_in_place_detached(&nonce, b"", &mut buf)
+                .unwrap();
+        }
+        assert_eq!(limited.messages_encrypted(), 3);
+
+        let mut buf2 = *b"hello world";
+        assert_eq!(
+            limited.encrypt_in_place_detached(&nonce, b"", &mut buf2),
+            Err(LimitError::MessageLimitReached)
+        );
+        assert_eq!(&buf2, b"hello world");
+        assert_eq!(limited.messages_encrypted(), 3);
+
+        // Decryption still works, and failures are reported as AEAD errors
+        limited
+            .decrypt_in_place_detached(&nonce, b"", &mut buf, &tag)
+            .unwrap();
+        assert_eq!(&buf, b"hello world");
+        assert_eq!(
+            limited.decrypt_in_place_detached(&nonce, b"x", &mut buf, &tag),
+            Err(LimitError::Aead(Error))
+        );
+    }
+
+    #[test]
+    fn byte_limit() {
+        let mut rng = rand::thread_rng();
+        let ciph = MacHteUtcAes128Gcm::new(&MacHteUtcAes128Gcm::generate_key(&mut rng));
+        let limited = Limited::with_limits(
+            ciph,
+            Limits {
+                max_messages: u64::MAX,
+                max_bytes: 20,
+            },
+        );
+        let nonce = Nonce::<MacHteUtcAes128Gcm>::default();
+
+        // Associated data counts towards the limit
+        let mut buf = [0u8; 10];
+        limited
+            .encrypt_in_place_detached(&nonce, b"aaaaa", &mut buf)
+            .unwrap();
+        assert_eq!(limited.bytes_encrypted(), 15);
+        assert_eq!(
+            limited.encrypt_in_place_detached(&nonce, b"a", &mut buf[..5]),
+            Err(LimitError::ByteLimitReached)
+        );
+
+        // Hitting the byte limit doesn't use up a message
+        assert_eq!(limited.messages_encrypted(), 1);
+        assert_eq!(limited.bytes_encrypted(), 15);
+        limited
+            .encrypt_in_place_detached(&nonce, b"", &mut buf[..5])
+            .unwrap();
+        assert_eq!(limited.bytes_encrypted(), 20);
+    }
+
+    // Concurrent encryptions can't exceed the limit together
+    #[test]
+    fn concurrent_limit() {
+        let mut rng = rand::thread_rng();
+        let ciph = UtcAes128Gcm::new(&UtcAes128Gcm::generate_key(&mut rng));
+        let limited = Limited::with_limits(
+            ciph,
+            Limits {
+                max_messages: 50,
+                max_bytes: u64::MAX,
+            },
+        );
+
+        let successes: usize = std::thread::scope(|s| {
+            let handles: Vec<_> = (0..4)
+                .map(|_| {
+                    s.spawn(|| {
+                        let nonce = Nonce::<UtcAes128Gcm>::default();
+                        (0..20)
+                            .filter(|_| {
+                                limited
+                                    .encrypt_in_place_detached(&nonce, b"", &mut [0u8; 4])
+                                    .is_ok()
+                            })
+                            .count()
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).sum()
+        });
+        assert_eq!(successes, 50);
+        assert_eq!(limited.messages_encrypted(), 50);
+    }
+}
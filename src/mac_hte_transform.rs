@@ -2,7 +2,7 @@
 //! described in <https://eprint.iacr.org/2022/268> §3. This version of `HtE` is generic over a
 //! given MAC.
 
-use crate::utc_transform::{UtcAes128Gcm, UtcAes256Gcm};
+use crate::utc_transform::{Commitment, DetachedCommitment, InnerTag, UtcAes128Gcm, UtcAes256Gcm};
 
 use core::marker::PhantomData;
 
@@ -43,6 +43,7 @@ pub type MacHteUtcAes256Gcm = MacHte<UtcAes256Gcm, SimpleHmac<Sha512>>;
 /// The Hash-then-Encrypt transform over a generic AEAD and MAC. This converts any key-committing
 /// AEAD to an everything-committing AEAD (i.e., CMTD-1 → CMTD-4). Its construction is described in
 /// Figure 6 of [Bellare and Hoang](https://eprint.iacr.org/2022/268).
+#[derive(Clone)]
 pub struct MacHte<A, M>
 where
     A: AeadInPlace + NewAead,
@@ -51,11 +52,15 @@ where
     LeEq<A::KeySize, M::OutputSize>: NonZero,
 {
     // We use the AEAD key as a MAC key. This is fine as long as the underlying MAC allows
-    // variable-sized keys. We'll know if it doesn't because it will panic immediately.
-    mac_key: Key<A>,
-    _marker: PhantomData<M>,
+    // variable-sized keys. We'll know if it doesn't because it will panic immediately. The MAC is
+    // keyed once here and cloned for every message, so that e.g. HMAC doesn't rehash its
+    // ipad/opad blocks each time.
+    mac: M,
+    _marker: PhantomData<A>,
 }
 
+// The keyed MAC state is derived from the key, so clearing it means replacing it with a MAC keyed
+// by zeros
 impl<A, M> Zeroize for MacHte<A, M>
 where
     A: AeadInPlace + NewAead,
@@ -64,7 +69,8 @@ where
     LeEq<A::KeySize, M::OutputSize>: NonZero,
 {
     fn zeroize(&mut self) {
-        self.mac_key.zeroize()
+        self.mac =
+            <M as KeyInit>::new_from_slice(&Key::<A>::default()).expect("invalid MAC key length");
     }
 }
 
@@ -91,16 +97,38 @@ where
 
     fn new(key: &Key<Self>) -> Self {
         MacHte {
-            mac_key: key.clone(),
+            mac: <M as KeyInit>::new_from_slice(key).expect("invalid MAC key length"),
             _marker: PhantomData,
         }
     }
 }
 
+impl<A, M> MacHte<A, M>
+where
+    A: AeadInPlace + NewAead,
+    M: Clone + Mac + KeyInit,
+    A::KeySize: IsLessOrEqual<M::OutputSize>,
+    LeEq<A::KeySize, M::OutputSize>: NonZero,
+{
+    /// Derives the encryption key `L ← H(K, (N, A))`
+    pub(crate) fn derive_key(&self, nonce: &Nonce<Self>, associated_data: &[u8]) -> Key<A> {
+        let digest = {
+            let mut mac = self.mac.clone();
+            mac.update(nonce);
+            mac.update(associated_data);
+            mac.finalize().into_bytes()
+        };
+
+        // Truncate the MAC to get the encryption key. This cannot fail because we require
+        // A::KeySize ≤ M::OutputSize.
+        Key::<A>::clone_from_slice(&digest[..A::KeySize::USIZE])
+    }
+}
+
 impl<A, M> AeadInPlace for MacHte<A, M>
 where
     A: AeadInPlace + NewAead,
-    M: Mac + KeyInit,
+    M: Clone + Mac + KeyInit,
     A::KeySize: IsLessOrEqual<M::OutputSize>,
     LeEq<A::KeySize, M::OutputSize>: NonZero,
 {
@@ -116,20 +144,10 @@ where
         buffer: &mut [u8],
     ) -> Result<Tag<Self>, Error> {
         // Derive the encryption key L
-        let digest = {
-            let mut mac =
-                <M as KeyInit>::new_from_slice(&self.mac_key).expect("invalid MAC key length");
-            mac.update(nonce);
-            mac.update(associated_data);
-            mac.finalize().into_bytes()
-        };
-
-        // Truncate the MAC to get the encryption key. This cannot fail because we require
-        // A::KeySize ≤ M::OutputSize.
-        let enc_key = Key::<A>::from_slice(&digest[..A::KeySize::USIZE]);
+        let enc_key = self.derive_key(nonce, associated_data);
 
         // Now use the key to encrypt the message. The associated data is excluded
-        let ciph = A::new(enc_key);
+        let ciph = A::new(&enc_key);
         ciph.encrypt_in_place_detached(nonce, &[], buffer)
     }
 
@@ -146,17 +164,7 @@ where
         tag: &Tag<Self>,
     ) -> Result<(), Error> {
         // Derive the encryption key L
-        let digest = {
-            let mut mac =
-                <M as KeyInit>::new_from_slice(&self.mac_key).expect("invalid MAC key length");
-            mac.update(nonce);
-            mac.update(associated_data);
-            mac.finalize().into_bytes()
-        };
-
-        // Truncate the MAC to get the encryption key. This cannot fail because we require
-        // A::KeySize ≤ M::OutputSize.
-        let enc_key = Key::<A>::from_slice(&digest[..A::KeySize::USIZE]);
+        let enc_key = self.derive_key(nonce, associated_data);
 
         // Now use the key to decrypt the message. The associated data is excluded
         let ciph = A::new(&enc_key);
@@ -164,11 +172,76 @@ where
     }
 }
 
+// If the underlying AEAD has a detachable commitment, then so does MacHte. The commitment is
+// computed by the underlying AEAD under the derived key L.
+impl<A, M> DetachedCommitment for MacHte<A, M>
+where
+    A: AeadInPlace + DetachedCommitment + NewAead,
+    M: Clone + Mac + KeyInit,
+    A::KeySize: IsLessOrEqual<M::OutputSize>,
+    LeEq<A::KeySize, M::OutputSize>: NonZero,
+{
+    type InnerTagSize = A::InnerTagSize;
+    type ComSize = A::ComSize;
+
+    fn encrypt_in_place_detached_with_commitment(
+        &self,
+        nonce: &Nonce<Self>,
+        associated_data: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<(InnerTag<Self>, Commitment<Self>), Error> {
+        let enc_key = self.derive_key(nonce, associated_data);
+        let ciph = A::new(&enc_key);
+        ciph.encrypt_in_place_detached_with_commitment(nonce, &[], buffer)
+    }
+
+    fn decrypt_in_place_detached_with_commitment(
+        &self,
+        nonce: &Nonce<Self>,
+        associated_data: &[u8],
+        buffer: &mut [u8],
+        tag: &InnerTag<Self>,
+        com: &Commitment<Self>,
+    ) -> Result<(), Error> {
+        let enc_key = self.derive_key(nonce, associated_data);
+        let ciph = A::new(&enc_key);
+        ciph.decrypt_in_place_detached_with_commitment(nonce, &[], buffer, tag, com)
+    }
+
+    fn commitment(&self, nonce: &Nonce<Self>, associated_data: &[u8]) -> Commitment<Self> {
+        let enc_key = self.derive_key(nonce, associated_data);
+        let ciph = A::new(&enc_key);
+        ciph.commitment(nonce, &[])
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::util::test_aead_correctness;
+    use crate::util::{test_aead_correctness, test_detached_commitment};
 
     test_aead_correctness!(MacHteUtcAes128Gcm, machte_utc_aes128_correctness);
     test_aead_correctness!(MacHteUtcAes256Gcm, machte_utc_aes256_correctness);
+
+    test_detached_commitment!(MacHteUtcAes128Gcm, machte_utc_aes128_detached_commitment);
+    test_detached_commitment!(MacHteUtcAes256Gcm, machte_utc_aes256_detached_commitment);
+
+    // Zeroizing replaces the keyed MAC, so afterwards the AEAD acts as if it were keyed by zeros
+    #[test]
+    fn machte_zeroize() {
+        let mut ciph =
+            MacHteUtcAes256Gcm::new(&MacHteUtcAes256Gcm::generate_key(&mut rand::thread_rng()));
+        ciph.zeroize();
+        let zero_ciph = MacHteUtcAes256Gcm::new(&Key::<MacHteUtcAes256Gcm>::default());
+
+        let nonce = Nonce::<MacHteUtcAes256Gcm>::default();
+        let (mut buf1, mut buf2) = (*b"hello world", *b"hello world");
+        let tag1 = ciph
+            .encrypt_in_place_detached(&nonce, b"aad", &mut buf1)
+            .unwrap();
+        let tag2 = zero_ciph
+            .encrypt_in_place_detached(&nonce, b"aad", &mut buf2)
+            .unwrap();
+        assert_eq!((buf1, tag1), (buf2, tag2));
+    }
 }
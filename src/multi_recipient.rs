@@ -0,0 +1,265 @@
+//! Defines multi-recipient encryption, where one payload is encrypted to many recipients, each with
+//! their own symmetric key. A random file key is wrapped once per recipient with [`UtcAes256Gcm`],
+//! and the payload is encrypted under a key derived from the file key with
+//! [`HkdfHteUtcAes256Gcm`].
+//!
+//! The ciphertext format is
+//!
+//! ```text
+//! header = version (= 1) || be16(n) || stanza_1 || ... || stanza_n
+//! ciphertext = header || mac || C || tag
+//! ```
+//!
+//! where `stanza_i = N_i || UtcAes256Gcm.Enc(K_i, N_i, STANZA_AAD, file_key)` for recipient key
+//! `K_i` and random nonce `N_i`, `mac = HMAC-SHA256(HKDF-SHA256(file_key, "header"), header)`, and
+//! `C || tag` is the payload encrypted under `HKDF-SHA256(file_key, "payload")` with associated
+//! data `header || aad`. Every file key encrypts exactly one payload, so its nonce is fixed to zero.
+//!
+//! A recipient takes the file key from the first stanza that their key opens, and then checks the
+//! MAC. A malicious sender could wrap a different file key for each recipient, but since HMAC is
+//! collision-resistant, at most one file key verifies the MAC over a given header. So every
+//! recipient who accepts the header has the same file key, and since the payload is committing,
+//! they all see the same plaintext.
+
+use crate::{seal::SealExt, HkdfHteUtcAes256Gcm, UtcAes256Gcm};
+
+use aead::{AeadCore, AeadInPlace, Error, Key, NewAead, Nonce, Tag};
+use cipher::typenum::Unsigned;
+use digest::{KeyInit, Mac};
+use hkdf::{hmac::SimpleHmac, SimpleHkdf};
+use rand_core::{CryptoRng, RngCore};
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+/// The only version of the format
+const VERSION: u8 = 1;
+
+/// The associated data of every stanza, which separates wrapped file keys from other uses of a
+/// recipient key
+const STANZA_AAD: &[u8] = b"kc-aeads multi-recipient stanza";
+
+/// The size of a file key
+const FILE_KEY_LEN: usize = 32;
+
+/// The size of the version and recipient count at the start of the header
+const HEADER_PREFIX_LEN: usize = 3;
+
+/// The size of the header MAC
+const MAC_LEN: usize = 32;
+
+type HeaderMac = SimpleHmac<Sha256>;
+
+/// The size of a stanza, i.e., a nonce, a wrapped file key, and a tag
+fn stanza_len() -> usize {
+    <UtcAes256Gcm as AeadCore>::NonceSize::USIZE
+        + FILE_KEY_LEN
+        + <UtcAes256Gcm as AeadCore>::TagSize::USIZE
+}
+
+// Derives a subkey of the file key for the given purpose
+fn derive(file_key: &[u8], info: &[u8]) -> Zeroizing<[u8; 32]> {
+    let mut out = Zeroizing::new([0u8; 32]);
+    SimpleHkdf::<Sha256>::new(None, file_key)
+        .expand(info, out.as_mut())
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    out
+}
+
+// Computes the MAC over the header
+fn header_mac(file_key: &[u8], header: &[u8]) -> HeaderMac {
+    let mac_key = derive(file_key, b"header");
+    let mut mac = <HeaderMac as KeyInit>::new_from_slice(mac_key.as_ref())
+        .expect("HMAC takes keys of any length");
+    mac.update(header);
+    mac
+}
+
+// Wraps the file key for one recipient
+fn wrap_file_key<R>(
+    rng: &mut R,
+    recipient: &Key<UtcAes256Gcm>,
+    file_key: &[u8],
+) -> Result<Vec<u8>, Error>
+where
+    R: CryptoRng + RngCore,
+{
+    UtcAes256Gcm::new(recipient).seal(rng, STANZA_AAD, file_key)
+}
+
+// Appends the header MAC and the encrypted payload to the header
+fn finish(
+    file_key: &[u8],
+    mut header: Vec<u8>,
+    associated_data: &[u8],
+    msg: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let mac = header_mac(file_key, &header).finalize().into_bytes();
+    let aad = [&header, associated_data].concat();
+    header.extend_from_slice(&mac);
+
+    let start = header.len();
+    header.extend_from_slice(msg);
+    let payload_key = derive(file_key, b"payload");
+    let tag =
+        HkdfHteUtcAes256Gcm::new(Key::<HkdfHteUtcAes256Gcm>::from_slice(payload_key.as_ref()))
+            .encrypt_in_place_detached(
+                &Nonce::<HkdfHteUtcAes256Gcm>::default(),
+                &aad,
+                &mut header[start..],
+            )?;
+    header.extend_from_slice(&tag);
+
+    Ok(header)
+}
+
+/// Encrypts `msg` to every recipient in `recipients`. Returns an error if there are no recipients
+/// or more than 65535.
+pub fn encrypt<R>(
+    rng: &mut R,
+    recipients: &[Key<UtcAes256Gcm>],
+    associated_data: &[u8],
+    msg: &[u8],
+) -> Result<Vec<u8>, Error>
+where
+    R: CryptoRng + RngCore,
+{
+    if recipients.is_empty() {
+        return Err(Error);
+    }
+    let num_recipients = u16::try_from(recipients.len()).map_err(|_| Error)?;
+
+    let mut file_key = Zeroizing::new([0u8; FILE_KEY_LEN]);
+    rng.fill_bytes(file_key.as_mut());
+
+    let mut header = Vec::with_capacity(HEADER_PREFIX_LEN + recipients.len() * stanza_len());
+    header.push(VERSION);
+    header.extend_from_slice(&num_recipients.to_be_bytes());
+    for recipient in recipients {
+        header.extend_from_slice(&wrap_file_key(rng, recipient, file_key.as_ref())?);
+    }
+
+    finish(file_key.as_ref(), header, associated_data, msg)
+}
+
+/// Decrypts a ciphertext made by [`encrypt`] with one recipient's key. Returns an error if the
+/// ciphertext is malformed, no stanza is for this key, the header MAC doesn't verify, or the
+/// payload fails to decrypt.
+pub fn decrypt(
+    recipient: &Key<UtcAes256Gcm>,
+    associated_data: &[u8],
+    ciphertext: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let tag_len = <HkdfHteUtcAes256Gcm as AeadCore>::TagSize::USIZE;
+
+    // Parse the header
+    if ciphertext.len() < HEADER_PREFIX_LEN || ciphertext[0] != VERSION {
+        return Err(Error);
+    }
+    let num_recipients = u16::from_be_bytes([ciphertext[1], ciphertext[2]]) as usize;
+    let header_len = HEADER_PREFIX_LEN + num_recipients * stanza_len();
+    if num_recipients == 0 || ciphertext.len() < header_len + MAC_LEN + tag_len {
+        return Err(Error);
+    }
+    let (header, rest) = ciphertext.split_at(header_len);
+    let (mac, rest) = rest.split_at(MAC_LEN);
+    let (payload, tag) = rest.split_at(rest.len() - tag_len);
+
+    // Take the file key from the first stanza that opens
+    let ciph = UtcAes256Gcm::new(recipient);
+    let file_key = header[HEADER_PREFIX_LEN..]
+        .chunks(stanza_len())
+        .find_map(|stanza| ciph.open(STANZA_AAD, stanza).ok())
+        .map(Zeroizing::new)
+        .ok_or(Error)?;
+
+    // Check the header MAC, then decrypt the payload
+    header_mac(&file_key, header)
+        .verify_slice(mac)
+        .map_err(|_| Error)?;
+
+    let payload_key = derive(&file_key, b"payload");
+    let aad = [header, associated_data].concat();
+    let mut buf = payload.to_vec();
+    HkdfHteUtcAes256Gcm::new(Key::<HkdfHteUtcAes256Gcm>::from_slice(payload_key.as_ref()))
+        .decrypt_in_place_detached(
+            &Nonce::<HkdfHteUtcAes256Gcm>::default(),
+            &aad,
+            &mut buf,
+            Tag::<HkdfHteUtcAes256Gcm>::from_slice(tag),
+        )?;
+
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn multi_recipient_correctness() {
+        let mut rng = rand::thread_rng();
+        let recipients: Vec<_> = (0..3)
+            .map(|_| UtcAes256Gcm::generate_key(&mut rng))
+            .collect();
+        let outsider = UtcAes256Gcm::generate_key(&mut rng);
+
+        let ct = encrypt(&mut rng, &recipients, b"aad", b"hello world").unwrap();
+        assert_eq!(
+            ct.len(),
+            HEADER_PREFIX_LEN
+                + 3 * stanza_len()
+                + MAC_LEN
+                + 11
+                + <HkdfHteUtcAes256Gcm as AeadCore>::TagSize::USIZE
+        );
+
+        // Every recipient decrypts, and nobody else does
+        for key in &recipients {
+            assert_eq!(decrypt(key, b"aad", &ct).unwrap(), b"hello world");
+            assert!(decrypt(key, b"bad", &ct).is_err());
+        }
+        assert!(decrypt(&outsider, b"aad", &ct).is_err());
+
+        // Modifying any byte fails for every recipient. A modified stanza only fails for its own
+        // recipient at unwrapping, but fails for everyone at the header MAC.
+        for i in 0..ct.len() {
+            let mut bad = ct.clone();
+            bad[i] ^= 1;
+            for key in &recipients {
+                assert!(decrypt(key, b"aad", &bad).is_err());
+            }
+        }
+
+        // As does truncating
+        for len in 0..ct.len() {
+            assert!(decrypt(&recipients[0], b"aad", &ct[..len]).is_err());
+        }
+
+        // There must be at least one recipient
+        assert!(encrypt(&mut rng, &[], b"", b"").is_err());
+    }
+
+    // A malicious sender wraps a different file key for each of two recipients. Whichever file key
+    // the MAC and payload are made under, the other recipient rejects the ciphertext, so the two
+    // can't be shown different plaintexts.
+    #[test]
+    fn multi_recipient_split_file_keys() {
+        let mut rng = rand::thread_rng();
+        let alice = UtcAes256Gcm::generate_key(&mut rng);
+        let bob = UtcAes256Gcm::generate_key(&mut rng);
+        let alice_fk = [1u8; FILE_KEY_LEN];
+        let bob_fk = [2u8; FILE_KEY_LEN];
+
+        let mut header = vec![VERSION, 0, 2];
+        header.extend_from_slice(&wrap_file_key(&mut rng, &alice, &alice_fk).unwrap());
+        header.extend_from_slice(&wrap_file_key(&mut rng, &bob, &bob_fk).unwrap());
+
+        let ct = finish(&alice_fk, header.clone(), b"", b"for alice").unwrap();
+        assert_eq!(decrypt(&alice, b"", &ct).unwrap(), b"for alice");
+        assert!(decrypt(&bob, b"", &ct).is_err());
+
+        let ct = finish(&bob_fk, header, b"", b"for bob").unwrap();
+        assert!(decrypt(&alice, b"", &ct).is_err());
+        assert_eq!(decrypt(&bob, b"", &ct).unwrap(), b"for bob");
+    }
+}
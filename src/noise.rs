@@ -0,0 +1,237 @@
+//! Defines a [Noise](https://noiseprotocol.org/noise.html) `CipherState` over committing AEADs.
+//!
+//! Noise names its cipher functions in protocol names, e.g., `Noise_XX_25519_AESGCM_SHA256`. The
+//! cipher functions here are not the standard `AESGCM` and `ChaChaPoly`, and must not use their
+//! names, since their tags are longer and their ciphertexts don't interoperate. We use
+//!
+//! * `UtcAESGCM` for [`UtcAes256Gcm`], with nonces encoded like `AESGCM`, and
+//! * `UtcChaChaPoly` for [`UtcChaCha20Poly1305`], with nonces encoded like `ChaChaPoly`.
+//!
+//! Noise §5.1 assumes that `ENCRYPT` adds 16 bytes, so the maximum plaintext per message is
+//! smaller by the commitment size than it would be with the standard cipher functions.
+
+use crate::{UtcAes256Gcm, UtcChaCha20Poly1305};
+
+use aead::{AeadCore, AeadInPlace, Error, Key, NewAead, Nonce};
+use cipher::typenum::Unsigned;
+use zeroize::Zeroizing;
+
+/// The nonce value that `REKEY` encrypts under, and which is otherwise never used
+const REKEY_NONCE: u64 = u64::MAX;
+
+/// A Noise cipher function, i.e., an AEAD with a 32-byte key and a way to encode Noise's 64-bit
+/// counter into its nonce
+pub trait NoiseCipher: AeadInPlace + NewAead {
+    /// The name of the cipher function in Noise protocol names
+    const NAME: &'static str;
+
+    /// Encodes the 64-bit counter `n` as a nonce
+    fn encode_nonce(n: u64) -> Nonce<Self>;
+}
+
+/// Like `AESGCM`, the nonce is 32 bits of zeros followed by the big-endian encoding of `n`
+impl NoiseCipher for UtcAes256Gcm {
+    const NAME: &'static str = "UtcAESGCM";
+
+    fn encode_nonce(n: u64) -> Nonce<Self> {
+        let mut nonce = Nonce::<Self>::default();
+        nonce[4..].copy_from_slice(&n.to_be_bytes());
+        nonce
+    }
+}
+
+/// Like `ChaChaPoly`, the nonce is 32 bits of zeros followed by the little-endian encoding of `n`
+impl NoiseCipher for UtcChaCha20Poly1305 {
+    const NAME: &'static str = "UtcChaChaPoly";
+
+    fn encode_nonce(n: u64) -> Nonce<Self> {
+        let mut nonce = Nonce::<Self>::default();
+        nonce[4..].copy_from_slice(&n.to_le_bytes());
+        nonce
+    }
+}
+
+/// A Noise `CipherState`, i.e., an optional key and a 64-bit counter nonce. This follows §5.1 of
+/// the Noise spec.
+pub struct CipherState<C: NoiseCipher> {
+    ciph: Option<C>,
+    n: u64,
+}
+
+impl<C: NoiseCipher> Default for CipherState<C> {
+    fn default() -> Self {
+        CipherState { ciph: None, n: 0 }
+    }
+}
+
+impl<C: NoiseCipher> CipherState<C> {
+    /// Makes a `CipherState` with no key
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `InitializeKey(key)`: Sets the key, and resets the nonce to 0
+    pub fn initialize_key(&mut self, key: &Key<C>) {
+        self.ciph = Some(C::new(key));
+        self.n = 0;
+    }
+
+    /// `HasKey()`: Returns whether a key is set
+    pub fn has_key(&self) -> bool {
+        self.ciph.is_some()
+    }
+
+    /// `SetNonce(nonce)`: Sets the nonce. This is used by protocols which handle out-of-order
+    /// transport messages.
+    pub fn set_nonce(&mut self, n: u64) {
+        self.n = n;
+    }
+
+    /// Returns the nonce that the next encryption or decryption will use
+    pub fn nonce(&self) -> u64 {
+        self.n
+    }
+
+    /// `EncryptWithAd(ad, plaintext)`: Encrypts `plaintext` under the next nonce, and returns the
+    /// ciphertext with the tag appended. If no key is set, returns `plaintext`. Returns an error
+    /// once the nonce reaches 2⁶⁴ - 1, which is reserved for `rekey`.
+    pub fn encrypt_with_ad(&mut self, ad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        let ciph = match &self.ciph {
+            Some(ciph) => ciph,
+            None => return Ok(plaintext.to_vec()),
+        };
+        if self.n == REKEY_NONCE {
+            return Err(Error);
+        }
+
+        let mut buf = plaintext.to_vec();
+        let tag = ciph.encrypt_in_place_detached(&C::encode_nonce(self.n), ad, &mut buf)?;
+        buf.extend_from_slice(&tag);
+        self.n += 1;
+
+        Ok(buf)
+    }
+
+    /// `DecryptWithAd(ad, ciphertext)`: Decrypts `ciphertext` under the next nonce. If no key is
+    /// set, returns `ciphertext`. On failure, returns an error and doesn't advance the nonce.
+    pub fn decrypt_with_ad(&mut self, ad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+        let ciph = match &self.ciph {
+            Some(ciph) => ciph,
+            None => return Ok(ciphertext.to_vec()),
+        };
+        if self.n == REKEY_NONCE {
+            return Err(Error);
+        }
+
+        let ct_len = ciphertext
+            .len()
+            .checked_sub(<C as AeadCore>::TagSize::USIZE)
+            .ok_or(Error)?;
+        let (ct, tag) = ciphertext.split_at(ct_len);
+        let mut buf = ct.to_vec();
+        ciph.decrypt_in_place_detached(
+            &C::encode_nonce(self.n),
+            ad,
+            &mut buf,
+            aead::Tag::<C>::from_slice(tag),
+        )?;
+        self.n += 1;
+
+        Ok(buf)
+    }
+
+    /// `Rekey()`: Replaces the key with the first 32 bytes of the encryption of 32 zero bytes under
+    /// the nonce 2⁶⁴ - 1, as in §4.2. This doesn't change the nonce. Does nothing if no key is set.
+    pub fn rekey(&mut self) {
+        let ciph = match &self.ciph {
+            Some(ciph) => ciph,
+            None => return,
+        };
+
+        let key_len = <C as NewAead>::KeySize::USIZE;
+        let mut new_key = Zeroizing::new(vec![0u8; key_len]);
+        // Encryption only fails on overlong inputs, and this input is 32 bytes
+        ciph.encrypt_in_place_detached(&C::encode_nonce(REKEY_NONCE), b"", &mut new_key)
+            .expect("rekey encryption failed");
+        self.ciph = Some(C::new(Key::<C>::from_slice(&new_key)));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    macro_rules! test_cipher_state {
+        ($cipher:ty, $test_name:ident) => {
+            #[test]
+            fn $test_name() {
+                let mut rng = rand::thread_rng();
+                let key = <$cipher>::generate_key(&mut rng);
+
+                // With no key, messages pass through unchanged
+                let mut sender = CipherState::<$cipher>::new();
+                assert!(!sender.has_key());
+                assert_eq!(sender.encrypt_with_ad(b"ad", b"hello").unwrap(), b"hello");
+                assert_eq!(sender.decrypt_with_ad(b"ad", b"hello").unwrap(), b"hello");
+
+                sender.initialize_key(&key);
+                let mut receiver = CipherState::<$cipher>::new();
+                receiver.initialize_key(&key);
+
+                // Messages round-trip, and every message uses the next nonce
+                let cts: Vec<_> = (0..3)
+                    .map(|_| sender.encrypt_with_ad(b"ad", b"hello world").unwrap())
+                    .collect();
+                assert_eq!(sender.nonce(), 3);
+                assert_ne!(cts[0], cts[1]);
+
+                // A failed decryption doesn't advance the nonce
+                assert!(receiver.decrypt_with_ad(b"ad", &cts[1]).is_err());
+                assert!(receiver.decrypt_with_ad(b"bad", &cts[0]).is_err());
+                assert!(receiver.decrypt_with_ad(b"ad", &cts[0][..5]).is_err());
+                assert_eq!(receiver.nonce(), 0);
+                for ct in &cts {
+                    assert_eq!(receiver.decrypt_with_ad(b"ad", ct).unwrap(), b"hello world");
+                }
+
+                // After rekeying both sides, they stay in sync, the nonce is unchanged, and the
+                // old key no longer works
+                let mut stale = CipherState::<$cipher>::new();
+                stale.initialize_key(&key);
+                stale.set_nonce(sender.nonce());
+                sender.rekey();
+                receiver.rekey();
+                assert_eq!(sender.nonce(), 3);
+                let ct = sender.encrypt_with_ad(b"", b"after").unwrap();
+                assert!(stale.decrypt_with_ad(b"", &ct).is_err());
+                assert_eq!(receiver.decrypt_with_ad(b"", &ct).unwrap(), b"after");
+
+                // The last nonce is reserved for rekey
+                sender.set_nonce(u64::MAX - 1);
+                receiver.set_nonce(u64::MAX - 1);
+                let ct = sender.encrypt_with_ad(b"", b"last").unwrap();
+                assert_eq!(receiver.decrypt_with_ad(b"", &ct).unwrap(), b"last");
+                assert!(sender.encrypt_with_ad(b"", b"too far").is_err());
+                assert!(receiver.decrypt_with_ad(b"", &ct).is_err());
+            }
+        };
+    }
+
+    test_cipher_state!(UtcAes256Gcm, noise_utc_aes256gcm);
+    test_cipher_state!(UtcChaCha20Poly1305, noise_utc_chacha20poly1305);
+
+    // The 8-byte counter goes in the last 8 bytes of the 12-byte nonce, in the same byte order as
+    // the standard cipher function
+    #[test]
+    fn noise_nonce_encoding() {
+        let n = 0x0102030405060708;
+        assert_eq!(
+            UtcAes256Gcm::encode_nonce(n).as_slice(),
+            [0, 0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8]
+        );
+        assert_eq!(
+            UtcChaCha20Poly1305::encode_nonce(n).as_slice(),
+            [0, 0, 0, 0, 8, 7, 6, 5, 4, 3, 2, 1]
+        );
+    }
+}
@@ -0,0 +1,364 @@
+//! Defines password-based encryption. The key is derived from the password with Argon2id, and the
+//! payload is encrypted with [`MacHteUtcAes256Gcm`], which is key-committing.
+//!
+//! Commitment is what stops [partitioning oracle attacks](https://eprint.iacr.org/2020/1491). With
+//! a non-committing AEAD like AES-GCM, an attacker can build one ciphertext which decrypts under
+//! thousands of candidate passwords at once, and then learn which set the victim's password is in
+//! from whether decryption succeeds. A committing AEAD's ciphertext decrypts under at most one key,
+//! so each query tests at most one password.
+//!
+//! The ciphertext format is
+//!
+//! ```text
+//! header = version (= 1) || be32(m_cost) || be32(t_cost) || be32(p_cost) || salt
+//! ciphertext = header || C || tag
+//! ```
+//!
+//! where `salt` is 16 random bytes, and `C || tag` is the payload encrypted under
+//! `Argon2id(password, salt)` with associated data `header || aad`. The salt is fresh for every
+//! encryption, so every key encrypts exactly one payload, and the nonce is fixed to zero.
+
+use crate::MacHteUtcAes256Gcm;
+
+use aead::{AeadInPlace, Error, Key, NewAead, Nonce, Tag};
+use argon2::{Algorithm, Argon2, Params, Version};
+use cipher::typenum::Unsigned;
+use rand_core::{CryptoRng, RngCore};
+use zeroize::Zeroizing;
+
+/// The only version of the format
+const VERSION: u8 = 1;
+
+/// The size of the random salt
+const SALT_LEN: usize = 16;
+
+/// The size of the header. This is the version, the three cost parameters, and the salt.
+pub const HEADER_LEN: usize = 1 + 3 * 4 + SALT_LEN;
+
+/// The Argon2id cost parameters of a ciphertext
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PasswordParams {
+    /// Memory size in KiB
+    pub m_cost: u32,
+    /// Number of passes
+    pub t_cost: u32,
+    /// Degree of parallelism
+    pub p_cost: u32,
+}
+
+impl PasswordParams {
+    /// The largest parameters [`decrypt`] accepts, 1 GiB of memory, 16 passes, and 16 lanes. A
+    /// ciphertext's header is read before it is authenticated, so without a limit, anyone who can
+    /// submit ciphertexts could make the decryptor allocate arbitrary amounts of memory.
+    pub const MAX: PasswordParams = PasswordParams {
+        m_cost: 1 << 20,
+        t_cost: 16,
+        p_cost: 16,
+    };
+
+    fn exceeds(&self, max: &PasswordParams) -> bool {
+        self.m_cost > max.m_cost || self.t_cost > max.t_cost || self.p_cost > max.p_cost
+    }
+}
+
+/// The defaults are the ones recommended by OWASP, which are also the defaults of the `argon2`
+/// crate: 19 MiB of memory, 2 passes, and 1 lane
+impl Default for PasswordParams {
+    fn default() -> Self {
+        PasswordParams {
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+// Derives the payload key from the password. Returns an error if the parameters are invalid.
+fn derive_key(
+    params: &PasswordParams,
+    password: &[u8],
+    salt: &[u8],
+) -> Result<Zeroizing<[u8; 32]>, Error> {
+    let argon_params =
+        Params::new(params.m_cost, params.t_cost, params.p_cost, Some(32)).map_err(|_| Error)?;
+    let mut key = Zeroizing::new([0u8; 32]);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, argon_params)
+        .hash_password_into(password, salt, key.as_mut())
+        .map_err(|_| Error)?;
+    Ok(key)
+}
+
+/// Encrypts `msg` under a key derived from `password` with the given parameters and a fresh random
+/// salt. Returns an error if the parameters are invalid.
+pub fn encrypt<R>(
+    rng: &mut R,
+    params: &PasswordParams,
+    password: &[u8],
+    associated_data: &[u8],
+    msg: &[u8],
+) -> Result<Vec<u8>, Error>
+where
+    R: CryptoRng + RngCore,
+{
+    let mut salt = [0u8; SALT_LEN];
+    rng.fill_bytes(&mut salt);
+    let key = derive_key(params, password, &salt)?;
+
+    let mut out = Vec::with_capacity(
+        HEADER_LEN + msg.len() + <MacHteUtcAes256Gcm as aead::AeadCore>::TagSize::USIZE,
+    );
+    out.push(VERSION);
+    out.extend_from_slice(&params.m_cost.to_be_bytes());
+    out.extend_from_slice(&params.t_cost.to_be_bytes());
+    out.extend_from_slice(&params.p_cost.to_be_bytes());
+    out.extend_from_slice(&salt);
+    out.extend_from_slice(msg);
+
+    // The AAD is header || aad
+    let (header, payload) = out.split_at_mut(HEADER_LEN);
+    let aad = [&*header, associated_data].concat();
+    let tag = MacHteUtcAes256Gcm::new(Key::<MacHteUtcAes256Gcm>::from_slice(key.as_ref()))
+        .encrypt_in_place_detached(&Nonce::<MacHteUtcAes256Gcm>::default(), &aad, payload)?;
+    out.extend_from_slice(&tag);
+
+    Ok(out)
+}
+
+/// Returns the Argon2id parameters in a ciphertext's header. These are unauthenticated until the
+/// ciphertext is decrypted.
+pub fn params(ciphertext: &[u8]) -> Result<PasswordParams, Error> {
+    if ciphertext.len() < HEADER_LEN || ciphertext[0] != VERSION {
+        return Err(Error);
+    }
+
+    let be32 = |i: usize| u32::from_be_bytes(ciphertext[i..i + 4].try_into().unwrap());
+    Ok(PasswordParams {
+        m_cost: be32(1),
+        t_cost: be32(5),
+        p_cost: be32(9),
+    })
+}
+
+/// Decrypts a ciphertext made by [`encrypt`] with the given password. Returns an error if the
+/// ciphertext is malformed, its parameters exceed [`PasswordParams::MAX`], or decryption fails.
+pub fn decrypt(
+    password: &[u8],
+    associated_data: &[u8],
+    ciphertext: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let tag_len = <MacHteUtcAes256Gcm as aead::AeadCore>::TagSize::USIZE;
+
+    let params = params(ciphertext)?;
+    if params.exceeds(&PasswordParams::MAX) || ciphertext.len() < HEADER_LEN + tag_len {
+        return Err(Error);
+    }
+    let (header, rest) = ciphertext.split_at(HEADER_LEN);
+    let (payload, tag) = rest.split_at(rest.len() - tag_len);
+
+    let key = derive_key(&params, password, &header[HEADER_LEN - SALT_LEN..])?;
+    let aad = [header, associated_data].concat();
+    let mut buf = payload.to_vec();
+    MacHteUtcAes256Gcm::new(Key::<MacHteUtcAes256Gcm>::from_slice(key.as_ref()))
+        .decrypt_in_place_detached(
+            &Nonce::<MacHteUtcAes256Gcm>::default(),
+            &aad,
+            &mut buf,
+            Tag::<MacHteUtcAes256Gcm>::from_slice(tag),
+        )?;
+
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Cheap parameters so the tests run quickly
+    const TEST_PARAMS: PasswordParams = PasswordParams {
+        m_cost: 8,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    #[test]
+    fn password_correctness() {
+        let mut rng = rand::thread_rng();
+
+        let ct = encrypt(&mut rng, &TEST_PARAMS, b"hunter2", b"aad", b"hello world").unwrap();
+        assert_eq!(params(&ct).unwrap(), TEST_PARAMS);
+        assert_eq!(decrypt(b"hunter2", b"aad", &ct).unwrap(), b"hello world");
+
+        // The password and AAD must match
+        assert!(decrypt(b"hunter3", b"aad", &ct).is_err());
+        assert!(decrypt(b"hunter2", b"bad", &ct).is_err());
+
+        // Modifying any byte fails, including the parameters and salt in the header
+        for i in 0..ct.len() {
+            let mut bad = ct.clone();
+            bad[i] ^= 1;
+            assert!(decrypt(b"hunter2", b"aad", &bad).is_err());
+        }
+
+        // As does truncating
+        for len in 0..ct.len() {
+            assert!(decrypt(b"hunter2", b"aad", &ct[..len]).is_err());
+        }
+    }
+
+    // Parameters above the maximum are rejected before doing any work
+    #[test]
+    fn password_max_params() {
+        let mut rng = rand::thread_rng();
+        let ct = encrypt(&mut rng, &TEST_PARAMS, b"pw", b"", b"").unwrap();
+
+        let mut bad = ct.clone();
+        bad[1..5].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(params(&bad).unwrap().exceeds(&PasswordParams::MAX));
+        assert!(decrypt(b"pw", b"", &bad).is_err());
+
+        // Invalid parameters are rejected on encryption
+        let invalid = PasswordParams {
+            t_cost: 0,
+            ..TEST_PARAMS
+        };
+        assert!(encrypt(&mut rng, &invalid, b"pw", b"", b"").is_err());
+    }
+
+    // Multiplies two elements of GF(2^128) in GCM's bit order, per NIST SP 800-38D Algorithm 1
+    fn gf_mul(x: u128, y: u128) -> u128 {
+        let mut z = 0;
+        let mut v = y;
+        for i in 0..128 {
+            if (x >> (127 - i)) & 1 == 1 {
+                z ^= v;
+            }
+            v = if v & 1 == 1 {
+                (v >> 1) ^ (0xE1 << 120)
+            } else {
+                v >> 1
+            };
+        }
+        z
+    }
+
+    // Returns x^(2^128 - 2) = x^-1
+    fn gf_inv(x: u128) -> u128 {
+        let mut acc = 1 << 127;
+        let mut sq = x;
+        for _ in 1..128 {
+            sq = gf_mul(sq, sq);
+            acc = gf_mul(acc, sq);
+        }
+        acc
+    }
+
+    // Returns GHASH_h(aad, ct)
+    fn ghash(h: u128, aad: &[u8], ct: &[u8]) -> u128 {
+        // Each input is zero-padded to a multiple of 16 bytes, then followed by a length block
+        let block = |b: &[u8]| {
+            let mut padded = [0u8; 16];
+            padded[..b.len()].copy_from_slice(b);
+            u128::from_be_bytes(padded)
+        };
+        let lens = ((aad.len() as u128 * 8) << 64) | (ct.len() as u128 * 8);
+        aad.chunks(16)
+            .chain(ct.chunks(16))
+            .map(block)
+            .chain([lens])
+            .fold(0, |x, b| gf_mul(x ^ b, h))
+    }
+
+    // Returns (H, E_K(J0)) for AES-256-GCM with a 96-bit nonce
+    fn gcm_keys(key: &[u8], nonce: &[u8]) -> (u128, u128) {
+        use aes::{
+            cipher::{BlockEncrypt, KeyInit},
+            Aes256,
+        };
+
+        let aes = <Aes256 as KeyInit>::new_from_slice(key).unwrap();
+        let mut h = aes::Block::default();
+        aes.encrypt_block(&mut h);
+        let mut j0 = aes::Block::default();
+        j0[..12].copy_from_slice(nonce);
+        j0[15] = 1;
+        aes.encrypt_block(&mut j0);
+        (
+            u128::from_be_bytes(h.into()),
+            u128::from_be_bytes(j0.into()),
+        )
+    }
+
+    // Finds a 16-byte ciphertext and a tag which are valid AES-256-GCM encryptions under both k1
+    // and k2, with a zero nonce and the given associated data. The tag under key i is
+    // E_i(J0) + GHASH_Hi(A, C), and GHASH is linear in C, so this solves for C.
+    fn gcm_collision(k1: &[u8], k2: &[u8], aad: &[u8]) -> ([u8; 16], [u8; 16]) {
+        let zero = [0u8; 16];
+        let (h1, e1) = gcm_keys(k1, &zero[..12]);
+        let (h2, e2) = gcm_keys(k2, &zero[..12]);
+
+        // GHASH_H(A, C) = GHASH_H(A, 0) + C·H^2, since C is followed by just the length block
+        let lhs = gf_mul(h1, h1) ^ gf_mul(h2, h2);
+        let rhs = e1 ^ e2 ^ ghash(h1, aad, &zero) ^ ghash(h2, aad, &zero);
+        let ct = gf_mul(rhs, gf_inv(lhs)).to_be_bytes();
+
+        let tag = e1 ^ ghash(h1, aad, &ct);
+        assert_eq!(tag, e2 ^ ghash(h2, aad, &ct));
+        (ct, tag.to_be_bytes())
+    }
+
+    // A partitioning oracle attack needs a ciphertext that decrypts under many passwords. Build
+    // one for two passwords against plain AES-256-GCM, and check that it works there. Then build
+    // one whose inner GCM tag is valid under both passwords' derived keys, and check that the
+    // commitment rejects at least one of them.
+    #[test]
+    fn password_no_multi_key_ciphertext() {
+        use crate::{util::CommittingPrf, UtcAes256Gcm};
+        use aes_gcm::Aes256Gcm;
+
+        let mut rng = rand::thread_rng();
+        let (pw1, pw2): (&[u8], &[u8]) = (b"123456", b"password");
+
+        // Take the header of a real ciphertext. Both passwords are tried with its salt.
+        let honest = encrypt(&mut rng, &TEST_PARAMS, pw1, b"", b"").unwrap();
+        let header = &honest[..HEADER_LEN];
+        let salt = &header[HEADER_LEN - SALT_LEN..];
+        let k1 = derive_key(&TEST_PARAMS, pw1, salt).unwrap();
+        let k2 = derive_key(&TEST_PARAMS, pw2, salt).unwrap();
+        let nonce = Nonce::<MacHteUtcAes256Gcm>::default();
+
+        // If the payload were plain AES-256-GCM, one ciphertext would decrypt under both passwords
+        let (ct, tag) = gcm_collision(k1.as_ref(), k2.as_ref(), header);
+        for k in [&k1, &k2] {
+            let mut buf = ct;
+            Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(k.as_ref()))
+                .decrypt_in_place_detached(&nonce, header, &mut buf, (&tag).into())
+                .unwrap();
+        }
+
+        // Now find the inner AES-256-GCM keys of MacHteUtcAes256Gcm. MacHte derives a UtC key from
+        // the nonce and AAD, and UtC derives the GCM key and the commitment from that.
+        let inner = |k: &[u8; 32]| {
+            let utc_key = MacHteUtcAes256Gcm::new(Key::<MacHteUtcAes256Gcm>::from_slice(k))
+                .derive_key(&nonce, header);
+            UtcAes256Gcm::new(&utc_key).prf.prf(&nonce)
+        };
+        let (com1, mask1) = inner(&k1);
+        let (com2, mask2) = inner(&k2);
+
+        // The inner GCM ciphertext is valid under both masks, with no AAD
+        let (ct, tag) = gcm_collision(&mask1, &mask2, b"");
+        for mask in [&mask1, &mask2] {
+            let mut buf = ct;
+            Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(mask))
+                .decrypt_in_place_detached(&nonce, b"", &mut buf, (&tag).into())
+                .unwrap();
+        }
+
+        // But the ciphertext carries one commitment, and it only matches one password
+        for (com, right_pw, wrong_pw) in [(&com1, pw1, pw2), (&com2, pw2, pw1)] {
+            let forged = [header, &ct, &tag, com].concat();
+            assert!(decrypt(right_pw, b"", &forged).is_ok());
+            assert!(decrypt(wrong_pw, b"", &forged).is_err());
+        }
+    }
+}
@@ -0,0 +1,282 @@
+//! Defines helpers which pick the nonce for the caller and prepend it to the ciphertext, so that
+//! callers never handle nonces directly. The sealed format is
+//!
+//! ```text
+//! sealed = nonce || ciphertext || tag
+//! ```
+//!
+//! [`SealExt::seal`] picks a random nonce. With 96-bit nonces, random nonces collide with
+//! probability about `q²/2⁹⁷` after `q` messages, so a key should encrypt no more than about 2³²
+//! messages this way. [`CounterSealer`] instead uses a counter, which never repeats as long as the
+//! key is only used by one `CounterSealer` at a time.
+
+use crate::{
+    AnyKcAead, FusedHteUtcAes128Gcm, FusedHteUtcAes256Gcm, HkdfHteUtcAes128Gcm,
+    HkdfHteUtcAes256Gcm, MacHteUtcAes128Gcm, MacHteUtcAes256Gcm, UtcAes128Gcm, UtcAes256Gcm,
+    UtcChaCha20Poly1305,
+};
+
+use aead::{AeadInPlace, Error, Nonce, Tag};
+use cipher::typenum::Unsigned;
+use rand_core::{CryptoRng, RngCore};
+
+/// Encrypts `msg` under the given nonce and returns `nonce || ciphertext || tag`
+fn seal_with_nonce<A: AeadInPlace + ?Sized>(
+    ciph: &A,
+    nonce: &Nonce<A>,
+    associated_data: &[u8],
+    msg: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let nonce_len = A::NonceSize::USIZE;
+
+    let mut out = Vec::with_capacity(nonce_len + msg.len() + A::TagSize::USIZE);
+    out.extend_from_slice(nonce);
+    out.extend_from_slice(msg);
+    let tag = ciph.encrypt_in_place_detached(nonce, associated_data, &mut out[nonce_len..])?;
+    out.extend_from_slice(&tag);
+
+    Ok(out)
+}
+
+mod private {
+    /// Marks the committing AEADs that get [`super::SealExt`]. This can't be implemented outside
+    /// the crate, so `SealExt` never gives its misuse-resistant API to a non-committing AEAD.
+    pub trait Sealed {}
+}
+
+macro_rules! impl_sealed {
+    ($($aead:ty),* $(,)?) => {
+        $(impl private::Sealed for $aead {})*
+    };
+}
+
+impl_sealed!(
+    UtcAes128Gcm,
+    UtcAes256Gcm,
+    UtcChaCha20Poly1305,
+    MacHteUtcAes128Gcm,
+    MacHteUtcAes256Gcm,
+    HkdfHteUtcAes128Gcm,
+    HkdfHteUtcAes256Gcm,
+    FusedHteUtcAes128Gcm,
+    FusedHteUtcAes256Gcm,
+);
+
+/// An extension trait which adds nonce-managing `seal` and `open` methods to every AEAD in this
+/// crate. It is sealed, so it can't be implemented for other AEADs.
+pub trait SealExt: AeadInPlace + private::Sealed {
+    /// Encrypts `msg` under a random nonce and returns `nonce || ciphertext || tag`
+    fn seal<R: CryptoRng + RngCore>(
+        &self,
+        rng: &mut R,
+        associated_data: &[u8],
+        msg: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let mut nonce = Nonce::<Self>::default();
+        rng.fill_bytes(&mut nonce);
+        seal_with_nonce(self, &nonce, associated_data, msg)
+    }
+
+    /// Decrypts the output of [`SealExt::seal`] or [`CounterSealer::seal`] and returns the
+    /// plaintext. Returns an error if `sealed` is too short or decryption fails.
+    fn open(&self, associated_data: &[u8], sealed: &[u8]) -> Result<Vec<u8>, Error> {
+        let nonce_len = Self::NonceSize::USIZE;
+        let tag_len = Self::TagSize::USIZE;
+        if sealed.len() < nonce_len + tag_len {
+            return Err(Error);
+        }
+        let (nonce, rest) = sealed.split_at(nonce_len);
+        let (ciphertext, tag) = rest.split_at(rest.len() - tag_len);
+
+        let mut buf = ciphertext.to_vec();
+        self.decrypt_in_place_detached(
+            Nonce::<Self>::from_slice(nonce),
+            associated_data,
+            &mut buf,
+            Tag::<Self>::from_slice(tag),
+        )?;
+
+        Ok(buf)
+    }
+}
+
+impl<A: AeadInPlace + private::Sealed> SealExt for A {}
+
+/// Seals messages under successive nonces, treating the nonce as a big-endian counter. Once every
+/// nonce has been used, sealing returns an error rather than wrapping around.
+///
+/// The counter lives in memory, so the same key MUST NOT be given to two `CounterSealer`s, e.g.,
+/// across restarts, unless their nonce ranges are disjoint. See
+/// [`CounterSealer::with_initial_nonce`].
+pub struct CounterSealer<A: SealExt> {
+    ciph: A,
+    // This is None once the last nonce has been used
+    next_nonce: Option<Nonce<A>>,
+}
+
+impl<A: SealExt> CounterSealer<A> {
+    /// Makes a new sealer whose first nonce is all zeros
+    pub fn new(ciph: A) -> Self {
+        Self::with_initial_nonce(ciph, Nonce::<A>::default())
+    }
+
+    /// Makes a new sealer whose first nonce is `nonce`. This can be used to resume from a
+    /// persisted [`CounterSealer::next_nonce`], or to give each sender of a shared key its own
+    /// range of nonces by fixing their leading bytes.
+    pub fn with_initial_nonce(ciph: A, nonce: Nonce<A>) -> Self {
+        CounterSealer {
+            ciph,
+            next_nonce: Some(nonce),
+        }
+    }
+
+    /// Returns the nonce that the next call to [`CounterSealer::seal`] will use, or `None` if
+    /// every nonce has been used
+    pub fn next_nonce(&self) -> Option<&Nonce<A>> {
+        self.next_nonce.as_ref()
+    }
+
+    /// Encrypts `msg` under the next nonce and returns `nonce || ciphertext || tag`. Returns an
+    /// error if every nonce has been used.
+    pub fn seal(&mut self, associated_data: &[u8], msg: &[u8]) -> Result<Vec<u8>, Error> {
+        let nonce = self.next_nonce.as_ref().ok_or(Error)?;
+        let sealed = seal_with_nonce(&self.ciph, nonce, associated_data, msg)?;
+
+        // Increment the counter. If it carries out of the top byte, we've used every nonce.
+        let mut next = nonce.clone();
+        let wrapped = next.iter_mut().rev().all(|b| {
+            *b = b.wrapping_add(1);
+            *b == 0
+        });
+        self.next_nonce = if wrapped { None } else { Some(next) };
+
+        Ok(sealed)
+    }
+
+    /// Decrypts the output of a seal method. This is the same as [`SealExt::open`].
+    pub fn open(&self, associated_data: &[u8], sealed: &[u8]) -> Result<Vec<u8>, Error> {
+        self.ciph.open(associated_data, sealed)
+    }
+}
+
+impl AnyKcAead {
+    /// Encrypts `msg` under a random nonce and returns `nonce || ciphertext || tag`. This is the
+    /// same as [`SealExt::seal`].
+    pub fn seal<R: CryptoRng + RngCore>(
+        &self,
+        rng: &mut R,
+        associated_data: &[u8],
+        msg: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let mut sealed = vec![0u8; self.nonce_len()];
+        rng.fill_bytes(&mut sealed);
+        sealed.extend_from_slice(&self.encrypt(&sealed, associated_data, msg)?);
+
+        Ok(sealed)
+    }
+
+    /// Decrypts the output of [`AnyKcAead::seal`] and returns the plaintext. This is the same as
+    /// [`SealExt::open`].
+    pub fn open(&self, associated_data: &[u8], sealed: &[u8]) -> Result<Vec<u8>, Error> {
+        if sealed.len() < self.nonce_len() {
+            return Err(Error);
+        }
+        let (nonce, ciphertext) = sealed.split_at(self.nonce_len());
+        self.decrypt(nonce, associated_data, ciphertext)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        AlgorithmId, FusedHteUtcAes128Gcm, HkdfHteUtcAes256Gcm, MacHteUtcAes128Gcm, UtcAes128Gcm,
+        UtcAes256Gcm,
+    };
+
+    use aead::NewAead;
+
+    macro_rules! test_seal {
+        ($aead:ty, $test_name:ident) => {
+            #[test]
+            fn $test_name() {
+                let mut rng = rand::thread_rng();
+                let ciph = <$aead>::new(&<$aead>::generate_key(&mut rng));
+                let nonce_len = <$aead as aead::AeadCore>::NonceSize::USIZE;
+                let tag_len = <$aead as aead::AeadCore>::TagSize::USIZE;
+
+                let sealed = ciph.seal(&mut rng, b"aad", b"hello world").unwrap();
+                assert_eq!(sealed.len(), nonce_len + 11 + tag_len);
+                assert_eq!(ciph.open(b"aad", &sealed).unwrap(), b"hello world");
+                assert!(ciph.open(b"aae", &sealed).is_err());
+
+                // Two seals of the same message use different nonces
+                let sealed2 = ciph.seal(&mut rng, b"aad", b"hello world").unwrap();
+                assert_ne!(sealed[..nonce_len], sealed2[..nonce_len]);
+
+                // Modifying any byte fails, including the nonce
+                for i in 0..sealed.len() {
+                    let mut bad = sealed.clone();
+                    bad[i] ^= 1;
+                    assert!(ciph.open(b"aad", &bad).is_err());
+                }
+
+                // Short inputs are errors, not panics
+                for len in 0..nonce_len + tag_len {
+                    assert!(ciph.open(b"aad", &sealed[..len]).is_err());
+                }
+
+                // Counter nonces count up from 0 and open with the same method
+                let mut sealer = CounterSealer::new(<$aead>::new(&<$aead>::generate_key(&mut rng)));
+                for i in 0u8..3 {
+                    let sealed = sealer.seal(b"aad", b"hello world").unwrap();
+                    assert!(sealed[..nonce_len - 1].iter().all(|&b| b == 0));
+                    assert_eq!(sealed[nonce_len - 1], i);
+                    assert_eq!(sealer.open(b"aad", &sealed).unwrap(), b"hello world");
+                }
+            }
+        };
+    }
+
+    test_seal!(UtcAes128Gcm, seal_utc_aes128);
+    test_seal!(UtcAes256Gcm, seal_utc_aes256);
+    test_seal!(MacHteUtcAes128Gcm, seal_machte_utc_aes128);
+    test_seal!(HkdfHteUtcAes256Gcm, seal_hkdfhte_utc_aes256);
+    test_seal!(FusedHteUtcAes128Gcm, seal_fusedhte_utc_aes128);
+
+    // The counter carries across bytes and refuses to wrap
+    #[test]
+    fn counter_sealer_no_wrap() {
+        let mut rng = rand::thread_rng();
+        let key = UtcAes128Gcm::generate_key(&mut rng);
+
+        let mut nonce = Nonce::<UtcAes128Gcm>::default();
+        nonce[11] = 0xff;
+        let mut sealer = CounterSealer::with_initial_nonce(UtcAes128Gcm::new(&key), nonce);
+        sealer.seal(b"", b"").unwrap();
+        assert_eq!(&sealer.next_nonce().unwrap()[10..], &[0x01, 0x00]);
+
+        let last_nonce = Nonce::<UtcAes128Gcm>::clone_from_slice(&[0xff; 12]);
+        let mut sealer = CounterSealer::with_initial_nonce(UtcAes128Gcm::new(&key), last_nonce);
+        let sealed = sealer.seal(b"", b"last").unwrap();
+        assert_eq!(sealer.next_nonce(), None);
+        assert!(sealer.seal(b"", b"one too many").is_err());
+        assert_eq!(sealer.open(b"", &sealed).unwrap(), b"last");
+    }
+
+    #[test]
+    fn any_aead_seal() {
+        let mut rng = rand::thread_rng();
+        for alg in AlgorithmId::ALL {
+            let mut key = vec![0u8; alg.key_len()];
+            rng.fill_bytes(&mut key);
+            let ciph = AnyKcAead::new(alg, &key).unwrap();
+
+            let sealed = ciph.seal(&mut rng, b"aad", b"hello world").unwrap();
+            assert_eq!(ciph.open(b"aad", &sealed).unwrap(), b"hello world");
+            assert!(ciph.open(b"aae", &sealed).is_err());
+            assert!(ciph.open(b"aad", &sealed[..alg.nonce_len()]).is_err());
+            assert!(ciph.open(b"aad", &[]).is_err());
+        }
+    }
+}
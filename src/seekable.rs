@@ -0,0 +1,486 @@
+//! Defines a chunked ciphertext format which supports decrypting arbitrary byte ranges of the
+//! plaintext, while only authenticating the chunks that the range touches.
+//!
+//! The plaintext is split into `chunk_size`-byte chunks, the last of which may be shorter. Each
+//! chunk is encrypted under its own key and nonce, both derived from the chunk index, so chunks
+//! cannot be reordered. The ciphertext is laid out as
+//!
+//! ```text
+//! header = nonce || be32(chunk_size) || be64(plaintext_len)
+//! ciphertext = header || header_tag || C_0 || T_0 || C_1 || T_1 || ...
+//! ```
+//!
+//! where `header_tag` is the encryption of the empty message with the header as associated data.
+//! Since the underlying AEAD is committing, `header_tag` commits to the key, and it is checked on
+//! every decryption. Every chunk is also encrypted with the header as associated data, so a chunk
+//! can't be moved into a ciphertext with a different length or nonce, and the length in the header
+//! determines exactly where the last chunk ends. Truncation is thus detected whenever a decrypted
+//! range touches the missing chunks.
+//!
+//! Decryption doesn't need the whole ciphertext in memory. [`Seekable::open_header`] checks the
+//! header against the total ciphertext length, and [`Seekable::decrypt_range`] then fetches only
+//! the chunks that the range touches, e.g., with ranged reads from blob storage.
+
+use crate::{HkdfHteUtcAes128Gcm, HkdfHteUtcAes256Gcm, UtcAes128Gcm, UtcAes256Gcm};
+
+use core::marker::PhantomData;
+
+use aead::{AeadCore, AeadInPlace, Error, Key, NewAead, Nonce, Tag};
+use cipher::{typenum::Unsigned, BlockSizeUser};
+use digest::{Digest, OutputSizeUser};
+use hkdf::SimpleHkdf;
+use sha2::{Sha256, Sha512};
+use zeroize::Zeroize;
+
+/// A seekable key-committing AEAD built on top of AES-128-GCM
+pub type SeekableUtcAes128Gcm = Seekable<UtcAes128Gcm, Sha256>;
+
+/// A seekable key-committing AEAD built on top of AES-256-GCM
+pub type SeekableUtcAes256Gcm = Seekable<UtcAes256Gcm, Sha512>;
+
+/// A seekable context-committing AEAD built on top of AES-128-GCM
+pub type SeekableHkdfHteUtcAes128Gcm = Seekable<HkdfHteUtcAes128Gcm, Sha256>;
+
+/// A seekable context-committing AEAD built on top of AES-256-GCM
+pub type SeekableHkdfHteUtcAes256Gcm = Seekable<HkdfHteUtcAes256Gcm, Sha512>;
+
+// Here's the current definition:
+//
+// Seekable[H,A].Enc(K, N, M, chunk_size):
+//     header ← N || be32(chunk_size) || be64(|M|)
+//     header_tag ← A.Enc(K, N, header, "")
+//     prk ← HKDF[H].Extract(salt="Seekable", ikm=K)
+//     for each chunk M_i of M:
+//         K_i ← HKDF[H].Expand(prk, info=N || be64(i), len=|K|)
+//         N_i ← N ⊕ be64(i)
+//         (C_i, T_i) ← A.Enc(K_i, N_i, header, M_i)
+//     return header || header_tag || C_0 || T_0 || C_1 || T_1 || ...
+//
+// Decrypting a range recomputes header_tag and returns ⊥ if it doesn't match. Then it decrypts
+// only the chunks which overlap the range.
+
+const EXTRACT_DOMAIN_SEP: &[u8] = b"Seekable";
+
+// The sizes of the encoded chunk size and plaintext length in the header
+const CHUNK_SIZE_LEN: usize = 4;
+const PLAINTEXT_LEN_LEN: usize = 8;
+
+/// A chunked AEAD over a committing AEAD `A` which supports random-access decryption. `A` should
+/// be one of `Utc` or `HkdfHte`, so that the header tag commits to the key.
+pub struct Seekable<A, H>
+where
+    A: AeadInPlace + NewAead,
+    H: BlockSizeUser + Clone + Digest + OutputSizeUser,
+{
+    header_ciph: A,
+    chunk_kdf: SimpleHkdf<H>,
+}
+
+impl<A, H> Seekable<A, H>
+where
+    A: AeadInPlace + NewAead,
+    H: BlockSizeUser + Clone + Digest + OutputSizeUser,
+{
+    /// Makes a new seekable AEAD with the given key
+    pub fn new(key: &Key<A>) -> Self {
+        Seekable {
+            header_ciph: A::new(key),
+            chunk_kdf: SimpleHkdf::extract(Some(EXTRACT_DOMAIN_SEP), key).1,
+        }
+    }
+
+    /// Returns the size of the header, including its tag. This is the number of bytes that
+    /// [`Self::open_header`] needs.
+    pub fn header_len() -> usize {
+        A::NonceSize::USIZE + CHUNK_SIZE_LEN + PLAINTEXT_LEN_LEN + A::TagSize::USIZE
+    }
+
+    /// Returns the size of the header that's used as associated data, i.e., without its tag
+    fn header_aad_len() -> usize {
+        Self::header_len() - A::TagSize::USIZE
+    }
+
+    /// Returns the number of chunks a plaintext of the given length is split into
+    fn num_chunks(chunk_size: u32, plaintext_len: u64) -> u64 {
+        plaintext_len.div_ceil(u64::from(chunk_size))
+    }
+
+    /// Returns the cipher and nonce used for the `i`-th chunk
+    fn chunk_cipher(&self, nonce: &Nonce<A>, i: u64) -> (A, Nonce<A>) {
+        // This only fails if the key size is greater than 255*HashLen, which is way too big
+        let mut chunk_key = Key::<A>::default();
+        self.chunk_kdf
+            .expand_multi_info(&[nonce, &i.to_be_bytes()], &mut chunk_key)
+            .expect("key size is far too large");
+        let ciph = A::new(&chunk_key);
+        chunk_key.zeroize();
+
+        // XOR the index into the end of the nonce
+        let mut chunk_nonce = nonce.clone();
+        chunk_nonce
+            .iter_mut()
+            .rev()
+            .zip(i.to_be_bytes().iter().rev())
+            .for_each(|(n, i)| *n ^= i);
+
+        (ciph, chunk_nonce)
+    }
+
+    /// Encrypts `plaintext` in `chunk_size`-byte chunks. The nonce MUST NOT be reused under the
+    /// same key.
+    ///
+    /// Panics if `chunk_size` is 0.
+    pub fn encrypt(
+        &self,
+        nonce: &Nonce<A>,
+        chunk_size: u32,
+        plaintext: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        assert!(chunk_size > 0, "chunk size must be nonzero");
+
+        let plaintext_len = plaintext.len() as u64;
+        let num_chunks = Self::num_chunks(chunk_size, plaintext_len) as usize;
+        let mut out = Vec::with_capacity(
+            Self::header_len() + plaintext.len() + num_chunks * A::TagSize::USIZE,
+        );
+
+        // Write the header and its tag
+        out.extend_from_slice(nonce);
+        out.extend_from_slice(&chunk_size.to_be_bytes());
+        out.extend_from_slice(&plaintext_len.to_be_bytes());
+        let header_tag = self
+            .header_ciph
+            .encrypt_in_place_detached(nonce, &out, &mut [])?;
+        let header = out.clone();
+        out.extend_from_slice(&header_tag);
+
+        // Encrypt every chunk with the header as associated data
+        for (i, chunk) in plaintext.chunks(chunk_size as usize).enumerate() {
+            let (ciph, chunk_nonce) = self.chunk_cipher(nonce, i as u64);
+
+            let start = out.len();
+            out.extend_from_slice(chunk);
+            let tag = ciph.encrypt_in_place_detached(&chunk_nonce, &header, &mut out[start..])?;
+            out.extend_from_slice(&tag);
+        }
+
+        Ok(out)
+    }
+
+    /// Checks the header of a ciphertext which is `ciphertext_len` bytes long in total. `header`
+    /// is the first [`Self::header_len`] bytes of the ciphertext. Nothing else is read, so the
+    /// rest of the ciphertext can stay in, e.g., blob storage until [`Self::decrypt_range`] asks
+    /// for it.
+    ///
+    /// Returns an error if the header is invalid under this key, or `ciphertext_len` isn't the
+    /// length that the header claims.
+    pub fn open_header(
+        &self,
+        header: &[u8],
+        ciphertext_len: u64,
+    ) -> Result<SeekableHeader<A>, Error> {
+        if header.len() != Self::header_len() {
+            return Err(Error);
+        }
+        let (header, header_tag) = header.split_at(Self::header_aad_len());
+
+        // The key commitment check. This fails if the key, nonce, chunk size, or length is wrong.
+        let nonce = Nonce::<A>::from_slice(&header[..A::NonceSize::USIZE]);
+        self.header_ciph.decrypt_in_place_detached(
+            nonce,
+            header,
+            &mut [],
+            Tag::<A>::from_slice(header_tag),
+        )?;
+
+        // Now we can parse the header
+        let (_, lens) = header.split_at(A::NonceSize::USIZE);
+        let (chunk_size_bytes, plaintext_len_bytes) = lens.split_at(CHUNK_SIZE_LEN);
+        let chunk_size = u32::from_be_bytes(chunk_size_bytes.try_into().unwrap());
+        let plaintext_len = u64::from_be_bytes(plaintext_len_bytes.try_into().unwrap());
+        if chunk_size == 0 {
+            return Err(Error);
+        }
+
+        // Make sure the ciphertext is exactly as long as it should be
+        let expected_len = Self::num_chunks(chunk_size, plaintext_len)
+            .checked_mul(A::TagSize::U64)
+            .and_then(|tags_len| tags_len.checked_add(plaintext_len))
+            .and_then(|body_len| body_len.checked_add(Self::header_len() as u64))
+            .ok_or(Error)?;
+        if ciphertext_len != expected_len {
+            return Err(Error);
+        }
+
+        Ok(SeekableHeader {
+            aad: header.to_vec(),
+            chunk_size,
+            plaintext_len,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Decrypts the `len` bytes of plaintext starting at `offset`, given the output of
+    /// [`Self::open_header`]. `fetch(ct_offset, ct_len)` must return the `ct_len` bytes of the
+    /// ciphertext starting at `ct_offset`. It is called once for each chunk that overlaps the
+    /// range, and never for anything else.
+    ///
+    /// Returns an error if `fetch` does, if any touched chunk fails to authenticate, or if the
+    /// range extends past the end of the plaintext.
+    pub fn decrypt_range<F>(
+        &self,
+        header: &SeekableHeader<A>,
+        mut fetch: F,
+        offset: u64,
+        len: usize,
+    ) -> Result<Vec<u8>, Error>
+    where
+        F: FnMut(u64, usize) -> Result<Vec<u8>, Error>,
+    {
+        let nonce = Nonce::<A>::from_slice(&header.aad[..A::NonceSize::USIZE]);
+        let plaintext_len = header.plaintext_len;
+
+        let end = offset.checked_add(len as u64).ok_or(Error)?;
+        if end > plaintext_len {
+            return Err(Error);
+        }
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+
+        let chunk_size = u64::from(header.chunk_size);
+        let segment_size = chunk_size + A::TagSize::U64;
+        let first_chunk = offset / chunk_size;
+        let last_chunk = (end - 1) / chunk_size;
+
+        let mut out = Vec::with_capacity(len);
+        for i in first_chunk..=last_chunk {
+            // The last chunk of the plaintext may be short
+            let chunk_start = i * chunk_size;
+            let chunk_len = core::cmp::min(chunk_size, plaintext_len - chunk_start) as usize;
+
+            // Fetch the chunk and its tag. open_header checked the total length, so the segment
+            // is in bounds of the ciphertext.
+            let ct_start = Self::header_len() as u64 + i * segment_size;
+            let mut buf = fetch(ct_start, chunk_len + A::TagSize::USIZE)?;
+            if buf.len() != chunk_len + A::TagSize::USIZE {
+                buf.zeroize();
+                return Err(Error);
+            }
+            let tag = Tag::<A>::clone_from_slice(&buf[chunk_len..]);
+            buf.truncate(chunk_len);
+
+            let (ciph, chunk_nonce) = self.chunk_cipher(nonce, i);
+            ciph.decrypt_in_place_detached(&chunk_nonce, &header.aad, &mut buf, &tag)?;
+
+            // Copy out the part of the chunk that's in the range
+            let from = offset.saturating_sub(chunk_start) as usize;
+            let to = core::cmp::min(end - chunk_start, chunk_len as u64) as usize;
+            out.extend_from_slice(&buf[from..to]);
+            buf.zeroize();
+        }
+
+        Ok(out)
+    }
+}
+
+/// The checked header of a [`Seekable`] ciphertext, returned by [`Seekable::open_header`]
+pub struct SeekableHeader<A: AeadCore> {
+    // The header without its tag, which is the associated data of every chunk
+    aad: Vec<u8>,
+    chunk_size: u32,
+    plaintext_len: u64,
+    // The nonce size comes from A
+    _marker: PhantomData<A>,
+}
+
+impl<A: AeadCore> SeekableHeader<A> {
+    /// Returns the size of the plaintext chunks
+    pub fn chunk_size(&self) -> u32 {
+        self.chunk_size
+    }
+
+    /// Returns the length of the plaintext
+    pub fn plaintext_len(&self) -> u64 {
+        self.plaintext_len
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use rand::RngCore;
+
+    const CHUNK_SIZE: u32 = 16;
+
+    // Opens the header of a ciphertext which is held in memory
+    fn open_slice<A, H>(ciph: &Seekable<A, H>, ct: &[u8]) -> Result<SeekableHeader<A>, Error>
+    where
+        A: AeadInPlace + NewAead,
+        H: BlockSizeUser + Clone + Digest + OutputSizeUser,
+    {
+        let header_len = core::cmp::min(ct.len(), Seekable::<A, H>::header_len());
+        ciph.open_header(&ct[..header_len], ct.len() as u64)
+    }
+
+    // Opens the header and decrypts a range of a ciphertext which is held in memory
+    fn decrypt_slice<A, H>(
+        ciph: &Seekable<A, H>,
+        ct: &[u8],
+        offset: u64,
+        len: usize,
+    ) -> Result<Vec<u8>, Error>
+    where
+        A: AeadInPlace + NewAead,
+        H: BlockSizeUser + Clone + Digest + OutputSizeUser,
+    {
+        let header = open_slice(ciph, ct)?;
+        let fetch = |start: u64, len: usize| {
+            let start = start as usize;
+            ct.get(start..start + len).map(<[u8]>::to_vec).ok_or(Error)
+        };
+        ciph.decrypt_range(&header, fetch, offset, len)
+    }
+
+    macro_rules! test_seekable {
+        ($seekable:ty, $aead:ty, $test_name:ident) => {
+            #[test]
+            fn $test_name() {
+                let mut rng = rand::thread_rng();
+                let key = <$aead>::generate_key(&mut rng);
+                let ciph = <$seekable>::new(&key);
+                let mut nonce = Nonce::<$aead>::default();
+                rng.fill_bytes(&mut nonce);
+                let header_len = <$seekable>::header_len();
+                let tag_len = <$aead as aead::AeadCore>::TagSize::USIZE;
+
+                for msg_len in [0, 1, CHUNK_SIZE as usize, 5 * CHUNK_SIZE as usize + 3] {
+                    let mut msg = vec![0u8; msg_len];
+                    rng.fill_bytes(&mut msg);
+                    let ct = ciph.encrypt(&nonce, CHUNK_SIZE, &msg).unwrap();
+                    let header = open_slice(&ciph, &ct).unwrap();
+                    assert_eq!(header.plaintext_len(), msg_len as u64);
+                    assert_eq!(header.chunk_size(), CHUNK_SIZE);
+
+                    // Ranges from every offset decrypt correctly, including ones which start and
+                    // end on chunk boundaries and ones which span several chunks
+                    let chunk_size = CHUNK_SIZE as usize;
+                    for offset in 0..=msg_len {
+                        let rest = msg_len - offset;
+                        for len in [0, 1, chunk_size - 1, chunk_size, chunk_size + 1, rest] {
+                            if len > rest {
+                                continue;
+                            }
+
+                            // The source refuses to return anything outside the touched chunks
+                            let touched = if len == 0 {
+                                0..0
+                            } else {
+                                let seg = chunk_size + tag_len;
+                                let first = offset / chunk_size;
+                                let last = (offset + len - 1) / chunk_size;
+                                let last_len =
+                                    core::cmp::min(chunk_size, msg_len - last * chunk_size);
+                                header_len + first * seg
+                                    ..header_len + last * seg + last_len + tag_len
+                            };
+                            let fetch = |start: u64, len: usize| {
+                                let range = start as usize..start as usize + len;
+                                if range.start < touched.start || range.end > touched.end {
+                                    return Err(Error);
+                                }
+                                Ok(ct[range].to_vec())
+                            };
+
+                            let pt = ciph
+                                .decrypt_range(&header, fetch, offset as u64, len)
+                                .unwrap();
+                            assert_eq!(pt, &msg[offset..offset + len]);
+                        }
+                    }
+
+                    // Ranges past the end fail
+                    assert!(decrypt_slice(&ciph, &ct, msg_len as u64, 1).is_err());
+                    assert!(decrypt_slice(&ciph, &ct, u64::MAX, 1).is_err());
+
+                    // A different key fails the header check
+                    let other = <$seekable>::new(&<$aead>::generate_key(&mut rng));
+                    assert!(open_slice(&other, &ct).is_err());
+
+                    // Modifying the header fails
+                    for i in 0..header_len {
+                        let mut bad_ct = ct.clone();
+                        bad_ct[i] ^= 1;
+                        assert!(open_slice(&ciph, &bad_ct).is_err());
+                    }
+
+                    // A short header, or the wrong total length, fails
+                    assert!(ciph
+                        .open_header(&ct[..header_len - 1], ct.len() as u64)
+                        .is_err());
+                    assert!(ciph
+                        .open_header(&ct[..header_len], ct.len() as u64 - 1)
+                        .is_err());
+                    assert!(ciph
+                        .open_header(&ct[..header_len], ct.len() as u64 + 1)
+                        .is_err());
+
+                    // A source which returns the wrong number of bytes fails
+                    if msg_len > 0 {
+                        let short_fetch = |start: u64, len: usize| {
+                            Ok(ct[start as usize..start as usize + len - 1].to_vec())
+                        };
+                        assert!(ciph.decrypt_range(&header, short_fetch, 0, 1).is_err());
+                    }
+                }
+
+                // Modifying a chunk only affects the ranges which touch it
+                let msg_len = 5 * CHUNK_SIZE as usize + 3;
+                let mut msg = vec![0u8; msg_len];
+                rng.fill_bytes(&mut msg);
+                let mut ct = ciph.encrypt(&nonce, CHUNK_SIZE, &msg).unwrap();
+                let chunk_1_start = header_len + CHUNK_SIZE as usize + tag_len;
+                ct[chunk_1_start] ^= 1;
+                let chunk_size = CHUNK_SIZE as u64;
+                assert!(decrypt_slice(&ciph, &ct, chunk_size, 1).is_err());
+                assert!(decrypt_slice(&ciph, &ct, 0, msg_len).is_err());
+                assert_eq!(
+                    decrypt_slice(&ciph, &ct, 0, CHUNK_SIZE as usize).unwrap(),
+                    &msg[..CHUNK_SIZE as usize]
+                );
+                assert_eq!(
+                    decrypt_slice(
+                        &ciph,
+                        &ct,
+                        2 * chunk_size,
+                        msg_len - 2 * CHUNK_SIZE as usize
+                    )
+                    .unwrap(),
+                    &msg[2 * CHUNK_SIZE as usize..]
+                );
+
+                // Swapping two equal-length chunks fails, since the keys depend on the index
+                let mut ct = ciph.encrypt(&nonce, CHUNK_SIZE, &msg).unwrap();
+                let seg = CHUNK_SIZE as usize + tag_len;
+                let (c0, c1) = ct[header_len..header_len + 2 * seg].split_at_mut(seg);
+                c0.swap_with_slice(c1);
+                assert!(decrypt_slice(&ciph, &ct, 0, 1).is_err());
+                assert!(decrypt_slice(&ciph, &ct, chunk_size, 1).is_err());
+            }
+        };
+    }
+
+    test_seekable!(SeekableUtcAes128Gcm, UtcAes128Gcm, seekable_utc_aes128);
+    test_seekable!(SeekableUtcAes256Gcm, UtcAes256Gcm, seekable_utc_aes256);
+    test_seekable!(
+        SeekableHkdfHteUtcAes128Gcm,
+        HkdfHteUtcAes128Gcm,
+        seekable_hkdfhte_utc_aes128
+    );
+    test_seekable!(
+        SeekableHkdfHteUtcAes256Gcm,
+        HkdfHteUtcAes256Gcm,
+        seekable_hkdfhte_utc_aes256
+    );
+}
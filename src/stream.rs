@@ -0,0 +1,671 @@
+//! Defines STREAM online encryption over the committing AEADs in this crate. STREAM is described
+//! in [Hoang, Reyhanitabar, Rogaway, and Vizár](https://eprint.iacr.org/2015/189). This lets the
+//! caller encrypt a message which is too large to hold in memory by splitting it into segments.
+//!
+//! Every segment is encrypted under the same key with a nonce of the form `prefix || counter ||
+//! last_block_flag`, so segments cannot be reordered, and the stream cannot be truncated without
+//! detection. Since each segment is a ciphertext of the underlying committing AEAD, every segment
+//! also commits to the key (and, for the HtE types, the nonce and associated data).
+//!
+//! The STREAM primitives themselves come from the [`aead::stream`] module. They work over any
+//! [`AeadInPlace`](aead::AeadInPlace), and this module just provides aliases for the types in this
+//! crate. For example, with a 12-byte AEAD nonce, `StreamBE32` uses a 7-byte nonce prefix, a 32-bit
+//! big-endian counter, and a 1-byte last-block flag.
+//!
+//! Running STREAM over [`Utc`] means every segment carries its own 32–64 byte commitment. To
+//! avoid this, [`Utc::stream_encryptor`] and [`Utc::stream_decryptor`] commit to the key once per
+//! stream. The commitment goes in the stream header, and every segment is encrypted with the
+//! underlying AEAD under its own key, derived from the same committing PRF output. So each segment
+//! only carries the underlying AEAD's tag.
+//!
+//! Finally, [`HteStreamEncryptor`] and [`HteStreamDecryptor`] give a context-committing streaming
+//! mode. Like `HkdfHte`, every segment is encrypted under a derived key. Here the key is derived
+//! from the stream nonce, the header associated data, the segment's position, a last-segment flag,
+//! and a running transcript of all previous segments' tags. So a segment cannot be reordered,
+//! dropped, truncated, or spliced into a different stream, even by someone who knows the key.
+
+use crate::{
+    util::{CommittingPrf, PrfCom},
+    MacHteUtcAes128Gcm, MacHteUtcAes256Gcm, Utc, UtcAes128Gcm, UtcAes256Gcm,
+};
+
+use core::ops::Sub;
+
+use aead::{AeadInPlace, Buffer, Error, Key, NewAead, Tag};
+use cipher::{
+    generic_array::{arr::AddLength, ArrayLength, GenericArray},
+    typenum::{Unsigned, U5},
+    BlockSizeUser,
+};
+use digest::{Digest, Output, OutputSizeUser};
+use hkdf::SimpleHkdf;
+use sha2::{Sha256, Sha512};
+use subtle::ConstantTimeEq;
+use zeroize::Zeroize;
+
+pub use aead::stream::{
+    Decryptor, DecryptorBE32, DecryptorLE31, Encryptor, EncryptorBE32, EncryptorLE31, NewStream,
+    Nonce, StreamBE32, StreamLE31, StreamPrimitive,
+};
+
+/// A STREAM encryptor over [`UtcAes128Gcm`]
+pub type UtcAes128GcmStreamEncryptor = EncryptorBE32<UtcAes128Gcm>;
+
+/// A STREAM decryptor over [`UtcAes128Gcm`]
+pub type UtcAes128GcmStreamDecryptor = DecryptorBE32<UtcAes128Gcm>;
+
+/// A STREAM encryptor over [`UtcAes256Gcm`]
+pub type UtcAes256GcmStreamEncryptor = EncryptorBE32<UtcAes256Gcm>;
+
+/// A STREAM decryptor over [`UtcAes256Gcm`]
+pub type UtcAes256GcmStreamDecryptor = DecryptorBE32<UtcAes256Gcm>;
+
+/// A STREAM encryptor over [`MacHteUtcAes128Gcm`]
+pub type MacHteUtcAes128GcmStreamEncryptor = EncryptorBE32<MacHteUtcAes128Gcm>;
+
+/// A STREAM decryptor over [`MacHteUtcAes128Gcm`]
+pub type MacHteUtcAes128GcmStreamDecryptor = DecryptorBE32<MacHteUtcAes128Gcm>;
+
+/// A STREAM encryptor over [`MacHteUtcAes256Gcm`]
+pub type MacHteUtcAes256GcmStreamEncryptor = EncryptorBE32<MacHteUtcAes256Gcm>;
+
+/// A STREAM decryptor over [`MacHteUtcAes256Gcm`]
+pub type MacHteUtcAes256GcmStreamDecryptor = DecryptorBE32<MacHteUtcAes256Gcm>;
+
+// Here's the current definition of the per-stream committing mode:
+//
+// UtcStream[F, A].Init(K, N):
+//     (com, mask) ← F.Prf(K, N)
+//     prk ← HKDF[SHA-256].Extract(salt="UtcStream", ikm=mask)
+//     prefix ← N[..|N| - 5]
+//     return com
+//
+// UtcStream[F, A].Enc(i, last, AD_i, M_i):
+//     K_i ← HKDF[SHA-256].Expand(prk, info=be32(i) || last, len=|K|)
+//     N_i ← prefix || be32(i) || last
+//     return A.Enc(K_i, N_i, AD_i, M_i)
+//
+// The header (N, com) is sent once, and the segments are ciphertexts of A. Like in UtC, the
+// commitment binds the key K. The mask is determined by (K, N), and every segment key by the mask,
+// so the whole stream decrypts under at most one key. Each segment gets its own key, so no key of
+// A ever encrypts more than one segment, and the usage limits of A don't shrink as the stream
+// grows. The mask is uniformly random and at most 256 bits, so HKDF-SHA256 is enough for every A.
+// The segment keys are already unique, so N_i is just the usual STREAM nonce, taken from N so that
+// the header fully determines the stream.
+
+const UTC_STREAM_DOMAIN_SEP: &[u8] = b"UtcStream";
+
+/// A STREAM encryptor returned by [`Utc::stream_encryptor`]
+pub type UtcStreamEncryptor<A> = Encryptor<A, UtcSegments<A>>;
+
+/// A STREAM decryptor returned by [`Utc::stream_decryptor`]
+pub type UtcStreamDecryptor<A> = Decryptor<A, UtcSegments<A>>;
+
+impl<A, F> Utc<A, F>
+where
+    A: AeadInPlace + NewAead,
+    F: CommittingPrf<KeySize = A::KeySize, MsgSize = A::NonceSize, MaskSize = A::KeySize>,
+    F::ComSize: AddLength<u8, A::TagSize>,
+    A::NonceSize: Sub<U5>,
+    <A::NonceSize as Sub<U5>>::Output: ArrayLength<u8>,
+{
+    /// Starts a segmented stream which commits to the key once. Returns the commitment, which
+    /// must be stored in the stream header alongside `nonce`, and a STREAM encryptor which
+    /// encrypts every segment under its own key of the underlying AEAD. Every segment's ciphertext
+    /// carries only the underlying AEAD's tag.
+    ///
+    /// As with a regular UtC encryption, `nonce` MUST NOT be reused under the same key.
+    pub fn stream_encryptor(
+        &self,
+        nonce: &aead::Nonce<Self>,
+    ) -> (PrfCom<F>, UtcStreamEncryptor<A>) {
+        let (prf_com, prf_mask) = self.prf.prf(nonce);
+        let segments = UtcSegments::new(&prf_mask, stream_prefix::<A>(nonce));
+
+        (prf_com, Encryptor::from_stream_primitive(segments))
+    }
+
+    /// Checks the commitment from a stream header and, if it matches, returns a STREAM decryptor
+    /// for the segments. Returns an error if the commitment does not match this key.
+    pub fn stream_decryptor(
+        &self,
+        nonce: &aead::Nonce<Self>,
+        prf_com: &PrfCom<F>,
+    ) -> Result<UtcStreamDecryptor<A>, Error> {
+        let (expected_prf_com, prf_mask) = self.prf.prf(nonce);
+
+        if prf_com.ct_eq(&expected_prf_com).unwrap_u8() == 1 {
+            let segments = UtcSegments::new(&prf_mask, stream_prefix::<A>(nonce));
+            Ok(Decryptor::from_stream_primitive(segments))
+        } else {
+            Err(Error)
+        }
+    }
+}
+
+/// The STREAM primitive behind [`Utc::stream_encryptor`] and [`Utc::stream_decryptor`]. Every
+/// segment is encrypted under its own key, derived from the stream's mask and the segment's
+/// position and last-segment flag.
+pub struct UtcSegments<A>
+where
+    A: AeadInPlace + NewAead,
+    A::NonceSize: Sub<U5>,
+    <A::NonceSize as Sub<U5>>::Output: ArrayLength<u8>,
+{
+    hkdf: SimpleHkdf<Sha256>,
+    prefix: Nonce<A, Self>,
+}
+
+impl<A> UtcSegments<A>
+where
+    A: AeadInPlace + NewAead,
+    A::NonceSize: Sub<U5>,
+    <A::NonceSize as Sub<U5>>::Output: ArrayLength<u8>,
+{
+    fn new(mask: &Key<A>, prefix: &Nonce<A, Self>) -> Self {
+        UtcSegments {
+            hkdf: SimpleHkdf::extract(Some(UTC_STREAM_DOMAIN_SEP), mask).1,
+            prefix: prefix.clone(),
+        }
+    }
+
+    /// Derives the key and nonce of the segment at the given position
+    fn segment_cipher(&self, position: u32, last: bool) -> (A, aead::Nonce<A>) {
+        let info = [&position.to_be_bytes()[..], &[last as u8]].concat();
+
+        // This only fails if A::KeySize is greater than 255*32, which is way too big
+        let mut seg_key = Key::<A>::default();
+        self.hkdf
+            .expand(&info, &mut seg_key)
+            .expect("key size is far too large");
+        let ciph = A::new(&seg_key);
+        seg_key.zeroize();
+
+        let mut nonce = aead::Nonce::<A>::default();
+        let (prefix, tail) = nonce.split_at_mut(self.prefix.len());
+        prefix.copy_from_slice(&self.prefix);
+        tail.copy_from_slice(&info);
+
+        (ciph, nonce)
+    }
+}
+
+impl<A> StreamPrimitive<A> for UtcSegments<A>
+where
+    A: AeadInPlace + NewAead,
+    A::NonceSize: Sub<U5>,
+    <A::NonceSize as Sub<U5>>::Output: ArrayLength<u8>,
+{
+    type NonceOverhead = U5;
+    type Counter = u32;
+    const COUNTER_INCR: u32 = 1;
+    const COUNTER_MAX: u32 = u32::MAX;
+
+    fn encrypt_in_place(
+        &self,
+        position: u32,
+        last_block: bool,
+        associated_data: &[u8],
+        buffer: &mut dyn Buffer,
+    ) -> Result<(), Error> {
+        let (ciph, nonce) = self.segment_cipher(position, last_block);
+        ciph.encrypt_in_place(&nonce, associated_data, buffer)
+    }
+
+    fn decrypt_in_place(
+        &self,
+        position: u32,
+        last_block: bool,
+        associated_data: &[u8],
+        buffer: &mut dyn Buffer,
+    ) -> Result<(), Error> {
+        let (ciph, nonce) = self.segment_cipher(position, last_block);
+        ciph.decrypt_in_place(&nonce, associated_data, buffer)
+    }
+}
+
+/// Returns the STREAM nonce prefix, i.e., the first `|N| - 5` bytes of `nonce`
+fn stream_prefix<A>(nonce: &aead::Nonce<A>) -> &Nonce<A, StreamBE32<A>>
+where
+    A: AeadInPlace,
+    A::NonceSize: Sub<U5>,
+    <A::NonceSize as Sub<U5>>::Output: ArrayLength<u8>,
+{
+    let prefix_len = <A::NonceSize as Sub<U5>>::Output::USIZE;
+    GenericArray::from_slice(&nonce[..prefix_len])
+}
+
+/// A context-committing stream encryptor over [`UtcAes128Gcm`]
+pub type UtcAes128GcmHteStreamEncryptor = HteStreamEncryptor<UtcAes128Gcm, Sha256>;
+
+/// A context-committing stream decryptor over [`UtcAes128Gcm`]
+pub type UtcAes128GcmHteStreamDecryptor = HteStreamDecryptor<UtcAes128Gcm, Sha256>;
+
+/// A context-committing stream encryptor over [`UtcAes256Gcm`]
+pub type UtcAes256GcmHteStreamEncryptor = HteStreamEncryptor<UtcAes256Gcm, Sha512>;
+
+/// A context-committing stream decryptor over [`UtcAes256Gcm`]
+pub type UtcAes256GcmHteStreamDecryptor = HteStreamDecryptor<UtcAes256Gcm, Sha512>;
+
+// Here's the current definition of the context-committing streaming mode. It is HtE where the
+// context of segment i is (N, A, i, last, T_i), and T_i is a running hash of the previous tags.
+//
+// HteStream[H, A].Init(K, N, A):
+//     prk ← HKDF[H].Extract(salt="HteStream", ikm=K)
+//     T_0 ← H(N || A)
+//
+// HteStream[H, A].Enc(i, last, M_i):
+//     L_i ← HKDF[H].Expand(prk, info=T_i || be32(i) || last, len=|K|)
+//     (C_i, τ_i) ← A.Enc(L_i, N, "", M_i)
+//     T_{i+1} ← H(T_i || τ_i)
+//     return (C_i, τ_i)
+//
+// Decryption derives L_i the same way and only advances the transcript if A.Dec succeeds.
+//
+// A is a key-committing AEAD, so (C_i, τ_i) decrypts under at most one L_i. HKDF is collision
+// resistant, so L_i fixes (K, T_i, i, last), and T_i fixes N, A, and all the previous tags. For UtC,
+// each τ_i contains a commitment to L_i, so T_{i+1} fixes L_i, and the chain continues all the way
+// back to the start of the stream.
+
+const HTE_STREAM_DOMAIN_SEP: &[u8] = b"HteStream";
+
+/// The state shared by [`HteStreamEncryptor`] and [`HteStreamDecryptor`]
+struct HteStreamState<A, H>
+where
+    A: AeadInPlace + NewAead,
+    H: BlockSizeUser + Clone + Digest + OutputSizeUser,
+{
+    hkdf: SimpleHkdf<H>,
+    nonce: aead::Nonce<A>,
+    transcript: Output<H>,
+    position: u32,
+}
+
+impl<A, H> HteStreamState<A, H>
+where
+    A: AeadInPlace + NewAead,
+    H: BlockSizeUser + Clone + Digest + OutputSizeUser,
+{
+    fn new(key: &Key<A>, nonce: &aead::Nonce<A>, associated_data: &[u8]) -> Self {
+        let transcript = H::new()
+            .chain_update(nonce)
+            .chain_update(associated_data)
+            .finalize();
+
+        HteStreamState {
+            hkdf: SimpleHkdf::extract(Some(HTE_STREAM_DOMAIN_SEP), key).1,
+            nonce: nonce.clone(),
+            transcript,
+            position: 0,
+        }
+    }
+
+    /// Derives the key of the current segment, or errors if the counter is exhausted
+    fn segment_cipher(&self, last: bool) -> Result<A, Error> {
+        // The maximum position is disallowed so that there's always room for a last segment
+        if self.position == u32::MAX {
+            return Err(Error);
+        }
+
+        // This only fails if A::KeySize is greater than 255*HashLen, which is way too big
+        let mut seg_key = Key::<A>::default();
+        self.hkdf
+            .expand_multi_info(
+                &[
+                    &self.transcript,
+                    &self.position.to_be_bytes(),
+                    &[last as u8],
+                ],
+                &mut seg_key,
+            )
+            .expect("key size is far too large");
+
+        Ok(A::new(&seg_key))
+    }
+
+    /// Folds the tag of the current segment into the transcript, and moves to the next segment
+    fn advance(&mut self, tag: &Tag<A>) {
+        self.transcript = H::new()
+            .chain_update(&self.transcript)
+            .chain_update(tag)
+            .finalize();
+        self.position += 1;
+    }
+
+    fn encrypt_in_place(&mut self, last: bool, buffer: &mut dyn Buffer) -> Result<(), Error> {
+        let ciph = self.segment_cipher(last)?;
+        let tag = ciph.encrypt_in_place_detached(&self.nonce, &[], buffer.as_mut())?;
+        buffer.extend_from_slice(&tag)?;
+        self.advance(&tag);
+
+        Ok(())
+    }
+
+    fn decrypt_in_place(&mut self, last: bool, buffer: &mut dyn Buffer) -> Result<(), Error> {
+        let tag_pos = buffer.len().checked_sub(A::TagSize::USIZE).ok_or(Error)?;
+
+        let ciph = self.segment_cipher(last)?;
+        let tag = Tag::<A>::clone_from_slice(&buffer.as_ref()[tag_pos..]);
+        ciph.decrypt_in_place_detached(&self.nonce, &[], &mut buffer.as_mut()[..tag_pos], &tag)?;
+        buffer.truncate(tag_pos);
+        self.advance(&tag);
+
+        Ok(())
+    }
+}
+
+/// A context-committing stream encryptor. The first segment is bound to the nonce and header
+/// associated data, and every subsequent segment is bound to all the segments before it.
+pub struct HteStreamEncryptor<A, H>
+where
+    A: AeadInPlace + NewAead,
+    H: BlockSizeUser + Clone + Digest + OutputSizeUser,
+{
+    state: HteStreamState<A, H>,
+}
+
+impl<A, H> HteStreamEncryptor<A, H>
+where
+    A: AeadInPlace + NewAead,
+    H: BlockSizeUser + Clone + Digest + OutputSizeUser,
+{
+    /// Starts a stream under the given key, nonce, and header associated data. The nonce MUST NOT
+    /// be reused under the same key.
+    pub fn new(key: &Key<A>, nonce: &aead::Nonce<A>, associated_data: &[u8]) -> Self {
+        HteStreamEncryptor {
+            state: HteStreamState::new(key, nonce, associated_data),
+        }
+    }
+
+    /// Encrypts the next segment in place, appending its tag to the buffer
+    pub fn encrypt_next_in_place(&mut self, buffer: &mut dyn Buffer) -> Result<(), Error> {
+        self.state.encrypt_in_place(false, buffer)
+    }
+
+    /// Encrypts the last segment in place, appending its tag to the buffer. This consumes the
+    /// encryptor, so no more segments can be added.
+    pub fn encrypt_last_in_place(mut self, buffer: &mut dyn Buffer) -> Result<(), Error> {
+        self.state.encrypt_in_place(true, buffer)
+    }
+}
+
+/// A context-committing stream decryptor. See [`HteStreamEncryptor`].
+pub struct HteStreamDecryptor<A, H>
+where
+    A: AeadInPlace + NewAead,
+    H: BlockSizeUser + Clone + Digest + OutputSizeUser,
+{
+    state: HteStreamState<A, H>,
+}
+
+impl<A, H> HteStreamDecryptor<A, H>
+where
+    A: AeadInPlace + NewAead,
+    H: BlockSizeUser + Clone + Digest + OutputSizeUser,
+{
+    /// Starts decrypting a stream under the given key, nonce, and header associated data
+    pub fn new(key: &Key<A>, nonce: &aead::Nonce<A>, associated_data: &[u8]) -> Self {
+        HteStreamDecryptor {
+            state: HteStreamState::new(key, nonce, associated_data),
+        }
+    }
+
+    /// Decrypts the next segment in place, removing its tag from the buffer. On error, the
+    /// decryptor does not advance.
+    pub fn decrypt_next_in_place(&mut self, buffer: &mut dyn Buffer) -> Result<(), Error> {
+        self.state.decrypt_in_place(false, buffer)
+    }
+
+    /// Decrypts the last segment in place, removing its tag from the buffer. This consumes the
+    /// decryptor, so no more segments can be decrypted.
+    pub fn decrypt_last_in_place(mut self, buffer: &mut dyn Buffer) -> Result<(), Error> {
+        self.state.decrypt_in_place(true, buffer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use aead::NewAead;
+    use aes_gcm::Aes256Gcm;
+    use rand::RngCore;
+
+    // Tests that a multi-segment stream round-trips, and that truncation and reordering are caught
+    macro_rules! test_stream {
+        ($aead:ty, $test_name:ident) => {
+            #[test]
+            fn $test_name() {
+                let mut rng = rand::thread_rng();
+                let key = <$aead>::generate_key(&mut rng);
+                let mut nonce = Nonce::<$aead, StreamBE32<$aead>>::default();
+                rng.fill_bytes(&mut nonce);
+
+                // Encrypt three segments, marking the final one as the last
+                let segments: [&[u8]; 3] = [b"first segment", b"second segment", b"last"];
+                let mut enc = EncryptorBE32::<$aead>::new(&key, &nonce);
+                let cts = [
+                    enc.encrypt_next(segments[0]).unwrap(),
+                    enc.encrypt_next(segments[1]).unwrap(),
+                    enc.encrypt_last(segments[2]).unwrap(),
+                ];
+
+                // Round trip
+                let mut dec = DecryptorBE32::<$aead>::new(&key, &nonce);
+                assert_eq!(dec.decrypt_next(cts[0].as_slice()).unwrap(), segments[0]);
+                assert_eq!(dec.decrypt_next(cts[1].as_slice()).unwrap(), segments[1]);
+                assert_eq!(dec.decrypt_last(cts[2].as_slice()).unwrap(), segments[2]);
+
+                // Truncation: the second segment is not marked as the last one
+                let mut dec = DecryptorBE32::<$aead>::new(&key, &nonce);
+                dec.decrypt_next(cts[0].as_slice()).unwrap();
+                assert!(dec.decrypt_last(cts[1].as_slice()).is_err());
+
+                // Reordering: the second segment can't be decrypted first
+                let mut dec = DecryptorBE32::<$aead>::new(&key, &nonce);
+                assert!(dec.decrypt_next(cts[1].as_slice()).is_err());
+
+                // Wrong key
+                let other_key = <$aead>::generate_key(&mut rng);
+                let mut dec = DecryptorBE32::<$aead>::new(&other_key, &nonce);
+                assert!(dec.decrypt_next(cts[0].as_slice()).is_err());
+            }
+        };
+    }
+
+    test_stream!(UtcAes128Gcm, stream_utc_aes128);
+    test_stream!(UtcAes256Gcm, stream_utc_aes256);
+    test_stream!(MacHteUtcAes128Gcm, stream_machte_utc_aes128);
+    test_stream!(MacHteUtcAes256Gcm, stream_machte_utc_aes256);
+
+    // Tests the per-stream committing mode: segments round-trip and carry only the GCM tag,
+    // truncation and reordering are caught, and a mismatched key or commitment is rejected at the
+    // header
+    #[test]
+    fn utc_committed_stream() {
+        let mut rng = rand::thread_rng();
+        let ciph = UtcAes256Gcm::new(&UtcAes256Gcm::generate_key(&mut rng));
+        let mut nonce = aead::Nonce::<UtcAes256Gcm>::default();
+        rng.fill_bytes(&mut nonce);
+
+        let segments: [&[u8]; 3] = [b"first segment", b"second segment", b"last"];
+        let (com, mut enc) = ciph.stream_encryptor(&nonce);
+        let cts = [
+            enc.encrypt_next(segments[0]).unwrap(),
+            enc.encrypt_next(segments[1]).unwrap(),
+            enc.encrypt_last(segments[2]).unwrap(),
+        ];
+
+        // Each segment only has a 16-byte GCM tag
+        for (ct, seg) in cts.iter().zip(segments.iter()) {
+            assert_eq!(ct.len(), seg.len() + 16);
+        }
+
+        // Round trip
+        let mut dec = ciph.stream_decryptor(&nonce, &com).unwrap();
+        assert_eq!(dec.decrypt_next(cts[0].as_slice()).unwrap(), segments[0]);
+        assert_eq!(dec.decrypt_next(cts[1].as_slice()).unwrap(), segments[1]);
+        assert_eq!(dec.decrypt_last(cts[2].as_slice()).unwrap(), segments[2]);
+
+        // Truncation
+        let mut dec = ciph.stream_decryptor(&nonce, &com).unwrap();
+        dec.decrypt_next(cts[0].as_slice()).unwrap();
+        assert!(dec.decrypt_last(cts[1].as_slice()).is_err());
+
+        // Reordering
+        let mut dec = ciph.stream_decryptor(&nonce, &com).unwrap();
+        assert!(dec.decrypt_next(cts[1].as_slice()).is_err());
+
+        // Segments are encrypted under their own keys, not under the mask itself
+        let (_, mask) = ciph.prf.prf(&nonce);
+        let mut mask_dec =
+            DecryptorBE32::from_aead(Aes256Gcm::new(&mask), stream_prefix::<Aes256Gcm>(&nonce));
+        assert!(mask_dec.decrypt_next(cts[0].as_slice()).is_err());
+
+        // A modified commitment is rejected
+        let mut bad_com = com;
+        bad_com[0] ^= 1;
+        assert!(ciph.stream_decryptor(&nonce, &bad_com).is_err());
+
+        // A different key is rejected before any segment is decrypted
+        let other_ciph = UtcAes256Gcm::new(&UtcAes256Gcm::generate_key(&mut rng));
+        assert!(other_ciph.stream_decryptor(&nonce, &com).is_err());
+    }
+
+    // Encrypts the given segments under a fresh HteStream, marking the final one as the last
+    fn hte_encrypt(
+        key: &Key<UtcAes128Gcm>,
+        nonce: &aead::Nonce<UtcAes128Gcm>,
+        aad: &[u8],
+        segments: &[&[u8]],
+    ) -> Vec<Vec<u8>> {
+        let mut enc = UtcAes128GcmHteStreamEncryptor::new(key, nonce, aad);
+        let (last, init) = segments.split_last().unwrap();
+
+        let mut cts: Vec<Vec<u8>> = init
+            .iter()
+            .map(|seg| {
+                let mut buf = seg.to_vec();
+                enc.encrypt_next_in_place(&mut buf).unwrap();
+                buf
+            })
+            .collect();
+        let mut buf = last.to_vec();
+        enc.encrypt_last_in_place(&mut buf).unwrap();
+        cts.push(buf);
+
+        cts
+    }
+
+    // Decrypts the given segments, treating the final one as the last. Returns None on error.
+    fn hte_decrypt(
+        key: &Key<UtcAes128Gcm>,
+        nonce: &aead::Nonce<UtcAes128Gcm>,
+        aad: &[u8],
+        cts: &[&Vec<u8>],
+    ) -> Option<Vec<Vec<u8>>> {
+        let mut dec = UtcAes128GcmHteStreamDecryptor::new(key, nonce, aad);
+        let (last, init) = cts.split_last().unwrap();
+
+        let mut pts = Vec::new();
+        for ct in init {
+            let mut buf = ct.to_vec();
+            dec.decrypt_next_in_place(&mut buf).ok()?;
+            pts.push(buf);
+        }
+        let mut buf = last.to_vec();
+        dec.decrypt_last_in_place(&mut buf).ok()?;
+        pts.push(buf);
+
+        Some(pts)
+    }
+
+    #[test]
+    fn hte_stream_correctness() {
+        let mut rng = rand::thread_rng();
+        let key = UtcAes128Gcm::generate_key(&mut rng);
+        let mut nonce = aead::Nonce::<UtcAes128Gcm>::default();
+        rng.fill_bytes(&mut nonce);
+
+        let segments: [&[u8]; 4] = [b"zero", b"one", b"", b"three"];
+        let cts = hte_encrypt(&key, &nonce, b"header", &segments);
+        let pts = hte_decrypt(&key, &nonce, b"header", &cts.iter().collect::<Vec<_>>()).unwrap();
+        assert_eq!(pts, segments);
+
+        // A wrong key, nonce, or header fails on the first segment
+        let other_key = UtcAes128Gcm::generate_key(&mut rng);
+        let mut other_nonce = nonce;
+        other_nonce[0] ^= 1;
+        let mut dec = UtcAes128GcmHteStreamDecryptor::new(&other_key, &nonce, b"header");
+        assert!(dec.decrypt_next_in_place(&mut cts[0].clone()).is_err());
+        let mut dec = UtcAes128GcmHteStreamDecryptor::new(&key, &other_nonce, b"header");
+        assert!(dec.decrypt_next_in_place(&mut cts[0].clone()).is_err());
+        let mut dec = UtcAes128GcmHteStreamDecryptor::new(&key, &nonce, b"headex");
+        assert!(dec.decrypt_next_in_place(&mut cts[0].clone()).is_err());
+    }
+
+    #[test]
+    fn hte_stream_reordering() {
+        let mut rng = rand::thread_rng();
+        let key = UtcAes128Gcm::generate_key(&mut rng);
+        let nonce = aead::Nonce::<UtcAes128Gcm>::default();
+
+        let segments: [&[u8]; 4] = [b"zero", b"one", b"two", b"three"];
+        let cts = hte_encrypt(&key, &nonce, b"", &segments);
+
+        // Swap segments 1 and 2
+        assert!(hte_decrypt(&key, &nonce, b"", &[&cts[0], &cts[2], &cts[1], &cts[3]]).is_none());
+
+        // Drop segment 1
+        assert!(hte_decrypt(&key, &nonce, b"", &[&cts[0], &cts[2], &cts[3]]).is_none());
+    }
+
+    #[test]
+    fn hte_stream_truncation() {
+        let mut rng = rand::thread_rng();
+        let key = UtcAes128Gcm::generate_key(&mut rng);
+        let nonce = aead::Nonce::<UtcAes128Gcm>::default();
+
+        let segments: [&[u8]; 3] = [b"zero", b"one", b"two"];
+        let cts = hte_encrypt(&key, &nonce, b"", &segments);
+
+        // Stopping early means a non-last segment is treated as the last one
+        assert!(hte_decrypt(&key, &nonce, b"", &[&cts[0], &cts[1]]).is_none());
+
+        // Extending the stream means the last segment is treated as a non-last one
+        assert!(hte_decrypt(&key, &nonce, b"", &[&cts[0], &cts[1], &cts[2], &cts[2]]).is_none());
+    }
+
+    #[test]
+    fn hte_stream_splicing() {
+        let mut rng = rand::thread_rng();
+        let key = UtcAes128Gcm::generate_key(&mut rng);
+        let nonce = aead::Nonce::<UtcAes128Gcm>::default();
+
+        // Two streams under the same key and nonce, differing only in header AAD
+        let segments: [&[u8]; 3] = [b"zero", b"one", b"two"];
+        let cts_a = hte_encrypt(&key, &nonce, b"stream a", &segments);
+        let cts_b = hte_encrypt(&key, &nonce, b"stream b", &segments);
+
+        // No segment of one stream can be spliced into the other, even at the same position
+        for i in 0..segments.len() {
+            let mut spliced: Vec<&Vec<u8>> = cts_a.iter().collect();
+            spliced[i] = &cts_b[i];
+            assert!(hte_decrypt(&key, &nonce, b"stream a", &spliced).is_none());
+        }
+
+        // A stream with the same header but its own nonce, so it doesn't reuse stream a's derived
+        // key. Its later segments are bound to its own first segment via the transcript, so they
+        // can't be spliced after stream a's first segment either.
+        let mut nonce_c = nonce;
+        nonce_c[0] = 1;
+        let cts_c = hte_encrypt(&key, &nonce_c, b"stream a", &[b"ZERO", b"one", b"two"]);
+        assert!(hte_decrypt(
+            &key,
+            &nonce,
+            b"stream a",
+            &[&cts_a[0], &cts_c[1], &cts_c[2]]
+        )
+        .is_none());
+    }
+}
@@ -0,0 +1,151 @@
+//! Defines `UtcChaCha20Poly1305`, the UtC transform over ChaCha20-Poly1305
+
+use crate::{hkdf_com_prf::HkdfComPrf, utc_transform::Utc};
+
+use aead::{AeadCore, AeadInPlace, Error, Key, NewAead, Nonce, Tag};
+use aes_gcm::ClobberingDecrypt;
+use chacha20::{
+    cipher::{NewCipher, StreamCipher, StreamCipherSeek},
+    ChaCha20, BLOCK_SIZE,
+};
+use chacha20poly1305::ChaCha20Poly1305;
+use cipher::typenum::{U0, U12, U16, U32};
+use sha2::Sha512;
+use subtle::Choice;
+use zeroize::Zeroizing;
+
+/// A key-committing AEAD built on top of ChaCha20-Poly1305
+pub type UtcChaCha20Poly1305 = Utc<ClobberingChaCha20Poly1305, HkdfComPrf<Sha512, U32, U12>>;
+
+/// ChaCha20-Poly1305 with the [`ClobberingDecrypt`] interface that [`Utc`] needs. The
+/// `chacha20poly1305` crate can't check a tag without decrypting, so the tag is checked by
+/// decrypting a copy of the buffer, and the keystream is applied to the buffer itself with the
+/// `chacha20` stream cipher. Compared to plain decryption, this costs a copy of the buffer and one
+/// extra keystream pass.
+#[derive(Clone)]
+pub struct ClobberingChaCha20Poly1305 {
+    aead: ChaCha20Poly1305,
+    key: Zeroizing<[u8; 32]>,
+}
+
+impl ClobberingChaCha20Poly1305 {
+    // Applies the ChaCha20 keystream that ChaCha20-Poly1305 encrypts the message with. Block 0 is
+    // used for the Poly1305 key, so the message keystream starts at block 1.
+    fn apply_keystream(&self, nonce: &Nonce<Self>, buffer: &mut [u8]) {
+        let mut cipher = ChaCha20::new(self.key.as_ref().into(), nonce);
+        cipher.seek(BLOCK_SIZE as u64);
+        cipher.apply_keystream(buffer);
+    }
+}
+
+impl NewAead for ClobberingChaCha20Poly1305 {
+    type KeySize = U32;
+
+    fn new(key: &Key<Self>) -> Self {
+        ClobberingChaCha20Poly1305 {
+            aead: ChaCha20Poly1305::new(key),
+            key: Zeroizing::new((*key).into()),
+        }
+    }
+}
+
+impl AeadCore for ClobberingChaCha20Poly1305 {
+    type NonceSize = U12;
+    type TagSize = U16;
+    type CiphertextOverhead = U0;
+}
+
+impl AeadInPlace for ClobberingChaCha20Poly1305 {
+    fn encrypt_in_place_detached(
+        &self,
+        nonce: &Nonce<Self>,
+        associated_data: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<Tag<Self>, Error> {
+        self.aead
+            .encrypt_in_place_detached(nonce, associated_data, buffer)
+    }
+
+    fn decrypt_in_place_detached(
+        &self,
+        nonce: &Nonce<Self>,
+        associated_data: &[u8],
+        buffer: &mut [u8],
+        tag: &Tag<Self>,
+    ) -> Result<(), Error> {
+        self.aead
+            .decrypt_in_place_detached(nonce, associated_data, buffer, tag)
+    }
+}
+
+impl ClobberingDecrypt for ClobberingChaCha20Poly1305 {
+    // Checks the tag, and always applies the keystream to `buffer`, whether or not the tag is
+    // valid. The tag is checked on a copy, so that `buffer` is in the same state either way, and
+    // `unclobber` can always restore it.
+    fn clobbering_decrypt(
+        &self,
+        nonce: &Nonce<Self>,
+        associated_data: &[u8],
+        buffer: &mut [u8],
+        tag: &Tag<Self>,
+    ) -> Result<Choice, Error> {
+        // If the tag is valid, the copy ends up holding the plaintext, so clear it when done
+        let mut copy = Zeroizing::new(buffer.to_vec());
+        let tag_ok = self
+            .aead
+            .decrypt_in_place_detached(nonce, associated_data, &mut copy, tag)
+            .is_ok();
+
+        self.apply_keystream(nonce, buffer);
+        Ok(Choice::from(tag_ok as u8))
+    }
+
+    // Applying the keystream again restores the ciphertext
+    fn unclobber(&self, nonce: &Nonce<Self>, buffer: &mut [u8], _tag: &Tag<Self>) {
+        self.apply_keystream(nonce, buffer);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        utc_transform::{DetachedCommitment, InnerTag},
+        util::{test_aead_correctness, test_detached_commitment},
+    };
+
+    test_aead_correctness!(UtcChaCha20Poly1305, utc_chacha20poly1305_correctness);
+    test_detached_commitment!(
+        UtcChaCha20Poly1305,
+        utc_chacha20poly1305_detached_commitment
+    );
+
+    // A failed decryption must leave the ciphertext in the buffer, whether the inner tag or the
+    // commitment is wrong
+    #[test]
+    fn utc_chacha20poly1305_unclobber() {
+        let mut rng = rand::thread_rng();
+        let ciph = UtcChaCha20Poly1305::new(&UtcChaCha20Poly1305::generate_key(&mut rng));
+        let nonce = Nonce::<UtcChaCha20Poly1305>::default();
+
+        let mut ct = *b"hello world";
+        let (inner_tag, com) = ciph
+            .encrypt_in_place_detached_with_commitment(&nonce, b"", &mut ct)
+            .unwrap();
+
+        let mut bad_inner_tag = inner_tag;
+        bad_inner_tag[0] ^= 1;
+        let mut bad_com = com;
+        bad_com[0] ^= 1;
+        let bad_tags: [(InnerTag<UtcChaCha20Poly1305>, _); 2] =
+            [(bad_inner_tag, com), (inner_tag, bad_com)];
+
+        for (tag, com) in bad_tags {
+            let mut buf = ct;
+            assert!(ciph
+                .decrypt_in_place_detached_with_commitment(&nonce, b"", &mut buf, &tag, &com)
+                .is_err());
+            assert_eq!(buf, ct);
+        }
+    }
+}
@@ -3,12 +3,15 @@
 
 use core::marker::PhantomData;
 
-use crate::{hkdf_com_prf::HkdfComPrf, util::CommittingPrf};
+use crate::{
+    hkdf_com_prf::HkdfComPrf,
+    util::{CommittingPrf, PrfCom, PrfMask, BATCH_SIZE},
+};
 
 use aead::{AeadCore, AeadInPlace, Error, NewAead, Nonce, Tag};
 use aes_gcm::{Aes128Gcm, Aes256Gcm, ClobberingDecrypt};
 use cipher::{
-    generic_array::{arr::AddLength, GenericArray},
+    generic_array::{arr::AddLength, ArrayLength, GenericArray},
     typenum::{Unsigned, U12, U16, U32},
 };
 use sha2::{Sha256, Sha512};
@@ -26,13 +29,16 @@ pub type UtcAes256Gcm = Utc<Aes256Gcm, HkdfComPrf<Sha512, U32, U12>>;
 /// (i.e., not necessarily nonce-misuse-resistant) AEAD into a key-committing unique-nonce-secure
 /// AEAD. Its construction is described in Figure 15 of [Bellare and
 /// Hoang](https://eprint.iacr.org/2022/268).
+#[derive(Clone)]
 pub struct Utc<A, F>
 where
     A: AeadInPlace + NewAead,
     F: CommittingPrf<KeySize = A::KeySize, MsgSize = A::NonceSize, MaskSize = A::KeySize>,
     F::ComSize: AddLength<u8, A::TagSize>,
 {
-    prf: F,
+    // The PRF is keyed once here. Only the underlying AEAD is keyed per message, since its key is
+    // the PRF mask, which depends on the nonce.
+    pub(crate) prf: F,
     ciph: PhantomData<A>,
 }
 
@@ -68,12 +74,112 @@ where
     }
 }
 
-impl<A, F> AeadInPlace for Utc<A, F>
+impl<A, F> Utc<A, F>
+where
+    A: AeadInPlace + NewAead,
+    F: CommittingPrf<KeySize = A::KeySize, MsgSize = A::NonceSize, MaskSize = A::KeySize>,
+    F::ComSize: AddLength<u8, A::TagSize>,
+{
+    /// Encrypts many messages at once. `buffers[i]` is encrypted in place under `nonces[i]` and
+    /// `associated_data[i]`, and its tag is written to `tags[i]`. This is equivalent to calling
+    /// `encrypt_in_place_detached` on each message, but it evaluates the committing PRF in
+    /// batches via [`CommittingPrf::prf_batch`].
+    ///
+    /// Returns an error if the slices have different lengths, or if any encryption fails. In the
+    /// latter case, the contents of `buffers` and `tags` are unspecified.
+    pub fn encrypt_in_place_detached_batch(
+        &self,
+        nonces: &[Nonce<Self>],
+        associated_data: &[&[u8]],
+        buffers: &mut [&mut [u8]],
+        tags: &mut [Tag<Self>],
+    ) -> Result<(), Error> {
+        let n = nonces.len();
+        if associated_data.len() != n || buffers.len() != n || tags.len() != n {
+            return Err(Error);
+        }
+
+        // Scratch space for the PRF outputs of one batch
+        let mut prf_outs: [(PrfCom<F>, PrfMask<F>); BATCH_SIZE] = Default::default();
+
+        for (((nonce_chunk, ad_chunk), buf_chunk), tag_chunk) in nonces
+            .chunks(BATCH_SIZE)
+            .zip(associated_data.chunks(BATCH_SIZE))
+            .zip(buffers.chunks_mut(BATCH_SIZE))
+            .zip(tags.chunks_mut(BATCH_SIZE))
+        {
+            // Generate the commitments and masks for the whole chunk
+            let prf_outs = &mut prf_outs[..nonce_chunk.len()];
+            self.prf.prf_batch(nonce_chunk, prf_outs)?;
+
+            // Now encrypt each message under its mask, same as in encrypt_in_place_detached
+            for ((((nonce, ad), buf), tag), (prf_com, prf_mask)) in nonce_chunk
+                .iter()
+                .zip(ad_chunk.iter())
+                .zip(buf_chunk.iter_mut())
+                .zip(tag_chunk.iter_mut())
+                .zip(prf_outs.iter())
+            {
+                let ciph = A::new(prf_mask);
+                let ciph_tag = ciph.encrypt_in_place_detached(nonce, ad, buf)?;
+                *tag = pack_tag::<A, F>(ciph_tag, prf_com.clone());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The underlying AEAD tag of a [`DetachedCommitment`] AEAD
+pub type InnerTag<A> = GenericArray<u8, <A as DetachedCommitment>::InnerTagSize>;
+
+/// The key commitment of a [`DetachedCommitment`] AEAD
+pub type Commitment<A> = GenericArray<u8, <A as DetachedCommitment>::ComSize>;
+
+/// An AEAD whose tag is the concatenation of an underlying AEAD tag and a key commitment. This
+/// lets the caller store the commitment separately from the ciphertext, e.g., in an index.
+pub trait DetachedCommitment: AeadCore {
+    /// The size of the underlying AEAD's tag
+    type InnerTagSize: ArrayLength<u8>;
+
+    /// The size of the key commitment
+    type ComSize: ArrayLength<u8>;
+
+    /// Encrypts the given buffer in place. Returns the underlying AEAD tag and the key commitment
+    /// as separate values rather than the concatenated [`Tag`].
+    fn encrypt_in_place_detached_with_commitment(
+        &self,
+        nonce: &Nonce<Self>,
+        associated_data: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<(InnerTag<Self>, Commitment<Self>), Error>;
+
+    /// Decrypts the given buffer in place, given the underlying AEAD tag and the key commitment
+    /// that were returned by [`Self::encrypt_in_place_detached_with_commitment`]
+    fn decrypt_in_place_detached_with_commitment(
+        &self,
+        nonce: &Nonce<Self>,
+        associated_data: &[u8],
+        buffer: &mut [u8],
+        tag: &InnerTag<Self>,
+        com: &Commitment<Self>,
+    ) -> Result<(), Error>;
+
+    /// Returns the key commitment that encrypting under the given nonce and associated data would
+    /// produce, without encrypting or decrypting anything. This lets a receiver with several
+    /// candidate keys find the one that made a ciphertext.
+    fn commitment(&self, nonce: &Nonce<Self>, associated_data: &[u8]) -> Commitment<Self>;
+}
+
+impl<A, F> DetachedCommitment for Utc<A, F>
 where
     A: AeadInPlace + ClobberingDecrypt + NewAead,
     F: CommittingPrf<KeySize = A::KeySize, MsgSize = A::NonceSize, MaskSize = A::KeySize>,
     F::ComSize: AddLength<u8, A::TagSize>,
 {
+    type InnerTagSize = A::TagSize;
+    type ComSize = F::ComSize;
+
     // Paraphrasing from Figure 15:
     //
     // UtC[F, A].Enc(K, N, A, M):
@@ -81,12 +187,14 @@ where
     //     (C, T) ← A.Enc(mask, N, A, M)
     //     T' ← T || com
     //     return (C, T')
-    fn encrypt_in_place_detached(
+    //
+    // This returns (T, com) without concatenating them.
+    fn encrypt_in_place_detached_with_commitment(
         &self,
         nonce: &Nonce<Self>,
         associated_data: &[u8],
         buffer: &mut [u8],
-    ) -> Result<Tag<Self>, Error> {
+    ) -> Result<(InnerTag<Self>, Commitment<Self>), Error> {
         // Generate the commitment and mask
         let (prf_com, prf_mask) = self.prf.prf(nonce);
 
@@ -94,7 +202,7 @@ where
         let ciph = A::new(&prf_mask);
         let ciph_tag = ciph.encrypt_in_place_detached(nonce, associated_data, buffer)?;
 
-        Ok(pack_tag::<A, F>(ciph_tag, prf_com))
+        Ok((ciph_tag, prf_com))
     }
 
     // Paraphrasing from Figure 15:
@@ -107,16 +215,16 @@ where
     //     else:
     //         M ← A.Dec(mask, N, A, C, T)
     //         return M
-    fn decrypt_in_place_detached(
+    //
+    // This takes (T, com) already separated.
+    fn decrypt_in_place_detached_with_commitment(
         &self,
         nonce: &Nonce<Self>,
         associated_data: &[u8],
         buffer: &mut [u8],
-        tag: &Tag<Self>,
+        ciph_tag: &InnerTag<Self>,
+        prf_com: &Commitment<Self>,
     ) -> Result<(), Error> {
-        // Unpack the components of the tag
-        let (ciph_tag, prf_com) = unpack_tag::<A, F>(tag);
-
         // Generate the commitment and mask
         let (expected_prf_com, prf_mask) = self.prf.prf(nonce);
 
@@ -138,6 +246,49 @@ where
             Err(Error)
         }
     }
+
+    // The commitment only depends on the nonce. The mask is discarded.
+    fn commitment(&self, nonce: &Nonce<Self>, _associated_data: &[u8]) -> Commitment<Self> {
+        self.prf.prf(nonce).0
+    }
+}
+
+impl<A, F> AeadInPlace for Utc<A, F>
+where
+    A: AeadInPlace + ClobberingDecrypt + NewAead,
+    F: CommittingPrf<KeySize = A::KeySize, MsgSize = A::NonceSize, MaskSize = A::KeySize>,
+    F::ComSize: AddLength<u8, A::TagSize>,
+{
+    fn encrypt_in_place_detached(
+        &self,
+        nonce: &Nonce<Self>,
+        associated_data: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<Tag<Self>, Error> {
+        let (ciph_tag, prf_com) =
+            self.encrypt_in_place_detached_with_commitment(nonce, associated_data, buffer)?;
+
+        Ok(pack_tag::<A, F>(ciph_tag, prf_com))
+    }
+
+    fn decrypt_in_place_detached(
+        &self,
+        nonce: &Nonce<Self>,
+        associated_data: &[u8],
+        buffer: &mut [u8],
+        tag: &Tag<Self>,
+    ) -> Result<(), Error> {
+        // Unpack the components of the tag
+        let (ciph_tag, prf_com) = unpack_tag::<A, F>(tag);
+
+        self.decrypt_in_place_detached_with_commitment(
+            nonce,
+            associated_data,
+            buffer,
+            ciph_tag,
+            prf_com,
+        )
+    }
 }
 
 /// Creates a `utc_tag = ciph_tag || prf_com`
@@ -159,9 +310,7 @@ where
 }
 
 /// Unpacks `utc_tag = ciph_tag || prf_com`
-fn unpack_tag<A, F>(
-    utc_tag: &Tag<Utc<A, F>>,
-) -> (&GenericArray<u8, A::TagSize>, &GenericArray<u8, F::ComSize>)
+fn unpack_tag<A, F>(utc_tag: &Tag<Utc<A, F>>) -> (&Tag<A>, &PrfCom<F>)
 where
     A: AeadInPlace + NewAead,
     F: CommittingPrf<KeySize = A::KeySize, MsgSize = A::NonceSize, MaskSize = A::KeySize>,
@@ -176,8 +325,87 @@ where
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::util::test_aead_correctness;
+    use crate::{
+        util::{test_aead_correctness, test_detached_commitment},
+        CxPrf,
+    };
+
+    use aes::{Aes128, Aes256};
 
     test_aead_correctness!(UtcAes128Gcm, utc_aes128_correctness);
     test_aead_correctness!(UtcAes256Gcm, utc_aes256_correctness);
+
+    test_detached_commitment!(UtcAes128Gcm, utc_aes128_detached_commitment);
+    test_detached_commitment!(UtcAes256Gcm, utc_aes256_detached_commitment);
+
+    // Checks that batch encryption matches one-at-a-time encryption, over enough messages to span
+    // several batches
+    fn check_batch_encrypt<A, F>()
+    where
+        A: AeadInPlace + NewAead + ClobberingDecrypt,
+        F: CommittingPrf<KeySize = A::KeySize, MsgSize = A::NonceSize, MaskSize = A::KeySize>,
+        F::ComSize: AddLength<u8, A::TagSize>,
+    {
+        use rand::RngCore;
+
+        let mut rng = rand::thread_rng();
+        let ciph = Utc::<A, F>::new(&Utc::<A, F>::generate_key(&mut rng));
+
+        let num_msgs = 3 * BATCH_SIZE + 1;
+        let nonces: Vec<_> = (0..num_msgs)
+            .map(|_| {
+                let mut nonce = Nonce::<Utc<A, F>>::default();
+                rng.fill_bytes(&mut nonce);
+                nonce
+            })
+            .collect();
+        let aads: Vec<Vec<u8>> = (0..num_msgs).map(|i| vec![i as u8; i]).collect();
+        let msgs: Vec<Vec<u8>> = (0..num_msgs).map(|i| vec![0xff - i as u8; 2 * i]).collect();
+
+        // Batch encrypt
+        let aad_refs: Vec<&[u8]> = aads.iter().map(Vec::as_slice).collect();
+        let mut bufs = msgs.clone();
+        let mut buf_refs: Vec<&mut [u8]> = bufs.iter_mut().map(Vec::as_mut_slice).collect();
+        let mut tags = vec![Tag::<Utc<A, F>>::default(); num_msgs];
+        ciph.encrypt_in_place_detached_batch(&nonces, &aad_refs, &mut buf_refs, &mut tags)
+            .unwrap();
+
+        // Check every output against a regular encryption, and make sure it decrypts
+        for i in 0..num_msgs {
+            let mut buf = msgs[i].clone();
+            let tag = ciph
+                .encrypt_in_place_detached(&nonces[i], &aads[i], &mut buf)
+                .unwrap();
+            assert_eq!(buf, bufs[i]);
+            assert_eq!(tag, tags[i]);
+
+            ciph.decrypt_in_place_detached(&nonces[i], &aads[i], &mut buf, &tag)
+                .unwrap();
+            assert_eq!(buf, msgs[i]);
+        }
+
+        // Mismatched lengths are an error
+        let mut buf_refs: Vec<&mut [u8]> = bufs.iter_mut().map(Vec::as_mut_slice).collect();
+        assert!(ciph
+            .encrypt_in_place_detached_batch(
+                &nonces[1..],
+                &aad_refs[1..],
+                &mut buf_refs,
+                &mut tags[1..]
+            )
+            .is_err());
+    }
+
+    // HkdfComPrf uses the default prf_batch, which evaluates one message at a time
+    #[test]
+    fn utc_batch_encrypt() {
+        check_batch_encrypt::<Aes128Gcm, HkdfComPrf<Sha256, U16, U12>>();
+    }
+
+    // CxPrf overrides prf_batch to pack a whole batch into one encrypt_blocks call
+    #[test]
+    fn utc_cx_batch_encrypt() {
+        check_batch_encrypt::<Aes128Gcm, CxPrf<Aes128, U12>>();
+        check_batch_encrypt::<Aes256Gcm, CxPrf<Aes256, U12>>();
+    }
 }
@@ -1,3 +1,4 @@
+use aead::Error;
 use cipher::{
     generic_array::{arr::AddLength, ArrayLength, GenericArray},
     KeyInit, KeySizeUser,
@@ -6,6 +7,15 @@ use cipher::{
 pub(crate) type DoubleKeySize<T> =
     <<T as KeySizeUser>::KeySize as AddLength<u8, <T as KeySizeUser>::KeySize>>::Output;
 
+/// The commitment output of a [`CommittingPrf`]
+pub type PrfCom<F> = GenericArray<u8, <F as CommittingPrf>::ComSize>;
+
+/// The mask output of a [`CommittingPrf`]
+pub type PrfMask<F> = GenericArray<u8, <F as CommittingPrf>::MaskSize>;
+
+/// The number of messages that batched operations process at a time
+pub(crate) const BATCH_SIZE: usize = 8;
+
 /// A helper trait for a _committing PRF_, which returns a commitment and a mask. This is defined
 /// in §7.
 pub trait CommittingPrf: KeyInit {
@@ -21,6 +31,46 @@ pub trait CommittingPrf: KeyInit {
         GenericArray<u8, Self::ComSize>,
         GenericArray<u8, Self::MaskSize>,
     );
+
+    /// Evaluates the PRF on every message in `msgs`, and writes the `i`-th output to `out[i]`.
+    /// The default implementation calls [`Self::prf`] on each message. Implementations can
+    /// override this to amortize work across messages.
+    ///
+    /// Returns an error if `msgs` and `out` have different lengths.
+    fn prf_batch(
+        &self,
+        msgs: &[GenericArray<u8, Self::MsgSize>],
+        out: &mut [(PrfCom<Self>, PrfMask<Self>)],
+    ) -> Result<(), Error> {
+        if msgs.len() != out.len() {
+            return Err(Error);
+        }
+
+        for (msg, o) in msgs.iter().zip(out.iter_mut()) {
+            *o = self.prf(msg);
+        }
+
+        Ok(())
+    }
+}
+
+// Decodes a hex string, for test vectors
+#[cfg(test)]
+pub(crate) fn hex(s: &str) -> Vec<u8> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+// Returns `len` random bytes, for test keys, nonces, and messages
+#[cfg(test)]
+pub(crate) fn random_bytes(len: usize) -> Vec<u8> {
+    use rand::RngCore;
+
+    let mut buf = vec![0u8; len];
+    rand::thread_rng().fill_bytes(&mut buf);
+    buf
 }
 
 // Tests that Dec(Enc(x)) == x for a lot of x
@@ -82,3 +132,76 @@ macro_rules! test_aead_correctness {
 
 #[cfg(test)]
 pub(crate) use test_aead_correctness;
+
+// Tests that the detached tag and commitment match the concatenated tag, that they decrypt
+// correctly, and that a modified commitment is rejected
+#[cfg(test)]
+macro_rules! test_detached_commitment {
+    ($aead:ty, $test_name:ident) => {
+        #[test]
+        fn $test_name() {
+            use crate::utc_transform::DetachedCommitment;
+            use aead::{AeadInPlace, NewAead, Nonce};
+            use rand::RngCore;
+
+            let mut rng = rand::thread_rng();
+
+            let ciph = {
+                let key = <$aead>::generate_key(&mut rng);
+                <$aead>::new(&key)
+            };
+
+            let msg = b"the quick brown fox";
+            let aad = b"jumps over the lazy dog";
+            let nonce = {
+                let mut buf = Nonce::<$aead>::default();
+                rng.fill_bytes(buf.as_mut_slice());
+                buf
+            };
+
+            // Encrypt with both APIs and check that the outputs agree
+            let mut buf = msg.to_vec();
+            let tag = ciph
+                .encrypt_in_place_detached(&nonce, aad, &mut buf)
+                .unwrap();
+            let mut detached_buf = msg.to_vec();
+            let (inner_tag, com) = ciph
+                .encrypt_in_place_detached_with_commitment(&nonce, aad, &mut detached_buf)
+                .unwrap();
+            assert_eq!(buf, detached_buf);
+            assert_eq!(&tag[..inner_tag.len()], inner_tag.as_slice());
+            assert_eq!(&tag[inner_tag.len()..], com.as_slice());
+
+            // The commitment can be recomputed without the ciphertext
+            assert_eq!(ciph.commitment(&nonce, aad), com);
+
+            // A modified commitment must be rejected, and the buffer must be left as ciphertext
+            let mut bad_com = com.clone();
+            bad_com[0] ^= 1;
+            assert!(ciph
+                .decrypt_in_place_detached_with_commitment(
+                    &nonce,
+                    aad,
+                    &mut detached_buf,
+                    &inner_tag,
+                    &bad_com
+                )
+                .is_err());
+            assert_eq!(buf, detached_buf);
+
+            // The correct commitment decrypts
+            ciph.decrypt_in_place_detached_with_commitment(
+                &nonce,
+                aad,
+                &mut detached_buf,
+                &inner_tag,
+                &com,
+            )
+            .unwrap();
+            assert_eq!(detached_buf, msg);
+        }
+    };
+}
+
+#[cfg(test)]
+pub(crate) use test_detached_commitment;